@@ -17,18 +17,97 @@
 from random import getrandbits as rnd
 from binascii import hexlify, unhexlify
 import click
+import hashlib
+import base64
 import binascii
 import json
-from typing import Optional, List, Tuple
+import string
+import time
+from typing import Optional, Dict, List, Tuple, Union
+from cryptography.hazmat.primitives.ciphers.aead import ChaCha20Poly1305
 import ledgerwallet.client
 
 MAX_NAME_LEN = 32
 MAX_LOGIN_LEN = 32
 MAX_PASS_LEN = 32
+MAX_LABEL_LEN = 16
+MAX_PARTIAL_READ = 4
+MAX_CHECKWORD_LEN = 16
+IMPORT_STORED = 0
+IMPORT_RESERVED = 1
+IMPORT_RENAMED = 2
+IMPORT_INVALID = 3
+IMPORT_OLDER = 4
+TOTP_STORED = 0
+TOTP_RESERVED = 1
+TOTP_INVALID = 2
+MAX_HINT_LEN = 8
+MAX_ENTRIES = 128
+MAX_BLOB_ENTRIES = 2
+MAX_SESSION_ADDS = 32
+
+CLASS_LOWER = 0x01
+CLASS_UPPER = 0x02
+CLASS_DIGIT = 0x04
+CLASS_SYMBOL = 0x08
+MAX_SYMBOLS_LEN = 16
+
+SYLLABLE_CONSONANTS = "bdfghjklmnprstvz"
+SYLLABLE_VOWELS = "aeiou"
+
+CHECK_OK = 0
+CHECK_TOO_LONG = 1
+CHECK_CONTAINS_ZERO = 2
+CHECK_INVALID_UTF8 = 3
+MAX_CHALLENGE_LEN = 255 - MAX_NAME_LEN
+DEFAULT_KDF_ITERATIONS = 2048
+CIPHER_AES_CBC = "aes-cbc"
+CIPHER_CHACHA20_POLY1305 = "chacha20-poly1305"
+MAX_KDF_ITERATIONS = 10000
+MAX_BENCHMARK_RECORDS = 64
+MAX_MATCHES = 64
+MAX_TAG_COUNTS = 64
+
+MISSING_LOGIN = 0
+MISSING_PASSWORD = 1
+MISSING_TAG = 2
+
+FLAG_BINARY = 0x01
+FLAG_ARM_REQUIRED = 0x02
+FLAG_ROTATE = 0x04
+STATUS_TAGGED = 0x08
+STATUS_WIPED = 0x10
+FLAG_TOTP = 0x20
+MAX_FLAGS = 128
+
+MODEL_NANO_S = 1
+CAPABILITY_BLE = 0x01
+
+PREFIX_READ = 0
+PREFIX_CANDIDATES = 1
+
+QR_TEXT = 1
+QR_BASE64 = 2
+
+IDLE_LABEL = 0
+IDLE_LABEL_COUNT = 1
+IDLE_CHECKWORD = 2
 
 class BadVersion(Exception):
     pass
 
+class IncompleteExport(Exception):
+    pass
+
+class CorruptedBackup(Exception):
+    pass
+
+class AmbiguousName(Exception):
+    def __init__(self, count: int, names: List[str]):
+        super().__init__("{} names match".format(count))
+        self.count = count
+        self.names = names
+
 def str_to_bytes_pad(s, size):
     result = bytearray(s.encode())
     assert len(result) <= size
@@ -36,6 +115,45 @@ def str_to_bytes_pad(s, size):
         result.append(0)
     return result
 
+def gen_params_bytes(length, classes, symbols, minimums):
+    data = bytes([length, classes]) + str_to_bytes_pad(symbols,
+        MAX_SYMBOLS_LEN)
+    if minimums is not None:
+        assert len(minimums) == 4
+        data += bytes(minimums)
+    return data
+
+def apply_hint(hint: str, password: str) -> str:
+    """
+    :param hint: Format hint of the password (see Client.set_hint). A `*`
+        stands for the password, otherwise the hint is a prefix.
+    :param password: Password read from the device.
+    :return: Password formatted as the hint tells.
+    """
+    if '*' in hint:
+        return hint.replace('*', password, 1)
+    return hint + password
+
+def passphrase_key(passphrase: str, salt: bytes, iterations: int) -> bytes:
+    """
+    :return: Export key derived from a passphrase, as done by the device (see
+        Client.set_passphrase).
+    """
+    return hashlib.pbkdf2_hmac('sha1', passphrase.encode(), salt, iterations,
+        32)
+
+def decrypt_entry(key: bytes, entry: bytes) -> bytes:
+    """
+    Decrypt an entry exported with the ChaCha20-Poly1305 cipher suite, without
+    a device.
+    :param key: Export key, derived with passphrase_key.
+    :param entry: Exported entry: nonce, ciphertext and tag.
+    :return: Plaintext entry, as exported in plaintext.
+    :raise cryptography.exceptions.InvalidTag: if the entry is corrupted, or
+        the key is wrong.
+    """
+    return ChaCha20Poly1305(key).decrypt(entry[:12], entry[12:], None)
+
 def bytes_to_str(data):
     while (len(data) > 0) and (data[-1] == 0):
         data = data[:-1]
@@ -84,6 +202,95 @@ class Client:
         value = resp[offset:offset+length]
         return value.decode()
 
+    def get_clear_wear(self) -> int:
+        """
+        :return: Approximate number of Flash pages written when removing all
+            the entries.
+        """
+        resp = self.dev.apdu_exchange(0x37, p1=0x00)
+        assert len(resp) == 4
+        return int.from_bytes(resp, 'big')
+
+    def get_import_wear(self, count: int) -> int:
+        """
+        :param count: Number of imported entries.
+        :return: Approximate number of Flash pages written when importing the
+            entries.
+        """
+        resp = self.dev.apdu_exchange(0x37, count.to_bytes(4, 'big'), p1=0x01)
+        assert len(resp) == 4
+        return int.from_bytes(resp, 'big')
+
+    def get_weakest(self) -> List[str]:
+        """
+        :return: Names of the entries, ordered from the weakest to the
+            strongest password.
+        """
+        names = []
+        while True:
+            resp = self.dev.apdu_exchange(0x3a, len(names).to_bytes(4, 'big'))
+            assert len(resp) == 1 + resp[0] * MAX_NAME_LEN
+            if resp[0] == 0:
+                return names
+            names += [bytes_to_str(resp[i:i + MAX_NAME_LEN])
+                for i in range(1, len(resp), MAX_NAME_LEN)]
+
+    def get_lacking_classes(self, required: int) -> List[str]:
+        """
+        :param required: Bitmask of the character classes a password must
+            contain (see CLASS_* constants).
+        :return: Names of the entries whose password lacks one of the
+            required classes. Binary secrets and empty passwords are ignored.
+        """
+        names = []
+        while True:
+            resp = self.dev.apdu_exchange(0x40,
+                bytes([required]) + len(names).to_bytes(4, 'big'))
+            assert len(resp) == 5 + resp[4] * MAX_NAME_LEN
+            names += [bytes_to_str(resp[i:i + MAX_NAME_LEN])
+                for i in range(5, len(resp), MAX_NAME_LEN)]
+            if len(names) >= int.from_bytes(resp[:4], 'big') or resp[4] == 0:
+                return names
+
+    def get_length_range(self) -> Tuple[int, int]:
+        """
+        :return: Lengths of the shortest and longest passwords, ignoring empty
+            ones.
+        """
+        resp = self.dev.apdu_exchange(0x39)
+        assert len(resp) == 2
+        return resp[0], resp[1]
+
+    def get_sequence_range(self) -> Tuple[int, int, int]:
+        """
+        :return: Modification sequence values of the oldest and newest
+            entries, and current value of the sequence counter.
+        """
+        resp = self.dev.apdu_exchange(0x36)
+        assert len(resp) == 12
+        return (int.from_bytes(resp[:4], 'big'),
+            int.from_bytes(resp[4:8], 'big'),
+            int.from_bytes(resp[8:], 'big'))
+
+    def get_stack_usage(self) -> Tuple[int, int]:
+        """
+        :return: Estimated number of free bytes of the stack, and size of the
+            stack.
+        """
+        resp = self.dev.apdu_exchange(0x3b)
+        assert len(resp) == 8
+        return int.from_bytes(resp[:4], 'big'), int.from_bytes(resp[4:], 'big')
+
+    def get_capabilities(self) -> Tuple[int, int, Tuple[int, int]]:
+        """
+        :return: Model identifier (MODEL_* constants), feature bits
+            (CAPABILITY_* constants), and width and height of the screen in
+            pixels.
+        """
+        resp = self.dev.apdu_exchange(0x35)
+        assert len(resp) == 4
+        return resp[0], resp[1], (resp[2], resp[3])
+
     def get_size(self) -> int:
         """
         :return: Number of password entries.
@@ -92,12 +299,23 @@ class Client:
         assert len(resp) == 4
         return int.from_bytes(resp, 'big')
 
-    def add(self, name: str, login: str, password: Optional[str] = None):
+    def add(self, name: str, login: str, password: Optional[str] = None,
+        preview: bool = False, exclude_name: bool = False,
+        pronounceable: bool = False) -> Tuple[bool, str]:
         """
         Add a new password.
         :param name: Password name.
         :param login: Password login.
         :param password: Password. None if it is generated by the device.
+        :param preview: If the password is generated by the device, display it
+            so the user can ask for another one before it is stored.
+        :param exclude_name: If the password is generated by the device, do not
+            use the characters of the name.
+        :param pronounceable: If the password is generated by the device, make
+            it of pronounceable syllables.
+        :return: True if an existing password has been updated, False if a new
+            one has been created, and fingerprint of the stored password, as
+            displayed by the device when it is read.
         """
         name_bytes = str_to_bytes_pad(name, MAX_NAME_LEN)
         login_bytes = str_to_bytes_pad(login, MAX_LOGIN_LEN)
@@ -105,46 +323,219 @@ class Client:
             p1 = 0x00
             password_bytes = str_to_bytes_pad(password, MAX_PASS_LEN)
         else:
-            p1 = 0x01
+            p1 = {False: 0x01, True: 0x02}[preview]
             password_bytes = bytearray()
-        self.dev.apdu_exchange(0x03, p1=p1, data=name_bytes + login_bytes +
-            password_bytes)
+        p2 = int(exclude_name) | (int(pronounceable) << 1) | 0x0c
+        r = self.dev.apdu_exchange(0x03, p1=p1, p2=p2,
+            data=name_bytes + login_bytes + password_bytes)
+        return r[0] == 1, bytes(r[1:]).decode()
 
-    def get_name(self, index: int) -> str:
+    def add_generated(self, name: str, login: str, length: int, classes: int,
+        symbols: str = "",
+        minimums: Optional[Tuple[int, int, int, int]] = None,
+        exclude_name: bool = False, pronounceable: bool = False
+        ) -> Tuple[bool, str]:
+        """
+        Add a new password generated by the device with the given parameters,
+        instead of the generation defaults.
+        :param name: Password name.
+        :param login: Password login.
+        :param length: Length of the password.
+        :param classes: Bitmask of character classes (see CLASS_* constants).
+        :param symbols: Characters of the symbol class.
+        :param minimums: Minimum number of lowercase letters, uppercase letters,
+            digits and symbols.
+        :param exclude_name: Do not use the characters of the name.
+        :param pronounceable: Make the password of pronounceable syllables,
+            with the given length. Other parameters are ignored.
+        :return: True if an existing password has been updated, False if a new
+            one has been created, and fingerprint of the stored password, as
+            displayed by the device when it is read.
+        """
+        name_bytes = str_to_bytes_pad(name, MAX_NAME_LEN)
+        login_bytes = str_to_bytes_pad(login, MAX_LOGIN_LEN)
+        p2 = int(exclude_name) | (int(pronounceable) << 1) | 0x0c
+        r = self.dev.apdu_exchange(0x03, p1=0x04, p2=p2,
+            data=name_bytes + login_bytes + gen_params_bytes(length, classes,
+            symbols, minimums))
+        return r[0] == 1, bytes(r[1:]).decode()
+
+    def add_binary(self, name: str, login: str, secret: bytes):
+        """
+        Add a new password which is a binary secret.
+        :param name: Password name.
+        :param login: Password login.
+        :param secret: Secret, which may contain any byte value.
+        """
+        assert len(secret) <= MAX_PASS_LEN
+        name_bytes = str_to_bytes_pad(name, MAX_NAME_LEN)
+        login_bytes = str_to_bytes_pad(login, MAX_LOGIN_LEN)
+        self.dev.apdu_exchange(0x03, p1=0x03, data=name_bytes + login_bytes +
+            bytes([len(secret)]) + secret)
+
+    def start_add_session(self, count: int):
+        """
+        Allow adding new passwords without confirmation.
+        :param count: Number of passwords which can be added without
+            confirmation. 0 closes the current session.
+        """
+        assert count <= MAX_SESSION_ADDS
+        self.dev.apdu_exchange(0x16, bytes([count]))
+
+    def get_name(self, index: int, recent_first: bool = False,
+        by_name: bool = False) -> str:
         """
         Retrieve name of a password
         :param index: Password entry index
+        :param recent_first: Count index from the most recently added or
+            updated entry, by modification sequence.
+        :param by_name: Index is the alphabetical rank of the name.
         :return: Name
         """
-        r = self.dev.apdu_exchange(0x04, index.to_bytes(4, 'big'))
+        p1 = {False: 0x00, True: 0x01}[recent_first]
+        if by_name:
+            p1 = 0x02
+        r = self.dev.apdu_exchange(0x04, index.to_bytes(4, 'big'), p1=p1)
         assert len(r) == 32
         return bytes_to_str(r)
 
-    def get_names(self) -> List[str]:
-        """ :return: List of password names """
-        return [self.get_name(i) for i in range(self.get_size())]
+    def get_name_length(self, index: int) -> int:
+        """
+        :param index: Password entry index
+        :return: Number of significant bytes of the name, without padding.
+        """
+        r = self.dev.apdu_exchange(0x3c, index.to_bytes(4, 'big'))
+        assert len(r) == 1
+        return r[0]
+
+    def get_names(self, recent_first: bool = False,
+        by_name: bool = False) -> List[str]:
+        """
+        :param recent_first: List most recently added or updated entries first.
+        :param by_name: List entries in alphabetical order.
+        :return: List of password names
+        """
+        return [self.get_name(i, recent_first, by_name)
+            for i in range(self.get_size())]
+
+    def get_read_confirmations(self, name: str) -> int:
+        """
+        :param name: Password name.
+        :return: Number of confirmations reading this password will require.
+        """
+        name_bytes = str_to_bytes_pad(name, MAX_NAME_LEN)
+        resp = self.dev.apdu_exchange(0x21, name_bytes)
+        assert len(resp) == 1
+        return resp[0]
 
-    def get_by_name(self, name: str) -> Tuple[str, str]:
+    def get_by_name(self, name: str, mark_rotation: bool = False
+        ) -> Tuple[str, Union[str, bytes]]:
         """
         Retrieve the password with the given name.
         :param name: Password name.
-        :return: Login and Password string tuple.
+        :param mark_rotation: Mark the password to be rotated once read.
+        :return: Login and Password tuple. The password is returned as bytes
+            if it is a binary secret.
         """
         name_bytes = str_to_bytes_pad(name, MAX_NAME_LEN)
-        r = self.dev.apdu_exchange(0x05, name_bytes)
+        r = self.dev.apdu_exchange(0x05, name_bytes,
+            p1={False: 0x00, True: 0x01}[mark_rotation])
         login = bytes_to_str(r[:32])
+        if len(r) == 32 + MAX_PASS_LEN + 1:
+            return (login, bytes(r[32:32+r[64]]))
         password = bytes_to_str(r[32:32+64])
         return (login, password)
 
-    def get_by_name_internal(self, name: str):
+    def get_by_prefix(self, prefix: str, mark_rotation: bool = False
+        ) -> Tuple[str, str, Union[str, bytes]]:
+        """
+        Retrieve the password whose name starts with the given prefix, if it is
+        the only one or if its name is exactly the prefix.
+        :param prefix: Beginning of the password name.
+        :param mark_rotation: Mark the password to be rotated once read.
+        :return: Name, login and password tuple. The password is returned as
+            bytes if it is a binary secret.
+        :raise AmbiguousName: if several names start with the prefix. The
+            device returns at most 7 of them.
+        """
+        name_bytes = str_to_bytes_pad(prefix, MAX_NAME_LEN)
+        r = self.dev.apdu_exchange(0x05, name_bytes,
+            p1={False: 0x00, True: 0x01}[mark_rotation], p2=0x04)
+        if r[0] == PREFIX_CANDIDATES:
+            names = [bytes_to_str(r[i:i+32]) for i in range(2, len(r), 32)]
+            raise AmbiguousName(r[1], names)
+        assert r[0] == PREFIX_READ
+        name = bytes_to_str(r[1:33])
+        r = r[33:]
+        login = bytes_to_str(r[:32])
+        if len(r) == 32 + MAX_PASS_LEN + 1:
+            return (name, login, bytes(r[32:32+r[64]]))
+        return (name, login, bytes_to_str(r[32:32+64]))
+
+    def get_password_start(self, name: str, count: int) -> Tuple[str, bytes]:
+        """
+        Retrieve only the first bytes of the password with the given name, to
+        check it is the expected one without disclosing it.
+        :param name: Password name.
+        :param count: Number of bytes, from 1 to MAX_PARTIAL_READ.
+        :return: Login and first bytes of the password tuple. Less bytes are
+            returned if the password is shorter.
+        """
+        assert 0 < count <= MAX_PARTIAL_READ
+        data = str_to_bytes_pad(name, MAX_NAME_LEN) + bytes([count])
+        r = self.dev.apdu_exchange(0x05, data, p2=0x03)
+        return (bytes_to_str(r[:32]), bytes(r[32:]))
+
+    def get_by_name_base64(self, name: str, mark_rotation: bool = False
+        ) -> Tuple[str, bytes]:
+        """
+        Retrieve the password with the given name, encoded in base64 by the
+        device, for transports which do not handle all byte values.
+        :param name: Password name.
+        :param mark_rotation: Mark the password to be rotated once read.
+        :return: Login and decoded password bytes tuple.
+        """
+        name_bytes = str_to_bytes_pad(name, MAX_NAME_LEN)
+        r = self.dev.apdu_exchange(0x05, name_bytes,
+            p1={False: 0x00, True: 0x01}[mark_rotation], p2=0x01)
+        login = bytes_to_str(r[:32])
+        return (login, base64.b64decode(bytes(r[32:]), validate=True))
+
+    def get_by_name_qr(self, name: str, mark_rotation: bool = False
+        ) -> Tuple[str, str]:
+        """
+        Retrieve the password with the given name, as a payload for a QR code.
+        :param name: Password name.
+        :param mark_rotation: Mark the password to be rotated once read.
+        :return: Login and payload tuple. The payload is the password, or the
+            base64 encoding of a binary secret.
+        """
+        name_bytes = str_to_bytes_pad(name, MAX_NAME_LEN)
+        r = self.dev.apdu_exchange(0x05, name_bytes,
+            p1={False: 0x00, True: 0x01}[mark_rotation], p2=0x02)
+        login = bytes_to_str(r[:32])
+        assert r[32] in (QR_TEXT, QR_BASE64)
+        return (login, bytes(r[33:]).decode())
+
+    def get_by_name_internal(self, name: str, split: bool = False):
         """
         Ask the device to display on screen the login and password with the
         given name. Using this method, no sensitive information is transfered to
         the computer.
         :param name: Password name.
+        :param split: Display the password in two halves, on two screens.
         """
         name_bytes = str_to_bytes_pad(name, MAX_NAME_LEN)
-        self.dev.apdu_exchange(0x0d, name_bytes)
+        self.dev.apdu_exchange(0x0d, name_bytes, p2=int(split))
+
+    def swap_passwords(self, first: str, second: str):
+        """
+        Swap the passwords of two entries, without reading them.
+        :param first: Name of the first entry.
+        :param second: Name of the second entry.
+        """
+        self.dev.apdu_exchange(0x38, str_to_bytes_pad(first, MAX_NAME_LEN) +
+            str_to_bytes_pad(second, MAX_NAME_LEN))
 
     def delete_by_name(self, name: str):
         """
@@ -154,29 +545,272 @@ class Client:
         name_bytes = str_to_bytes_pad(name, MAX_NAME_LEN)
         self.dev.apdu_exchange(0x06, name_bytes)
 
-    def export(self, encrypt: bool=True) -> List[bytes]:
+    def delete_range(self, start: int, count: int) -> int:
+        """
+        Remove contiguous entries, with a single confirmation.
+        :param start: Index of the first entry.
+        :param count: Number of entries.
+        :return: Number of removed entries.
+        """
+        resp = self.dev.apdu_exchange(
+            0x2f, start.to_bytes(4, 'big') + count.to_bytes(4, 'big'))
+        assert len(resp) == 4
+        return int.from_bytes(resp, 'big')
+
+    def get_missing_count(self, field: int) -> int:
+        """
+        :param field: Checked field (MISSING_* constants).
+        :return: Number of entries lacking this field.
+        """
+        resp = self.dev.apdu_exchange(0x30, p1=field)
+        assert len(resp) == 4
+        return int.from_bytes(resp, 'big')
+
+    def get_missing(self, field: int) -> List[str]:
+        """
+        :param field: Checked field (MISSING_* constants).
+        :return: Names of the entries lacking this field.
+        """
+        return [bytes_to_str(self.dev.apdu_exchange(0x30,
+            i.to_bytes(4, 'big'), p1=field))
+            for i in range(self.get_missing_count(field))]
+
+    def open_provisioning(self):
+        """
+        Open a provisioning session: entries created or updated until it is
+        closed can be listed with get_provisioned.
+        """
+        self.dev.apdu_exchange(0x31, p1=0x00)
+
+    def close_provisioning(self):
+        """ Close the provisioning session. """
+        self.dev.apdu_exchange(0x31, p1=0x01)
+
+    def get_provisioned(self) -> List[str]:
+        """
+        :return: Names of the entries created or updated during the last
+            provisioning session.
+        """
+        resp = self.dev.apdu_exchange(0x31, p1=0x02)
+        assert len(resp) == 4
+        return [bytes_to_str(self.dev.apdu_exchange(0x31,
+            i.to_bytes(4, 'big'), p1=0x02))
+            for i in range(int.from_bytes(resp, 'big'))]
+
+    def arm(self, name: str):
+        """
+        Arm a protected password, so it can be read by the next command.
+        :param name: Password name.
+        """
+        name_bytes = str_to_bytes_pad(name, MAX_NAME_LEN)
+        self.dev.apdu_exchange(0x32, name_bytes, p1=0x00)
+
+    def set_arm_required(self, name: str, required: bool):
+        """
+        Protect a password, which can then only be read right after being
+        armed.
+        :param name: Password name.
+        :param required: True to protect the password, False to stop.
+        """
+        name_bytes = str_to_bytes_pad(name, MAX_NAME_LEN)
+        self.dev.apdu_exchange(0x32, name_bytes,
+            p1={True: 0x01, False: 0x02}[required])
+
+    def get_flags(self, start: int = 0, count: int = MAX_ENTRIES) -> List[int]:
+        """
+        :param start: Index of the first entry.
+        :param count: Number of entries.
+        :return: Status byte (FLAG_* and STATUS_* bits) of each entry of the
+            range, which is truncated to the stored entries.
+        """
+        flags = []
+        while count > 0:
+            data = (start.to_bytes(4, 'big')
+                + min(count, MAX_FLAGS).to_bytes(4, 'big'))
+            resp = self.dev.apdu_exchange(0x34, data)
+            assert len(resp) == 1 + resp[0]
+            if resp[0] == 0:
+                break
+            flags += list(resp[1:])
+            start += resp[0]
+            count -= resp[0]
+        return flags
+
+    def match_names(self, pattern: str) -> Tuple[int, List[int]]:
+        """
+        Find the entries whose name matches a glob pattern, with `*` and `?`.
+        :param pattern: Glob pattern.
+        :return: Number of matching entries, and their indexes. The list of
+            indexes is truncated to MAX_MATCHES entries.
+        """
+        resp = self.dev.apdu_exchange(0x33, pattern.encode(), p1=0x01)
+        return int.from_bytes(resp[:4], 'big'), [i for i in resp[4:]]
+
+    def get_duplicate_names(self) -> List[int]:
+        """
+        Check the consistency of the store, whose names should be unique.
+        :return: Indexes of the entries named as an entry with a lower index,
+            which lookups by name never find.
+        """
+        resp = self.dev.apdu_exchange(0x42)
+        count = int.from_bytes(resp[:4], 'big')
+        assert len(resp) == 4 + count
+        return [i for i in resp[4:]]
+
+    def wipe_password(self, name: str):
+        """
+        Erase a password, keeping its entry with an empty password.
+        :param name: Password name.
+        """
+        name_bytes = str_to_bytes_pad(name, MAX_NAME_LEN)
+        self.dev.apdu_exchange(0x28, name_bytes)
+
+    def set_tag(self, name: str, tag: int):
+        """
+        Set the tag of a password, used to export a single category.
+        :param name: Password name.
+        :param tag: Tag value, 0 for untagged.
+        """
+        name_bytes = str_to_bytes_pad(name, MAX_NAME_LEN)
+        self.dev.apdu_exchange(0x19, name_bytes + bytes([tag]))
+
+    def set_hint(self, name: str, hint: str):
+        """
+        Set the format hint of a password, used to present it (see
+        apply_hint).
+        :param name: Password name.
+        :param hint: Format hint, empty to remove it.
+        """
+        name_bytes = str_to_bytes_pad(name, MAX_NAME_LEN)
+        hint_bytes = hint.encode()
+        assert len(hint_bytes) <= MAX_HINT_LEN
+        self.dev.apdu_exchange(0x3d, name_bytes + hint_bytes)
+
+    def get_hint(self, name: str) -> str:
+        """
+        :param name: Password name.
+        :return: Format hint of the password, empty if it has none.
+        """
+        name_bytes = str_to_bytes_pad(name, MAX_NAME_LEN)
+        return bytes(self.dev.apdu_exchange(0x3e, name_bytes)).decode()
+
+    def export(self, encrypt: bool=True, start: int=0,
+        tag: Optional[int] = None, passphrase: bool = False,
+        compact: bool = False, chacha: bool = False
+        ) -> Tuple[str, List[bytes]]:
         """
         Export passwords.
         :param encrypt: True to encrypt passwords during export, False to export
             in plaintext.
-        :return: Exported entries.
+        :param passphrase: Encrypt with the key derived from the passphrase
+            given with set_passphrase, instead of the seed.
+        :param start: Index of the first entry to export. Used to resume an
+            interrupted export.
+        :param tag: If set, only export the entries with this tag.
+        :param compact: Export plaintext entries without padding, each field
+            prefixed with its length.
+        :param chacha: Encrypt with ChaCha20-Poly1305 instead of AES-256-CBC,
+            so entries encrypted with a passphrase can be decrypted without a
+            device (see decrypt_entry).
+        :return: Device label and exported entries, starting from index `start`.
+        :raise IncompleteExport: if the device did not send all the entries.
+        """
+        label, entries, _ = self._export(encrypt, start, tag, passphrase,
+            False, compact, chacha)
+        return label, entries
+
+    def export_sealed(self, encrypt: bool=True, tag: Optional[int] = None,
+        passphrase: bool = False, compact: bool = False, chacha: bool = False
+        ) -> Tuple[str, List[bytes], bytes]:
+        """
+        Export passwords with a seal, which authenticates the whole export even
+        in plaintext. Only a device with the same seed can check it.
+        :param encrypt: True to encrypt passwords during export, False to export
+            in plaintext.
+        :param passphrase: Encrypt with the key derived from the passphrase
+            given with set_passphrase, instead of the seed.
+        :param tag: If set, only export the entries with this tag.
+        :param compact: Export plaintext entries without padding, each field
+            prefixed with its length.
+        :param chacha: Encrypt with ChaCha20-Poly1305 instead of AES-256-CBC.
+        :return: Device label, exported entries and seal (salt and HMAC).
+        :raise IncompleteExport: if the device did not send all the entries.
         """
+        return self._export(encrypt, 0, tag, passphrase, True, compact,
+            chacha)
+
+    def _export(self, encrypt: bool, start: int, tag: Optional[int],
+        passphrase: bool, seal: bool, compact: bool, chacha: bool
+        ) -> Tuple[str, List[bytes], bytes]:
+        assert not (compact and encrypt)
+        assert encrypt or not chacha
         p1 = 0x01
         if not encrypt:
             p1 = 0x00
-        count = int.from_bytes(self.dev.apdu_exchange(0x07, p1=p1), 'big')
+        elif passphrase:
+            p1 = 0x02
+        data = b""
+        if start > 0 or tag is not None:
+            data = start.to_bytes(4, 'big')
+        if tag is not None:
+            data += bytes([tag])
+        p2 = 0x01
+        if seal:
+            p2 |= 0x02
+        if compact:
+            p2 |= 0x04
+        if chacha:
+            p2 |= 0x08
+        r = self.dev.apdu_exchange(0x07, data, p1=p1, p2=p2)
+        count = int.from_bytes(r[:4], 'big')
+        label = bytes_to_str(r[4:4+MAX_LABEL_LEN])
+        salt = r[4+MAX_LABEL_LEN:]
         entries = []
-        for i in range(count):
+        for i in range(start, count):
             entries.append(self.dev.apdu_exchange(0x08))
-        return entries
+        # The trailer gives the number of entries actually sent, followed by
+        # the HMAC of the seal
+        r = self.dev.apdu_exchange(0x08)
+        exported = int.from_bytes(r[:4], 'big')
+        if exported != len(entries):
+            raise IncompleteExport(
+                "Device sent {} entries, {} expected".format(exported,
+                len(entries)))
+        return label, entries, salt + r[4:]
 
-    def import_(self, version, entries: List[bytes], encrypted: bool):
+    def import_(self, version, entries: List[bytes], encrypted: bool,
+        passphrase: bool = False, rename: bool = False,
+        seal: Optional[bytes] = None, compact: bool = False,
+        announced: Optional[int] = None, newest: bool = False,
+        unverified: bool = False, chacha: bool = False) -> Tuple[int, int]:
         """
         Import password entries.
         :param version: Export file version, used for migration.
         :param entries: Password entries to be imported.
         :param encrypted: True if the entries are encrypted, False if it is in
             plaintext.
+        :param passphrase: Entries are encrypted with the key derived from the
+            passphrase given with set_passphrase, instead of the seed.
+        :param rename: Entries with the name of a stored entry are stored
+            under a new name, with a numeric suffix, instead of replacing it.
+        :param seal: Seal returned by export_sealed. The entries are verified
+            with the seal first, so nothing is imported from a corrupted
+            backup, unless unverified is set, as they cannot be verified.
+        :param compact: Entries are in plaintext, exported with compact=True.
+        :param announced: Number of entries announced by the export file. If
+            set, the device checks it matches the number of entries sent, to
+            detect truncated files.
+        :param newest: Entries with the name of a stored entry replace it only
+            if they have been modified more recently, so merging several
+            backups does not depend on their order.
+        :param unverified: Encrypted entries are sent without their MAC, and
+            are not authenticated by the device. Only use this over a trusted
+            channel: corrupted entries would be imported.
+        :param chacha: Entries are encrypted with ChaCha20-Poly1305, exported
+            with chacha=True.
+        :return: Number of entries skipped because their name is reserved by
+            the decoy password, or because the stored entry is newer, and
+            number of renamed entries.
         """
         # We don't support import on 1.0.0 anymore.
         # App must be upgraded. Password exports from 1.0.0 can be imported.
@@ -187,18 +821,141 @@ class Client:
             raise BadVersion("Cannot import version < 1.1.0 encrypted exports")
         apdu = bytearray(b'\x80\x09\x00\x00\x04' +
             len(entries).to_bytes(4, 'big'))
+        # The device stores each entry as it is received, and can only check
+        # the seal after the last one: check it first.
+        if seal is not None and not unverified:
+            _, invalid = self.verify_backup(entries, encrypted, passphrase,
+                seal, compact, chacha)
+            if invalid:
+                raise CorruptedBackup("{} invalid entries".format(invalid))
         p1 = 0x00
         if encrypted:
             p1 = 0x01
-        r = self.dev.apdu_exchange(
-            0x09, p1=p1, data=len(entries).to_bytes(4, 'big'))
+            if passphrase:
+                p1 = 0x02
+        p2 = {False: 0x00, True: 0x01}[rename]
+        count = len(entries) if announced is None else announced
+        data = count.to_bytes(4, 'big')
+        if seal is not None:
+            p2 |= 0x02
+            data += seal[:16]
+        if compact:
+            assert not encrypted
+            p2 |= 0x08
+        if announced is not None:
+            p2 |= 0x10
+            data += len(entries).to_bytes(4, 'big')
+        if newest:
+            p2 |= 0x20
+        if unverified:
+            assert encrypted and not chacha
+            p2 |= 0x40
+        if chacha:
+            assert encrypted
+            p2 |= 0x80
+        r = self.dev.apdu_exchange(0x09, data, p1=p1, p2=p2)
+        skipped = 0
+        renamed = 0
         for p in entries:
             if version < "1.1.0":
                 # Patch the data blob to add login
                 assert encrypted == False
                 p = p[:32] + (b"\x00" * 32) + p[32:64]
-            assert len(p) == {True: 16+96+16, False: 96}[encrypted]
-            self.dev.apdu_exchange(0x0a, p)
+            # Entries exported by older versions have no metadata block
+            nonce_len = 12 if chacha else 16
+            assert compact or len(p) in {True: (nonce_len+96+16,
+                nonce_len+112+16), False: (96, 112)}[encrypted]
+            if unverified:
+                p = p[:-16]
+            r = self.dev.apdu_exchange(0x0a, p)
+            skipped += r[0] in (IMPORT_RESERVED, IMPORT_OLDER)
+            renamed += r[0] == IMPORT_RENAMED
+        if seal is not None or announced is not None:
+            self.dev.apdu_exchange(0x0a, seal[16:] if seal else b"",
+                p1=int(announced is not None))
+        return skipped, renamed
+
+    def verify_backup(self, entries: List[bytes], encrypted: bool,
+        passphrase: bool = False, seal: Optional[bytes] = None,
+        compact: bool = False, chacha: bool = False) -> Tuple[int, int]:
+        """
+        Check the MACs of exported entries, without importing them.
+        :param entries: Exported entries.
+        :param encrypted: True if the entries are encrypted, False if it is in
+            plaintext.
+        :param passphrase: Entries are encrypted with the key derived from the
+            passphrase given with set_passphrase, instead of the seed.
+        :param seal: Seal returned by export_sealed, if any.
+        :param compact: Entries are in plaintext, exported with compact=True.
+        :param chacha: Entries are encrypted with ChaCha20-Poly1305, exported
+            with chacha=True.
+        :return: Number of valid entries and number of invalid entries.
+        """
+        p1 = 0x00
+        if encrypted:
+            p1 = {False: 0x01, True: 0x02}[passphrase]
+        p2 = 0x04
+        data = len(entries).to_bytes(4, 'big')
+        if seal is not None:
+            p2 |= 0x02
+            data += seal[:16]
+        if compact:
+            assert not encrypted
+            p2 |= 0x08
+        if chacha:
+            assert encrypted
+            p2 |= 0x80
+        self.dev.apdu_exchange(0x09, data, p1=p1, p2=p2)
+        for p in entries:
+            r = self.dev.apdu_exchange(0x0a, p)
+            assert r[0] in (IMPORT_STORED, IMPORT_INVALID)
+        r = self.dev.apdu_exchange(0x0a, b"" if seal is None else seal[16:])
+        assert len(r) == 8
+        return int.from_bytes(r[:4], 'big'), int.from_bytes(r[4:], 'big')
+
+    def set_passphrase(self, passphrase: str,
+        iterations: int = DEFAULT_KDF_ITERATIONS,
+        salt: Optional[bytes] = None) -> bytes:
+        """
+        Derive the key used to encrypt exports independently of the seed.
+        :param passphrase: Passphrase.
+        :param iterations: Number of PBKDF2 iterations.
+        :param salt: Salt of the backup to be imported. None to generate a new
+            one for an export.
+        :return: Salt, to be stored in the backup.
+        """
+        assert 0 < iterations <= MAX_KDF_ITERATIONS
+        data = iterations.to_bytes(4, 'big')
+        if salt is None:
+            p1 = 0x00
+        else:
+            p1 = 0x01
+            assert len(salt) == 16
+            data += salt
+        data += passphrase.encode()
+        return self.dev.apdu_exchange(0x1d, data, p1=p1)
+
+    def import_blob(self, entries: List[bytes]) -> int:
+        """
+        Import a few plaintext password entries with a single APDU.
+        :param entries: Password entries to be imported, as exported in
+            plaintext. At most MAX_BLOB_ENTRIES entries.
+        :return: Number of entries skipped because their name is reserved by
+            the decoy password.
+        """
+        assert len(entries) <= MAX_BLOB_ENTRIES
+        assert len(set(len(p) for p in entries)) <= 1
+        assert all(len(p) in (96, 112) for p in entries)
+        r = self.dev.apdu_exchange(0x12,
+            bytes([len(entries)]) + b"".join(entries))
+        return r[0]
+
+    def is_import_pending(self) -> bool:
+        """
+        :return: True if the last import has not completed, which means the
+            store may only hold a part of the backup.
+        """
+        return self.dev.apdu_exchange(0x46)[0] == 1
 
     def clear(self):
         """ Remove all passwords """
@@ -212,6 +969,426 @@ class Client:
         assert res[0] in (0, 1)
         return bool(res[0])
 
+    def get_reuse_count(self) -> int:
+        """
+        :return: Number of entries sharing their password with at least one
+            other entry.
+        """
+        resp = self.dev.apdu_exchange(0x0f)
+        assert len(resp) == 4
+        return int.from_bytes(resp, 'big')
+
+    def set_gen_defaults(self, length: int, classes: int, symbols: str = "",
+        minimums: Optional[Tuple[int, int, int, int]] = None):
+        """
+        Set the parameters of passwords generated by the device.
+        :param length: Length of generated passwords.
+        :param classes: Bitmask of character classes (see CLASS_* constants).
+        :param symbols: Characters of the symbol class.
+        :param minimums: Minimum number of lowercase letters, uppercase letters,
+            digits and symbols.
+        """
+        data = gen_params_bytes(length, classes, symbols, minimums)
+        self.dev.apdu_exchange(0x1b, data, p1=0x00)
+
+    def reset_gen_defaults(self):
+        """
+        Restore factory parameters of passwords generated by the device.
+        """
+        self.dev.apdu_exchange(0x1b, p1=0x01)
+
+    def get_gen_defaults(self) -> Tuple[int, int, str,
+        Tuple[int, int, int, int]]:
+        """
+        :return: Length, bitmask of character classes (see CLASS_* constants),
+            characters of the symbol class and minimum number of lowercase
+            letters, uppercase letters, digits and symbols of passwords
+            generated by the device.
+        """
+        r = self.dev.apdu_exchange(0x45)
+        assert len(r) == 2 + MAX_SYMBOLS_LEN + 4
+        symbols = bytes_to_str(r[2:2+MAX_SYMBOLS_LEN])
+        return (r[0], r[1], symbols, tuple(r[2+MAX_SYMBOLS_LEN:]))
+
+    def challenge_response(self, name: str, challenge: bytes) -> bytes:
+        """
+        Compute HMAC-SHA1 of a challenge, keyed with a stored password.
+        :param name: Password name.
+        :param challenge: Challenge bytes, not empty.
+        :return: 20 bytes HMAC.
+        """
+        assert 0 < len(challenge) <= MAX_CHALLENGE_LEN
+        resp = self.dev.apdu_exchange(0x1c,
+            str_to_bytes_pad(name, MAX_NAME_LEN) + challenge)
+        assert len(resp) == 20
+        return resp
+
+    def rotate_tag(self, tag: int) -> int:
+        """
+        Regenerate the passwords of all the entries with the given tag.
+        :param tag: Tag value.
+        :return: Number of regenerated passwords.
+        """
+        resp = self.dev.apdu_exchange(0x20, bytes([tag]))
+        assert len(resp) == 4
+        return int.from_bytes(resp, 'big')
+
+    def compare_passwords(self, name_a: str, name_b: str) -> bool:
+        """
+        :return: True if the passwords of the two entries are identical.
+        """
+        resp = self.dev.apdu_exchange(0x1a,
+            str_to_bytes_pad(name_a, MAX_NAME_LEN) +
+            str_to_bytes_pad(name_b, MAX_NAME_LEN))
+        assert len(resp) == 1
+        return resp[0] == 1
+
+    def find_password(self, password: Union[str, bytes]
+        ) -> Tuple[int, List[str]]:
+        """
+        Find the entries using a password, without reading any password.
+        :param password: Password, or binary secret.
+        :return: Number of entries using the password, and names of the first
+            7 of them.
+        """
+        if isinstance(password, str):
+            password = password.encode()
+        r = self.dev.apdu_exchange(0x47, password)
+        count = int.from_bytes(r[:4], 'big')
+        names = [bytes_to_str(r[i:i+32]) for i in range(4, len(r), 32)]
+        return count, names
+
+    def check_password(self, password: str) -> int:
+        """
+        Check if a password can be stored as is, without storing it.
+        :param password: Candidate password.
+        :return: 0 if accepted, otherwise a reason code (see CHECK_* constants).
+        """
+        data = password.encode()
+        assert len(data) <= 255
+        resp = self.dev.apdu_exchange(0x1f, data)
+        assert len(resp) == 1
+        return resp[0]
+
+    def get_letter_counts(self) -> Dict[str, int]:
+        """
+        :return: Number of entries by first letter of their name, from 'A' to
+            'Z' (ignoring case). Other first characters are counted in '#'.
+        """
+        resp = self.dev.apdu_exchange(0x1e)
+        assert len(resp) == 27
+        return dict(zip(string.ascii_uppercase + '#', resp))
+
+    def get_tag_counts(self) -> Dict[int, int]:
+        """
+        :return: Number of entries with each tag in use. Untagged entries are
+            counted under tag 0.
+        """
+        counts = {}
+        start = 0
+        while start <= 0xff:
+            resp = self.dev.apdu_exchange(0x41, bytes([start]))
+            assert len(resp) % 2 == 0
+            counts.update(zip(resp[0::2], resp[1::2]))
+            if len(resp) < 2 * MAX_TAG_COUNTS:
+                break
+            start = resp[-2] + 1
+        return counts
+
+    def get_stale_count(self, age: int) -> int:
+        """
+        :param age: Number of modifications after which an entry is stale.
+        :return: Number of entries not created or updated during the last
+            `age` modifications.
+        """
+        resp = self.dev.apdu_exchange(0x13, age.to_bytes(4, 'big'))
+        assert len(resp) == 4
+        return int.from_bytes(resp, 'big')
+
+    def set_decoy(self, name: str, login: str = "", password: str = ""):
+        """
+        Set the decoy password, returned instead of any stored password when
+        its name is requested.
+        :param name: Decoy name. Empty to disable the decoy.
+        :param login: Decoy login.
+        :param password: Decoy password.
+        """
+        self.dev.apdu_exchange(0x18,
+            str_to_bytes_pad(name, MAX_NAME_LEN) +
+            str_to_bytes_pad(login, MAX_LOGIN_LEN) +
+            str_to_bytes_pad(password, MAX_PASS_LEN))
+
+    def get_password_classes(self, name: str) -> Tuple[int, int]:
+        """
+        :param name: Password name.
+        :return: Bitmask of the character classes present in the password (see
+            CLASS_* constants), and length of the password.
+        """
+        resp = self.dev.apdu_exchange(0x17, str_to_bytes_pad(name,
+            MAX_NAME_LEN))
+        assert len(resp) == 2
+        return resp[0], resp[1]
+
+    def get_storage_usage(self) -> Tuple[int, int]:
+        """
+        :return: Number of NVM bytes used by the stored passwords, and total
+            number of bytes reserved for the password store.
+        """
+        resp = self.dev.apdu_exchange(0x14)
+        assert len(resp) == 8
+        return (int.from_bytes(resp[:4], 'big'),
+                int.from_bytes(resp[4:], 'big'))
+
+    def get_derivation_path(self) -> List[int]:
+        """
+        :return: Components of the BIP32 path used to derive the encryption
+            key of exports.
+        """
+        resp = self.dev.apdu_exchange(0x15)
+        count = resp[0]
+        assert len(resp) == 1 + 4 * count
+        return [int.from_bytes(resp[1 + 4 * i:5 + 4 * i], 'big')
+                for i in range(count)]
+
+    def get_commands(self) -> List[int]:
+        """
+        :return: INS codes of the commands supported by the application.
+        """
+        return list(self.dev.apdu_exchange(0x22))
+
+    def benchmark(self, count: int) -> float:
+        """
+        Measure the time the device needs to encrypt and MAC exported entries.
+        Dummy entries are used, stored passwords are not read.
+        :param count: Number of entries, at most MAX_BENCHMARK_RECORDS.
+        :return: Time per entry in seconds, without the transport overhead.
+        """
+        assert 0 < count <= MAX_BENCHMARK_RECORDS
+        start = time.perf_counter()
+        self.dev.apdu_exchange(0x23, bytes([0]))
+        overhead = time.perf_counter() - start
+        start = time.perf_counter()
+        r = self.dev.apdu_exchange(0x23, bytes([count]))
+        elapsed = time.perf_counter() - start
+        assert r[0] == count
+        return max(elapsed - overhead, 0) / count
+
+    def get_add_count(self) -> int:
+        """
+        :return: Number of entries added or updated since the application
+            started.
+        """
+        resp = self.dev.apdu_exchange(0x27)
+        assert len(resp) == 4
+        return int.from_bytes(resp, 'big')
+
+    def check_rng(self) -> bool:
+        """
+        Run health tests on the random number generator of the device.
+        :return: True if the tests pass.
+        """
+        resp = self.dev.apdu_exchange(0x29)
+        assert len(resp) == 1
+        return resp[0] == 0
+
+    def get_confirmation_count(self) -> int:
+        """
+        :return: Number of confirmations approved by the user on the device
+            since the application was installed.
+        """
+        resp = self.dev.apdu_exchange(0x2a)
+        assert len(resp) == 4
+        return int.from_bytes(resp, 'big')
+
+    def set_max_entries(self, max_entries: int):
+        """
+        Limit the number of stored entries below the capacity of the device.
+        :param max_entries: Maximum number of entries, 0 to remove the limit.
+        """
+        assert 0 <= max_entries <= MAX_ENTRIES
+        self.dev.apdu_exchange(0x2b, max_entries.to_bytes(4, 'big'))
+
+    def export_uri(self, name: str) -> str:
+        """
+        Export an entry as a URI, in plaintext: otpauth for binary secrets, or
+        nanopass for passwords.
+        :param name: Password name.
+        :return: URI of the entry.
+        """
+        r = self.dev.apdu_exchange(0x4a, str_to_bytes_pad(name, MAX_NAME_LEN))
+        return bytes(r).decode()
+
+    def import_uri(self, uri: str):
+        """
+        Import an entry from a URI, as returned by export_uri. A stored entry
+        with the same name is replaced.
+        :param uri: otpauth or nanopass URI.
+        """
+        self.dev.apdu_exchange(0x4b, uri.encode())
+
+    def import_totp(self, uris: List[str]) -> List[int]:
+        """
+        Import TOTP secrets from otpauth URIs, as exported by authenticator
+        applications. A stored entry with the same name is replaced. The
+        secrets can then only be used by get_totp: reading or exporting them
+        in plaintext fails with TotpOnly.
+        :param uris: otpauth URIs.
+        :return: Status of each URI: TOTP_STORED, TOTP_RESERVED if its name is
+            reserved by the decoy password, or TOTP_INVALID if it is malformed
+            or describes codes the device cannot compute.
+        """
+        assert len(uris) <= MAX_ENTRIES
+        self.dev.apdu_exchange(0x4e, len(uris).to_bytes(4, 'big'))
+        return [self.dev.apdu_exchange(0x0a, uri.encode())[0] for uri in uris]
+
+    def get_totp(self, name: str, timestamp: Optional[int] = None) -> str:
+        """
+        Compute the TOTP code of a secret imported with import_totp.
+        :param name: Password name.
+        :param timestamp: Unix time, current time if None.
+        :return: Code, 6 digits.
+        """
+        if timestamp is None:
+            timestamp = int(time.time())
+        r = self.dev.apdu_exchange(0x4c, str_to_bytes_pad(name, MAX_NAME_LEN)
+            + timestamp.to_bytes(8, 'big'))
+        assert len(r) == 6
+        return bytes(r).decode()
+
+    def get_totp_remaining(self, name: str,
+            timestamp: Optional[int] = None) -> int:
+        """
+        Get the number of seconds before the TOTP code of a TOTP secret
+        changes.
+        :param name: Password name.
+        :param timestamp: Unix time, current time if None.
+        :return: Seconds, from 1 to 30.
+        """
+        if timestamp is None:
+            timestamp = int(time.time())
+        r = self.dev.apdu_exchange(0x4d, str_to_bytes_pad(name, MAX_NAME_LEN)
+            + timestamp.to_bytes(8, 'big'))
+        assert len(r) == 1
+        return r[0]
+
+    def sort_by_name(self) -> int:
+        """
+        Reorder the entries by name, so their indexes follow the alphabetical
+        order.
+        :return: Number of swaps done by the device.
+        """
+        r = self.dev.apdu_exchange(0x49)
+        assert len(r) == 4
+        return int.from_bytes(r, 'big')
+
+    def get_status(self) -> Dict[str, int]:
+        """
+        :return: Overview of the store: number of entries ('count'), maximum
+            number of entries ('capacity'), number of entries which can still
+            be added ('free') and 1 if no entry can be added ('full').
+        """
+        r = self.dev.apdu_exchange(0x48)
+        assert r[0] >= 1 and len(r) >= 14
+        return {
+            'count': int.from_bytes(r[1:5], 'big'),
+            'capacity': int.from_bytes(r[5:9], 'big'),
+            'free': int.from_bytes(r[9:13], 'big'),
+            'full': r[13]
+        }
+
+    def get_max_entries(self) -> int:
+        """
+        :return: Maximum number of stored entries.
+        """
+        resp = self.dev.apdu_exchange(0x2c)
+        assert len(resp) == 4
+        return int.from_bytes(resp, 'big')
+
+    def get_next_index(self) -> int:
+        """
+        :return: Index the next created entry will have.
+        """
+        resp = self.dev.apdu_exchange(0x2d)
+        assert len(resp) == 4
+        return int.from_bytes(resp, 'big')
+
+    def verify_key(self) -> Tuple[bool, bytes, bytes]:
+        """
+        Check the encryption key derived from the seed has not changed.
+        :return: Whether the fingerprints match, the recorded fingerprint and
+            the fingerprint of the current key.
+        """
+        resp = self.dev.apdu_exchange(0x2e)
+        assert len(resp) == 17
+        return resp[0] == 1, resp[1:9], resp[9:]
+
+    def record_key(self):
+        """
+        Record the fingerprint of the current encryption key, after a seed
+        change.
+        """
+        self.dev.apdu_exchange(0x2e, p1=1)
+
+    def get_store_digest(self) -> bytes:
+        """
+        :return: Digest of all the stored entries, identical for devices with
+            the same seed and the same entries.
+        """
+        r = self.dev.apdu_exchange(0x25)
+        assert len(r) == 20
+        return bytes(r)
+
+    def verify_store_digest(self, digest: bytes) -> bool:
+        """
+        :param digest: Store digest recorded earlier, for instance when making
+            a backup (see get_store_digest).
+        :return: True if the store still matches the digest.
+        """
+        assert len(digest) == 20
+        r = self.dev.apdu_exchange(0x3f, digest)
+        assert len(r) == 1
+        return r[0] == 1
+
+    def get_entry_digest(self, name: str) -> bytes:
+        """
+        :param name: Password name.
+        :return: Digest of the entry, identical for devices with the same seed
+            and the same entry.
+        """
+        r = self.dev.apdu_exchange(0x26, str_to_bytes_pad(name, MAX_NAME_LEN))
+        assert len(r) == 20
+        return bytes(r)
+
+    def set_label(self, label: str):
+        """
+        Set the label of the device, displayed on the idle screen.
+        :param label: New label. Empty to restore the default label.
+        """
+        self.dev.apdu_exchange(0x10, str_to_bytes_pad(label, MAX_LABEL_LEN))
+
+    def set_idle_mode(self, mode: int):
+        """
+        Select the content of the idle screen.
+        :param mode: One of the IDLE_* constants.
+        """
+        self.dev.apdu_exchange(0x24, p1=mode)
+
+    def get_label(self) -> str:
+        """ :return: Label of the device. Empty if the default is used. """
+        return bytes_to_str(self.dev.apdu_exchange(0x11))
+
+    def set_checkword(self, checkword: str):
+        """
+        Set the recovery checkword of the store, to recognize it once restored.
+        :param checkword: New checkword. Empty to clear it.
+        """
+        self.dev.apdu_exchange(0x43,
+            str_to_bytes_pad(checkword, MAX_CHECKWORD_LEN))
+
+    def get_checkword(self) -> str:
+        """ :return: Recovery checkword of the store. Empty if not set. """
+        return bytes_to_str(self.dev.apdu_exchange(0x44))
+
 
 @click.group()
 @click.pass_context
@@ -226,45 +1403,220 @@ def version(ctx):
     dev = ctx.obj['DEV']
     print(dev.get_version())
 
+@cli.command(help="Estimate the Flash pages written by an operation")
+@click.option('--import', 'count', type=int,
+    help="Estimate for importing this number of passwords instead of clearing")
+@click.pass_context
+def wear(ctx, count):
+    dev = ctx.obj['DEV']
+    if count is None:
+        print("Clear:", dev.get_clear_wear(), "pages")
+    else:
+        print("Import:", dev.get_import_wear(count), "pages")
+
+@cli.command(help="List the passwords from the weakest to the strongest")
+@click.option('--count', type=int, help="Only list the weakest passwords")
+@click.pass_context
+def weakest(ctx, count):
+    dev = ctx.obj['DEV']
+    for name in dev.get_weakest()[:count]:
+        print(name)
+
+@cli.command(help="List the passwords lacking required character classes")
+@click.option('--lower', is_flag=True, help="Require a lowercase letter")
+@click.option('--upper', is_flag=True, help="Require an uppercase letter")
+@click.option('--digit', is_flag=True, help="Require a digit")
+@click.option('--symbol', is_flag=True, help="Require a symbol")
+@click.pass_context
+def lacking(ctx, lower, upper, digit, symbol):
+    dev = ctx.obj['DEV']
+    required = ((CLASS_LOWER if lower else 0) | (CLASS_UPPER if upper else 0)
+        | (CLASS_DIGIT if digit else 0) | (CLASS_SYMBOL if symbol else 0))
+    if required == 0:
+        raise click.UsageError("At least one class must be required")
+    for name in dev.get_lacking_classes(required):
+        print(name)
+
+@cli.command(help="Print the lengths of the shortest and longest passwords")
+@click.pass_context
+def lengths(ctx):
+    dev = ctx.obj['DEV']
+    shortest, longest = dev.get_length_range()
+    print("Shortest password:", shortest)
+    print("Longest password:", longest)
+
+@cli.command(help="Print the number of modifications spanned by the store")
+@click.pass_context
+def span(ctx):
+    dev = ctx.obj['DEV']
+    oldest, newest, current = dev.get_sequence_range()
+    print("Store spans", newest - oldest, "modifications")
+    print("Last modification", current - newest, "modifications ago")
+
+@cli.command(help="Print the free space of the stack of the device")
+@click.pass_context
+def stack(ctx):
+    dev = ctx.obj['DEV']
+    free, size = dev.get_stack_usage()
+    print("Stack: {} bytes free out of {}".format(free, size))
+
+@cli.command(help="Print the stored length of the name of an entry")
+@click.argument("index", type=int)
+@click.pass_context
+def namelen(ctx, index):
+    dev = ctx.obj['DEV']
+    print(dev.get_name_length(index))
+
+@cli.command(help="Print the device model and its features")
+@click.pass_context
+def capabilities(ctx):
+    dev = ctx.obj['DEV']
+    model, features, (width, height) = dev.get_capabilities()
+    print("Model:", {MODEL_NANO_S: "Nano S"}.get(model, model))
+    print("Bluetooth:", "yes" if features & CAPABILITY_BLE else "no")
+    print("Screen: {}x{}".format(width, height))
+
 @cli.command(help="Inserts a new password")
 @click.argument('name')
 @click.option('--login', default="")
+@click.option('--preview', is_flag=True,
+    help="Review generated password on the device before storing it")
+@click.option('--binary', is_flag=True,
+    help="Store a binary secret, given in hexadecimal")
+@click.option('--exclude-name', is_flag=True,
+    help="Do not use the characters of the name in generated password")
+@click.option('--pronounceable', is_flag=True,
+    help="Generate a password made of pronounceable syllables")
 @click.pass_context
-def insert(ctx, name, login):
+def insert(ctx, name, login, preview, binary, exclude_name, pronounceable):
+    dev = ctx.obj['DEV']
+    if binary:
+        secret = bytes.fromhex(input("Secret (hexadecimal):"))
+        print("Confirm password creation on your device...")
+        dev.add_binary(name, login, secret)
+        return
     password = input("Password (empty to generate):")
     if len(password) == 0:
         password = None
     print("Confirm password creation on your device...")
-    dev = ctx.obj['DEV']
-    dev.add(name, login, password)
+    updated, fingerprint = dev.add(name, login, password, preview,
+        exclude_name, pronounceable)
+    print("Changed" if updated else "Added")
+    print("fingerprint:", fingerprint)
 
 @cli.command(help="Print a stored password")
 @click.pass_context
 @click.argument('name')
-def get(ctx, name):
+@click.option('--base64', 'encoded', is_flag=True,
+    help="Transfer the password encoded in base64")
+@click.option('--arm', is_flag=True, help="Arm a protected password first")
+@click.option('--once', is_flag=True,
+    help="Mark the password to be rotated once read")
+@click.option('--qr', is_flag=True,
+    help="Display the password as a QR code, to scan it with a phone")
+@click.option('--prefix', is_flag=True,
+    help="Accept the beginning of the name, if only one name matches")
+@click.option('--start', type=click.IntRange(1, MAX_PARTIAL_READ),
+    help="Only print the first characters of the password, to check it")
+def get(ctx, name, encoded, arm, once, qr, prefix, start):
     dev = ctx.obj['DEV']
-    if not dev.has_name(name):
+    if prefix and (encoded or arm or qr):
+        raise click.UsageError(
+            "--prefix cannot be used with --base64, --arm or --qr")
+    if start and (encoded or qr or prefix or once):
+        raise click.UsageError(
+            "--start cannot be used with --base64, --qr, --prefix or --once")
+    if not prefix and not dev.has_name(name):
         print("Credentials not found")
         return
-    print("Confirm access on device...")
-    login, password = dev.get_by_name(name)
+    if start:
+        print("Confirm access on device...")
+        if arm:
+            dev.arm(name)
+        login, password_start = dev.get_password_start(name, start)
+        if len(login):
+            print("login:", login)
+        print("start:", password_start.decode(errors='replace'))
+        return
+    if prefix:
+        if not any(n.startswith(name) for n in dev.get_names()):
+            print("Credentials not found")
+            return
+        try:
+            print("Confirm access on device if a single name matches...")
+            name, login, password = dev.get_by_prefix(name,
+                mark_rotation=once)
+        except AmbiguousName as e:
+            print(e.count, "names match, select one of them:")
+            for candidate in e.names:
+                print(" ", candidate)
+            if e.count > len(e.names):
+                print("  ...")
+            return
+        print("name:", name)
+    else:
+        print("Confirm access on device...")
+    if arm:
+        dev.arm(name)
+    if qr:
+        try:
+            import qrcode
+        except ImportError:
+            raise click.ClickException(
+                "The qrcode package is required to display QR codes")
+        login, payload = dev.get_by_name_qr(name, mark_rotation=once)
+        if len(login):
+            print("login:", login)
+        code = qrcode.QRCode()
+        code.add_data(payload)
+        code.print_ascii(invert=True)
+        return
+    if encoded:
+        login, password = dev.get_by_name_base64(name, mark_rotation=once)
+        try:
+            password = password.decode()
+        except UnicodeDecodeError:
+            pass
+    elif not prefix:
+        login, password = dev.get_by_name(name, mark_rotation=once)
     if len(login):
         print("login:", login)
-    print("password:", password)
+    if isinstance(password, bytes):
+        print("secret:", password.hex())
+    else:
+        print("password:", password)
+        hint = dev.get_hint(name)
+        if hint:
+            print("formatted:", apply_hint(hint, password))
 
 @cli.command(help="Print a stored password on the device")
 @click.pass_context
 @click.argument('name')
-def getinternal(ctx, name):
+@click.option('--split', is_flag=True,
+    help="Display the password in two halves, on two screens")
+def getinternal(ctx, name, split):
     print("Confirm password display on your device...")
     dev = ctx.obj['DEV']
-    print(dev.get_by_name_internal(name))
+    print(dev.get_by_name_internal(name, split))
+
+@cli.command(help="Swap the passwords of two entries")
+@click.argument('first')
+@click.argument('second')
+@click.pass_context
+def swap(ctx, first, second):
+    dev = ctx.obj['DEV']
+    print("Confirm the swap on your device...")
+    dev.swap_passwords(first, second)
 
 @cli.command(help="List the names of stored passwords")
+@click.option('--recent', is_flag=True,
+    help="List most recently added or updated passwords first")
+@click.option('--sorted', 'by_name', is_flag=True,
+    help="List passwords in alphabetical order")
 @click.pass_context
-def list(ctx):
+def list(ctx, recent, by_name):
     dev = ctx.obj['DEV']
-    entries = dev.get_names()
+    entries = dev.get_names(recent, by_name)
     for entry in entries:
         print('-', entry)
 
@@ -275,30 +1627,203 @@ def remove(ctx, name):
     dev = ctx.obj['DEV']
     dev.delete_by_name(name)
 
+@cli.command(help="Remove COUNT passwords, starting at index START")
+@click.argument('start', type=click.IntRange(0))
+@click.argument('count', type=click.IntRange(0))
+@click.pass_context
+def prune(ctx, start, count):
+    dev = ctx.obj['DEV']
+    print("Confirm removal on your device...")
+    print("{} passwords removed".format(dev.delete_range(start, count)))
+
+@cli.command(help="Require arming a password before reading it")
+@click.pass_context
+@click.argument('name')
+@click.option('--off', is_flag=True, help="Stop requiring arming")
+def protect(ctx, name, off):
+    dev = ctx.obj['DEV']
+    print("Confirm on your device...")
+    dev.set_arm_required(name, not off)
+
+@cli.command(help="Erase a password, keeping its name as a placeholder")
+@click.pass_context
+@click.argument('name')
+def wipe(ctx, name):
+    dev = ctx.obj['DEV']
+    print("Confirm password erasure on your device...")
+    dev.wipe_password(name)
+
 @cli.command(help="Export passwords to JSON file")
 @click.argument('path')
 @click.option('--encrypt/--no-encrypt', default=True)
+@click.option('--resume', is_flag=True,
+    help="Continue an interrupted export into an existing file")
+@click.option('--tag', type=click.IntRange(0, 255),
+    help="Only export the passwords with this tag")
+@click.option('--passphrase', is_flag=True,
+    help="Encrypt with a passphrase instead of the seed")
+@click.option('--compact', is_flag=True,
+    help="Remove the padding of plaintext passwords")
+@click.option('--cipher', type=click.Choice([CIPHER_AES_CBC,
+    CIPHER_CHACHA20_POLY1305]), default=CIPHER_AES_CBC,
+    help="Cipher suite of encrypted passwords. With a passphrase, "
+    "chacha20-poly1305 backups can be decrypted without a device")
 @click.pass_context
-def export(ctx, path, encrypt):
+def export(ctx, path, encrypt, resume, tag, passphrase, compact, cipher):
     dev = ctx.obj['DEV']
-    entries = dev.export(encrypt)
+    if compact and encrypt:
+        raise click.UsageError("Only plaintext exports can be compact")
+    chacha = cipher == CIPHER_CHACHA20_POLY1305
+    if chacha and not encrypt:
+        raise click.UsageError("Only encrypted exports have a cipher suite")
+    entries = []
+    kdf = None
+    if resume:
+        previous = json.loads(open(path, 'rb').read().decode())
+        assert previous['encrypted'] == encrypt
+        assert previous.get('compact', False) == compact
+        assert previous.get('cipher', CIPHER_AES_CBC) == cipher
+        entries = [bytes.fromhex(e) for e in previous['entries']]
+        kdf = previous.get('kdf')
+        assert (kdf is not None) == passphrase
+    if passphrase:
+        secret = click.prompt("Passphrase", hide_input=True,
+            confirmation_prompt=kdf is None)
+        if kdf is None:
+            salt = dev.set_passphrase(secret)
+            kdf = {
+                'algorithm': 'pbkdf2-hmac-sha1',
+                'iterations': DEFAULT_KDF_ITERATIONS,
+                'salt': salt.hex()
+            }
+        else:
+            dev.set_passphrase(secret, kdf['iterations'],
+                bytes.fromhex(kdf['salt']))
+    # Plaintext exports have no MAC: seal them to detect corruption, unless
+    # resuming, as the seal only covers the entries of a single transfer.
+    seal = None
+    if not encrypt and not resume:
+        label, new_entries, seal = dev.export_sealed(False, tag=tag,
+            compact=compact)
+    else:
+        label, new_entries = dev.export(encrypt, start=len(entries), tag=tag,
+            passphrase=passphrase, compact=compact, chacha=chacha)
+    entries += new_entries
     export = {
         'version': dev.get_version(),
+        'label': label,
         'encrypted': encrypt,
+        'count': len(entries),
         'entries': [binascii.hexlify(e).decode() for e in entries]
     }
+    if compact:
+        export['compact'] = True
+    if chacha:
+        export['cipher'] = cipher
+    if kdf is not None:
+        export['kdf'] = kdf
+    if seal is not None:
+        export['seal'] = seal.hex()
+    # Record the store digest, to check later if the backup is still up to
+    # date. It only describes the store if all the passwords are exported.
+    if tag is None:
+        export['digest'] = dev.get_store_digest().hex()
+    # The checkword is not a secret, and lets the user recognize the store
+    # once restored
+    checkword = dev.get_checkword()
+    if checkword:
+        export['checkword'] = checkword
     with open(path, 'wb') as f:
         f.write(json.dumps(export, indent=2).encode())
 
 @cli.command(name='import', help="Import passwords from JSON file")
 @click.argument('path')
+@click.option('--keep-both', is_flag=True,
+    help="Rename imported passwords instead of replacing stored ones")
+@click.option('--no-seal', is_flag=True,
+    help="Do not check the seal, for backups made with another seed")
+@click.option('--merge', is_flag=True,
+    help="Keep stored passwords modified more recently than imported ones")
+@click.option('--skip-mac', is_flag=True,
+    help="Do not authenticate encrypted passwords, which is faster but "
+    "imports corrupted passwords")
 @click.pass_context
-def import_(ctx, path):
+def import_(ctx, path, keep_both, no_seal, merge, skip_mac):
     dev = ctx.obj['DEV']
     data = json.loads(open(path, 'rb').read().decode())
     entries = [bytes.fromhex(e) for e in data['entries']]
     encrypted = data['encrypted']
-    dev.import_(data['version'], entries, encrypted)
+    if skip_mac and not encrypted:
+        raise click.UsageError("Only encrypted backups have MACs")
+    if data.get('label'):
+        print("Backup exported from", data['label'])
+    if dev.is_import_pending():
+        print("Warning: the previous import did not complete")
+    kdf = data.get('kdf')
+    if kdf is not None:
+        secret = click.prompt("Passphrase", hide_input=True)
+        dev.set_passphrase(secret, kdf['iterations'],
+            bytes.fromhex(kdf['salt']))
+    seal = None
+    if 'seal' in data and not no_seal:
+        seal = bytes.fromhex(data['seal'])
+    compact = data.get('compact', False)
+    chacha = data.get('cipher') == CIPHER_CHACHA20_POLY1305
+    if skip_mac and chacha:
+        raise click.UsageError("ChaCha20-Poly1305 tags are always checked")
+    # Files made by older versions do not announce their number of entries
+    announced = data.get('count')
+    renamed = 0
+    if (not encrypted) and (data['version'] >= "1.1.0") and (
+        len(entries) <= MAX_BLOB_ENTRIES) and not keep_both and (
+        seal is None) and not compact and (
+        announced in (None, len(entries))) and not merge:
+        skipped = dev.import_blob(entries)
+    else:
+        skipped, renamed = dev.import_(data['version'], entries, encrypted,
+            passphrase=kdf is not None, rename=keep_both, seal=seal,
+            compact=compact, announced=announced, newest=merge,
+            unverified=skip_mac, chacha=chacha)
+    if skipped and merge:
+        print(skipped, "passwords skipped: their name is the decoy name, or "
+            "the stored password is newer")
+    elif skipped:
+        print(skipped, "passwords skipped: their name is the decoy name")
+    if renamed:
+        print(renamed, "passwords renamed: their name was already used")
+    checkword = data.get('checkword')
+    if checkword and checkword != dev.get_checkword():
+        print("Confirm recovery checkword", repr(checkword),
+            "on your device...")
+        dev.set_checkword(checkword)
+
+@cli.command(help="Check a JSON backup file without importing it")
+@click.argument('path')
+@click.option('--no-seal', is_flag=True,
+    help="Do not check the seal, for backups made with another seed")
+@click.pass_context
+def verify(ctx, path, no_seal):
+    dev = ctx.obj['DEV']
+    data = json.loads(open(path, 'rb').read().decode())
+    entries = [bytes.fromhex(e) for e in data['entries']]
+    kdf = data.get('kdf')
+    if kdf is not None:
+        secret = click.prompt("Passphrase", hide_input=True)
+        dev.set_passphrase(secret, kdf['iterations'],
+            bytes.fromhex(kdf['salt']))
+    seal = None
+    if 'seal' in data and not no_seal:
+        seal = bytes.fromhex(data['seal'])
+    valid, invalid = dev.verify_backup(entries, data['encrypted'],
+        passphrase=kdf is not None, seal=seal,
+        compact=data.get('compact', False),
+        chacha=data.get('cipher') == CIPHER_CHACHA20_POLY1305)
+    print(valid, "valid passwords,", invalid, "invalid passwords")
+    if 'digest' in data:
+        if dev.verify_store_digest(bytes.fromhex(data['digest'])):
+            print("The store matches the backup")
+        else:
+            print("The store has changed since the backup")
 
 @cli.command(help="Clear all passwords")
 @click.pass_context
@@ -306,6 +1831,394 @@ def clear(ctx):
     dev = ctx.obj['DEV']
     dev.clear()
 
+@cli.command(help="Set the tag of a password")
+@click.argument('name')
+@click.argument('tag', type=click.IntRange(0, 255))
+@click.pass_context
+def tag(ctx, name, tag):
+    dev = ctx.obj['DEV']
+    dev.set_tag(name, tag)
+
+@cli.command(help="Set the format hint of a password, such as a prefix")
+@click.argument('name')
+@click.argument('hint', default="")
+@click.pass_context
+def hint(ctx, name, hint):
+    dev = ctx.obj['DEV']
+    dev.set_hint(name, hint)
+
+@cli.command(help="Check that the names of the passwords are unique")
+@click.pass_context
+def duplicates(ctx):
+    dev = ctx.obj['DEV']
+    indexes = dev.get_duplicate_names()
+    if not indexes:
+        print("No duplicate name")
+    for index in indexes:
+        print("Entry", index, "has the name of a previous entry:",
+            dev.get_name(index))
+
+@cli.command(help="Print the number of passwords with each tag")
+@click.pass_context
+def tags(ctx):
+    dev = ctx.obj['DEV']
+    for tag, count in sorted(dev.get_tag_counts().items()):
+        print(tag if tag else "untagged", count)
+
+@cli.command(help="Regenerate the passwords with the given tag")
+@click.argument('tag', type=click.IntRange(0, 255))
+@click.pass_context
+def rotate(ctx, tag):
+    dev = ctx.obj['DEV']
+    print("Confirm rotation on your device...")
+    print(dev.rotate_tag(tag), "passwords regenerated")
+
+@cli.command(help="Count passwords used by more than one entry")
+@click.pass_context
+def reused(ctx):
+    dev = ctx.obj['DEV']
+    print(dev.get_reuse_count(), "entries share their password")
+
+@cli.command(help="List the passwords lacking a field")
+@click.argument('field', type=click.Choice(['login', 'password', 'tag']))
+@click.pass_context
+def missing(ctx, field):
+    dev = ctx.obj['DEV']
+    field = {'login': MISSING_LOGIN, 'password': MISSING_PASSWORD,
+        'tag': MISSING_TAG}[field]
+    for name in dev.get_missing(field):
+        print(name)
+
+@cli.command(help="Open, close or audit a provisioning session")
+@click.argument('action', type=click.Choice(['open', 'close', 'list']))
+@click.pass_context
+def provisioning(ctx, action):
+    dev = ctx.obj['DEV']
+    if action == 'open':
+        dev.open_provisioning()
+    elif action == 'close':
+        dev.close_provisioning()
+    else:
+        for name in dev.get_provisioned():
+            print(name)
+
+@cli.command(name='export-uri', help="Print an entry as a URI, in plaintext")
+@click.argument('name')
+@click.pass_context
+def export_uri(ctx, name):
+    dev = ctx.obj['DEV']
+    print("Confirm export on your device...")
+    print(dev.export_uri(name))
+
+@cli.command(name='import-uri',
+    help="Import an entry from an otpauth URI, or a URI printed by export-uri")
+@click.argument('uri')
+@click.pass_context
+def import_uri(ctx, uri):
+    dev = ctx.obj['DEV']
+    print("Confirm import on your device...")
+    dev.import_uri(uri)
+
+@cli.command(name='import-totp',
+    help="Import TOTP secrets from a file with one otpauth URI per line")
+@click.argument('file', type=click.File('r'))
+@click.pass_context
+def import_totp(ctx, file):
+    dev = ctx.obj['DEV']
+    uris = [line.strip() for line in file if line.strip()]
+    print("Confirm import on your device...")
+    statuses = dev.import_totp(uris)
+    for uri, status in zip(uris, statuses):
+        if status == TOTP_RESERVED:
+            print("Skipped, name is reserved: {}".format(uri.split('?')[0]))
+        elif status == TOTP_INVALID:
+            print("Skipped, invalid URI: {}".format(uri.split('?')[0]))
+    print("{} of {} secrets imported".format(statuses.count(TOTP_STORED),
+        len(uris)))
+
+@cli.command(help="Print the TOTP code of an imported TOTP secret")
+@click.argument('name')
+@click.pass_context
+def totp(ctx, name):
+    dev = ctx.obj['DEV']
+    timestamp = int(time.time())
+    print("Confirm on your device...")
+    code = dev.get_totp(name, timestamp)
+    remaining = dev.get_totp_remaining(name, timestamp)
+    print("{} (valid for {} s)".format(code, remaining))
+
+@cli.command(help="Reorder the passwords by name")
+@click.pass_context
+def sort(ctx):
+    dev = ctx.obj['DEV']
+    print("Confirm sorting on your device...")
+    print(dev.sort_by_name(), "swaps")
+
+@cli.command(help="Print an overview of the store")
+@click.pass_context
+def overview(ctx):
+    dev = ctx.obj['DEV']
+    status = dev.get_status()
+    print("{count} of {capacity} entries, {free} free".format(**status))
+    if status['full']:
+        print("The store is full")
+
+@cli.command(help="List the passwords with their status")
+@click.pass_context
+def status(ctx):
+    dev = ctx.obj['DEV']
+    symbols = [(FLAG_BINARY, "binary"), (FLAG_ARM_REQUIRED, "protected"),
+        (FLAG_ROTATE, "rotate"), (STATUS_TAGGED, "tagged"),
+        (STATUS_WIPED, "wiped"), (FLAG_TOTP, "totp")]
+    for name, flags in zip(dev.get_names(), dev.get_flags()):
+        print(name, ", ".join(s for bit, s in symbols if flags & bit))
+
+@cli.command(help="List the passwords whose name matches a glob pattern")
+@click.argument('pattern')
+@click.pass_context
+def search(ctx, pattern):
+    dev = ctx.obj['DEV']
+    count, indexes = dev.match_names(pattern)
+    for index in indexes:
+        print(dev.get_name(index))
+    if count > len(indexes):
+        print("... and", count - len(indexes), "more")
+
+@cli.command(help="Set the parameters of generated passwords")
+@click.option('--length', type=click.IntRange(1, MAX_PASS_LEN), default=16)
+@click.option('--lower/--no-lower', default=True)
+@click.option('--upper/--no-upper', default=True)
+@click.option('--digits/--no-digits', default=True)
+@click.option('--symbols', default="",
+    help="Characters of the symbol class, if any")
+@click.option('--min-lower', default=0, help="Minimum number of lowercase")
+@click.option('--min-upper', default=0, help="Minimum number of uppercase")
+@click.option('--min-digits', default=0, help="Minimum number of digits")
+@click.option('--min-symbols', default=0, help="Minimum number of symbols")
+@click.option('--reset', is_flag=True, help="Restore factory defaults")
+@click.option('--show', is_flag=True, help="Print the current parameters")
+@click.pass_context
+def gendefaults(ctx, length, lower, upper, digits, symbols, min_lower,
+    min_upper, min_digits, min_symbols, reset, show):
+    dev = ctx.obj['DEV']
+    if show:
+        length, classes, symbols, minimums = dev.get_gen_defaults()
+        names = ["lower", "upper", "digit", "symbol"]
+        masks = [CLASS_LOWER, CLASS_UPPER, CLASS_DIGIT, CLASS_SYMBOL]
+        print("length:", length)
+        print("classes:", "+".join(name for name, mask in zip(names, masks)
+            if classes & mask))
+        if symbols:
+            print("symbols:", symbols)
+        for name, minimum in zip(names, minimums):
+            if minimum:
+                print("minimum {}:".format(name), minimum)
+        return
+    print("Confirm generation defaults change on your device...")
+    if reset:
+        dev.reset_gen_defaults()
+        return
+    classes = ((CLASS_LOWER if lower else 0) | (CLASS_UPPER if upper else 0) |
+        (CLASS_DIGIT if digits else 0) | (CLASS_SYMBOL if symbols else 0))
+    dev.set_gen_defaults(length, classes, symbols,
+        (min_lower, min_upper, min_digits, min_symbols))
+
+@cli.command(help="Answer a challenge with HMAC-SHA1 keyed by a password")
+@click.argument('name')
+@click.argument('challenge')
+@click.pass_context
+def challenge(ctx, name, challenge):
+    dev = ctx.obj['DEV']
+    print("Confirm challenge on your device...")
+    print(dev.challenge_response(name, bytes.fromhex(challenge)).hex())
+
+@cli.command(help="Check if two entries share the same password")
+@click.argument('name_a')
+@click.argument('name_b')
+@click.pass_context
+def compare(ctx, name_a, name_b):
+    dev = ctx.obj['DEV']
+    if dev.compare_passwords(name_a, name_b):
+        print("Passwords are identical")
+    else:
+        print("Passwords are different")
+
+@cli.command(help="List the entries using a password, such as a leaked one")
+@click.pass_context
+def find(ctx):
+    dev = ctx.obj['DEV']
+    password = click.prompt("Password", hide_input=True)
+    print("Confirm search on your device...")
+    count, names = dev.find_password(password)
+    for name in names:
+        print(name)
+    if count > len(names):
+        print("...")
+    print(count, "entries use this password")
+
+@cli.command(help="Count passwords by first letter of their name")
+@click.pass_context
+def letters(ctx):
+    dev = ctx.obj['DEV']
+    for letter, count in dev.get_letter_counts().items():
+        if count:
+            print(letter, count)
+
+@cli.command(help="Count passwords not updated recently")
+@click.argument('age', type=int)
+@click.pass_context
+def stale(ctx, age):
+    dev = ctx.obj['DEV']
+    print(dev.get_stale_count(age),
+          "entries not updated during the last", age, "modifications")
+
+@cli.command(help="Print the character classes and length of a password")
+@click.argument('name')
+@click.pass_context
+def classes(ctx, name):
+    dev = ctx.obj['DEV']
+    classes, length = dev.get_password_classes(name)
+    names = [n for (bit, n) in ((CLASS_LOWER, "lower"), (CLASS_UPPER, "upper"),
+        (CLASS_DIGIT, "digit"), (CLASS_SYMBOL, "symbol")) if classes & bit]
+    print("Length {}, classes: {}".format(length, ", ".join(names) or "none"))
+
+@cli.command(help="Set the decoy password revealed under duress")
+@click.argument('name', required=False, default="")
+@click.option('-l', '--login', default="")
+@click.option('-p', '--password', default="")
+@click.pass_context
+def decoy(ctx, name, login, password):
+    dev = ctx.obj['DEV']
+    print("Confirm decoy change on your device...")
+    dev.set_decoy(name, login, password)
+
+@cli.command(help="Print NVM usage of the password store")
+@click.pass_context
+def usage(ctx):
+    dev = ctx.obj['DEV']
+    used, total = dev.get_storage_usage()
+    print("{} / {} bytes used ({}%)".format(used, total, used * 100 // total))
+
+@cli.command(help="Print the derivation path of the export encryption key")
+@click.pass_context
+def path(ctx):
+    dev = ctx.obj['DEV']
+    components = []
+    for component in dev.get_derivation_path():
+        if component & 0x80000000:
+            components.append("{}'".format(component & 0x7fffffff))
+        else:
+            components.append(str(component))
+    print("/".join(["m"] + components))
+
+@cli.command(help="List the codes of the commands supported by the device")
+@click.pass_context
+def commands(ctx):
+    dev = ctx.obj['DEV']
+    print(" ".join("0x{:02x}".format(ins) for ins in dev.get_commands()))
+
+@cli.command(help="Measure the encryption speed of exports on the device")
+@click.option('--count', type=click.IntRange(1, MAX_BENCHMARK_RECORDS),
+    default=MAX_BENCHMARK_RECORDS, help="Number of dummy entries")
+@click.pass_context
+def benchmark(ctx, count):
+    dev = ctx.obj['DEV']
+    print("{:.1f} ms per entry".format(dev.benchmark(count) * 1000))
+
+@cli.command(help="Print the digest of the store, or of a password")
+@click.argument('name', required=False)
+@click.pass_context
+def digest(ctx, name):
+    dev = ctx.obj['DEV']
+    if name is None:
+        print(dev.get_store_digest().hex())
+    else:
+        print(dev.get_entry_digest(name).hex())
+
+@cli.command(help="Check the random number generator of the device")
+@click.pass_context
+def rng(ctx):
+    dev = ctx.obj['DEV']
+    # A healthy generator may rarely fail, so retry once
+    if dev.check_rng() or dev.check_rng():
+        print("Random number generator is healthy")
+    else:
+        print("Random number generator failed health tests")
+
+@cli.command(help="Print the number of confirmations approved on the device")
+@click.pass_context
+def confirmations(ctx):
+    dev = ctx.obj['DEV']
+    print(dev.get_confirmation_count())
+
+@cli.command(help="Set or print the maximum number of stored passwords")
+@click.argument('limit', type=click.IntRange(0, MAX_ENTRIES), required=False)
+@click.pass_context
+def limit(ctx, limit):
+    dev = ctx.obj['DEV']
+    if limit is None:
+        print(dev.get_max_entries())
+    else:
+        print("Confirm limit change on your device...")
+        dev.set_max_entries(limit)
+
+@cli.command(help="Check the seed has not changed since the first run")
+@click.option('--record', is_flag=True,
+    help="Accept the current seed for later checks")
+@click.pass_context
+def checkkey(ctx, record):
+    dev = ctx.obj['DEV']
+    if record:
+        print("Confirm on your device if the seed changed...")
+        dev.record_key()
+        return
+    match, recorded, current = dev.verify_key()
+    if match:
+        print("Encryption key matches fingerprint {}".format(current.hex()))
+    else:
+        print("Seed changed: fingerprint {} instead of {}".format(
+            current.hex(), recorded.hex()))
+        print("Passwords exported with encryption before cannot be imported")
+
+@cli.command(help="Allow adding passwords without confirmation")
+@click.argument('count', type=click.IntRange(0, MAX_SESSION_ADDS))
+@click.pass_context
+def session(ctx, count):
+    dev = ctx.obj['DEV']
+    if count:
+        print("Confirm add session on your device...")
+    dev.start_add_session(count)
+
+@cli.command(help="Set or print the label of the device")
+@click.argument('label', required=False)
+@click.pass_context
+def label(ctx, label):
+    dev = ctx.obj['DEV']
+    if label is None:
+        print(dev.get_label())
+    else:
+        print("Confirm label change on your device...")
+        dev.set_label(label)
+
+@cli.command(help="Set or print the recovery checkword of the store")
+@click.argument('checkword', required=False)
+@click.pass_context
+def checkword(ctx, checkword):
+    dev = ctx.obj['DEV']
+    if checkword is None:
+        print(dev.get_checkword())
+    else:
+        print("Confirm checkword change on your device...")
+        dev.set_checkword(checkword)
+
+@cli.command(help="Select the content of the idle screen")
+@click.argument('mode', type=click.Choice(['label', 'count', 'checkword']))
+@click.pass_context
+def idle(ctx, mode):
+    dev = ctx.obj['DEV']
+    dev.set_idle_mode({'label': IDLE_LABEL, 'count': IDLE_LABEL_COUNT,
+        'checkword': IDLE_CHECKWORD}[mode])
+
 @cli.command(name='open', help="Open application")
 @click.pass_context
 def open_(ctx):
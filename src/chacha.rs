@@ -0,0 +1,297 @@
+// Copyright 2020 Ledger SAS
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! ChaCha20-Poly1305 authenticated encryption (RFC 8439), used by exports
+//! which can be decrypted by standard desktop tools.
+
+/// Size of a nonce in bytes.
+pub const NONCE_SIZE: usize = 12;
+
+/// Size of an authentication tag in bytes.
+pub const TAG_SIZE: usize = 16;
+
+/// Size of a ChaCha20 block in bytes.
+const BLOCK_SIZE: usize = 64;
+
+/// ChaCha20 constants: "expand 32-byte k" in little-endian words.
+const SIGMA: [u32; 4] = [0x61707865, 0x3320646e, 0x79622d32, 0x6b206574];
+
+/// Mask of a 26-bit limb of the Poly1305 accumulator.
+const LIMB_MASK: u32 = 0x3ffffff;
+
+fn le32(bytes: &[u8]) -> u32 {
+    let mut word = [0; 4];
+    word.copy_from_slice(&bytes[..4]);
+    u32::from_le_bytes(word)
+}
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+/// Returns the ChaCha20 key stream block with the given counter.
+fn chacha20_block(
+    key: &[u8; 32],
+    nonce: &[u8; NONCE_SIZE],
+    counter: u32,
+) -> [u8; BLOCK_SIZE] {
+    let mut state = [0u32; 16];
+    state[..4].copy_from_slice(&SIGMA);
+    for (i, word) in state[4..12].iter_mut().enumerate() {
+        *word = le32(&key[4 * i..]);
+    }
+    state[12] = counter;
+    for (i, word) in state[13..].iter_mut().enumerate() {
+        *word = le32(&nonce[4 * i..]);
+    }
+
+    let mut working = state;
+    for _ in 0..10 {
+        quarter_round(&mut working, 0, 4, 8, 12);
+        quarter_round(&mut working, 1, 5, 9, 13);
+        quarter_round(&mut working, 2, 6, 10, 14);
+        quarter_round(&mut working, 3, 7, 11, 15);
+        quarter_round(&mut working, 0, 5, 10, 15);
+        quarter_round(&mut working, 1, 6, 11, 12);
+        quarter_round(&mut working, 2, 7, 8, 13);
+        quarter_round(&mut working, 3, 4, 9, 14);
+    }
+
+    let mut block = [0u8; BLOCK_SIZE];
+    for (i, chunk) in block.chunks_mut(4).enumerate() {
+        let word = working[i].wrapping_add(state[i]);
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    block
+}
+
+/// Encrypts or decrypts a buffer in place with ChaCha20, starting at the
+/// given block counter.
+fn chacha20_xor(
+    key: &[u8; 32],
+    nonce: &[u8; NONCE_SIZE],
+    counter: u32,
+    buffer: &mut [u8],
+) {
+    for (i, chunk) in buffer.chunks_mut(BLOCK_SIZE).enumerate() {
+        let stream = chacha20_block(key, nonce, counter + i as u32);
+        for (b, s) in chunk.iter_mut().zip(stream.iter()) {
+            *b ^= s;
+        }
+    }
+}
+
+/// Poly1305 computation, with the accumulator in 26-bit limbs. Messages are
+/// padded with zeros to complete blocks, as required by the AEAD
+/// construction.
+struct Poly1305 {
+    r: [u32; 5],
+    h: [u32; 5],
+    pad: [u32; 4],
+}
+
+impl Poly1305 {
+    fn new(key: &[u8; 32]) -> Poly1305 {
+        Poly1305 {
+            r: [
+                le32(&key[0..]) & 0x3ffffff,
+                (le32(&key[3..]) >> 2) & 0x3ffff03,
+                (le32(&key[6..]) >> 4) & 0x3ffc0ff,
+                (le32(&key[9..]) >> 6) & 0x3f03fff,
+                (le32(&key[12..]) >> 8) & 0x00fffff,
+            ],
+            h: [0; 5],
+            pad: [
+                le32(&key[16..]),
+                le32(&key[20..]),
+                le32(&key[24..]),
+                le32(&key[28..]),
+            ],
+        }
+    }
+
+    /// Authenticates data, padded with zeros to a multiple of 16 bytes.
+    fn update_padded(&mut self, data: &[u8]) {
+        for chunk in data.chunks(16) {
+            let mut block = [0u8; 16];
+            block[..chunk.len()].copy_from_slice(chunk);
+            self.block(&block);
+        }
+    }
+
+    fn block(&mut self, m: &[u8; 16]) {
+        let [r0, r1, r2, r3, r4] = self.r;
+        let (s1, s2, s3, s4) = (r1 * 5, r2 * 5, r3 * 5, r4 * 5);
+        let h = &mut self.h;
+        h[0] += le32(&m[0..]) & LIMB_MASK;
+        h[1] += (le32(&m[3..]) >> 2) & LIMB_MASK;
+        h[2] += (le32(&m[6..]) >> 4) & LIMB_MASK;
+        h[3] += (le32(&m[9..]) >> 6) & LIMB_MASK;
+        h[4] += (le32(&m[12..]) >> 8) | (1 << 24);
+
+        let mul = |a: u32, b: u32| a as u64 * b as u64;
+        let d0 = mul(h[0], r0)
+            + mul(h[1], s4)
+            + mul(h[2], s3)
+            + mul(h[3], s2)
+            + mul(h[4], s1);
+        let mut d1 = mul(h[0], r1)
+            + mul(h[1], r0)
+            + mul(h[2], s4)
+            + mul(h[3], s3)
+            + mul(h[4], s2);
+        let mut d2 = mul(h[0], r2)
+            + mul(h[1], r1)
+            + mul(h[2], r0)
+            + mul(h[3], s4)
+            + mul(h[4], s3);
+        let mut d3 = mul(h[0], r3)
+            + mul(h[1], r2)
+            + mul(h[2], r1)
+            + mul(h[3], r0)
+            + mul(h[4], s4);
+        let mut d4 = mul(h[0], r4)
+            + mul(h[1], r3)
+            + mul(h[2], r2)
+            + mul(h[3], r1)
+            + mul(h[4], r0);
+
+        // Partial reduction modulo 2^130 - 5
+        h[0] = d0 as u32 & LIMB_MASK;
+        d1 += d0 >> 26;
+        h[1] = d1 as u32 & LIMB_MASK;
+        d2 += d1 >> 26;
+        h[2] = d2 as u32 & LIMB_MASK;
+        d3 += d2 >> 26;
+        h[3] = d3 as u32 & LIMB_MASK;
+        d4 += d3 >> 26;
+        h[4] = d4 as u32 & LIMB_MASK;
+        h[0] += (d4 >> 26) as u32 * 5;
+        h[1] += h[0] >> 26;
+        h[0] &= LIMB_MASK;
+    }
+
+    /// Terminates the computation and returns the tag.
+    fn finalize(self) -> [u8; TAG_SIZE] {
+        let mut h = self.h;
+        // Full carry propagation
+        for i in 1..4 {
+            h[i + 1] += h[i] >> 26;
+            h[i] &= LIMB_MASK;
+        }
+        h[0] += (h[4] >> 26) * 5;
+        h[4] &= LIMB_MASK;
+        h[1] += h[0] >> 26;
+        h[0] &= LIMB_MASK;
+
+        // Compute h - p = h + 5 - 2^130, and keep it if it is not negative
+        let mut g = [0u32; 5];
+        let mut carry = 5;
+        for i in 0..4 {
+            g[i] = h[i] + carry;
+            carry = g[i] >> 26;
+            g[i] &= LIMB_MASK;
+        }
+        g[4] = (h[4] + carry).wrapping_sub(1 << 26);
+        let keep_g = (g[4] >> 31).wrapping_sub(1);
+        for i in 0..5 {
+            h[i] = (h[i] & !keep_g) | (g[i] & keep_g);
+        }
+
+        // Add the pad modulo 2^128
+        let words = [
+            h[0] | (h[1] << 26),
+            (h[1] >> 6) | (h[2] << 20),
+            (h[2] >> 12) | (h[3] << 14),
+            (h[3] >> 18) | (h[4] << 8),
+        ];
+        let mut tag = [0u8; TAG_SIZE];
+        let mut f = 0u64;
+        for i in 0..4 {
+            f = words[i] as u64 + self.pad[i] as u64 + (f >> 32);
+            tag[4 * i..4 * i + 4].copy_from_slice(&(f as u32).to_le_bytes());
+        }
+        tag
+    }
+}
+
+/// Returns the Poly1305 tag of the associated data and ciphertext, with the
+/// one-time key derived from the first key stream block.
+fn aead_tag(
+    key: &[u8; 32],
+    nonce: &[u8; NONCE_SIZE],
+    aad: &[u8],
+    ciphertext: &[u8],
+) -> [u8; TAG_SIZE] {
+    let mut poly_key = [0u8; 32];
+    poly_key.copy_from_slice(&chacha20_block(key, nonce, 0)[..32]);
+    let mut poly = Poly1305::new(&poly_key);
+    poly.update_padded(aad);
+    poly.update_padded(ciphertext);
+    let mut lengths = [0u8; 16];
+    lengths[..8].copy_from_slice(&(aad.len() as u64).to_le_bytes());
+    lengths[8..].copy_from_slice(&(ciphertext.len() as u64).to_le_bytes());
+    poly.block(&lengths);
+    poly.finalize()
+}
+
+/// Encrypts a buffer in place with ChaCha20-Poly1305, and returns the
+/// authentication tag.
+///
+/// # Arguments
+///
+/// * `nonce` - Must never be used twice with the same key.
+/// * `aad` - Associated data, authenticated but not encrypted.
+/// * `buffer` - Data to be encrypted.
+pub fn seal(
+    key: &[u8; 32],
+    nonce: &[u8; NONCE_SIZE],
+    aad: &[u8],
+    buffer: &mut [u8],
+) -> [u8; TAG_SIZE] {
+    chacha20_xor(key, nonce, 1, buffer);
+    aead_tag(key, nonce, aad, buffer)
+}
+
+/// Checks the authentication tag of a buffer, and decrypts it in place with
+/// ChaCha20-Poly1305 if it is valid. Returns false, leaving the buffer
+/// encrypted, if the tag is invalid. The tag is compared in constant time.
+pub fn open(
+    key: &[u8; 32],
+    nonce: &[u8; NONCE_SIZE],
+    aad: &[u8],
+    buffer: &mut [u8],
+    tag: &[u8],
+) -> bool {
+    let expected = aead_tag(key, nonce, aad, buffer);
+    if tag.len() != TAG_SIZE {
+        return false;
+    }
+    let mut diff = 0;
+    for (a, b) in expected.iter().zip(tag.iter()) {
+        diff |= a ^ b;
+    }
+    if diff != 0 {
+        return false;
+    }
+    chacha20_xor(key, nonce, 1, buffer);
+    true
+}
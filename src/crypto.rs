@@ -0,0 +1,95 @@
+// Copyright 2020 Ledger SAS
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! AES-256 primitives used to encrypt and authenticate exported passwords,
+//! wrapping the unsafe tinyaes bindings.
+
+use crate::tinyaes;
+use core::mem::MaybeUninit;
+
+/// AES block size in bytes.
+pub const BLOCK_SIZE: usize = 16;
+
+/// Encrypts a buffer in place with AES-256-CBC.
+///
+/// # Arguments
+///
+/// * `buffer` - Data to be encrypted. Its length must be a multiple of
+///   BLOCK_SIZE.
+pub fn cbc_encrypt(key: &[u8; 32], iv: &[u8; BLOCK_SIZE], buffer: &mut [u8]) {
+    let mut aes_ctx = MaybeUninit::<tinyaes::AES_ctx>::uninit();
+    unsafe {
+        tinyaes::AES_init_ctx_iv(
+            aes_ctx.as_mut_ptr(),
+            key.as_ptr(),
+            iv.as_ptr(),
+        );
+        tinyaes::AES_CBC_encrypt_buffer(
+            aes_ctx.as_mut_ptr(),
+            buffer.as_mut_ptr(),
+            buffer.len() as u32,
+        );
+    }
+}
+
+/// Decrypts a buffer in place with AES-256-CBC.
+///
+/// # Arguments
+///
+/// * `buffer` - Data to be decrypted. Its length must be a multiple of
+///   BLOCK_SIZE.
+pub fn cbc_decrypt(key: &[u8; 32], iv: &[u8; BLOCK_SIZE], buffer: &mut [u8]) {
+    let mut aes_ctx = MaybeUninit::<tinyaes::AES_ctx>::uninit();
+    unsafe {
+        tinyaes::AES_init_ctx_iv(
+            aes_ctx.as_mut_ptr(),
+            key.as_ptr(),
+            iv.as_ptr(),
+        );
+        tinyaes::AES_CBC_decrypt_buffer(
+            aes_ctx.as_mut_ptr(),
+            buffer.as_mut_ptr(),
+            buffer.len() as u32,
+        );
+    }
+}
+
+/// Computes the AES-256-CBC-MAC of some data: the last block of its
+/// AES-256-CBC encryption. The data is not modified.
+///
+/// Returns None if the data is empty or its length is not a multiple of
+/// BLOCK_SIZE, as there is no complete last block to use as MAC.
+pub fn cbc_mac(
+    key: &[u8; 32],
+    iv: &[u8; BLOCK_SIZE],
+    data: &[u8],
+) -> Option<[u8; BLOCK_SIZE]> {
+    if data.len() < BLOCK_SIZE || data.len() % BLOCK_SIZE != 0 {
+        return None;
+    }
+    let mut mac = *iv;
+    let mut aes_ctx = MaybeUninit::<tinyaes::AES_ctx>::uninit();
+    unsafe {
+        tinyaes::AES_init_ctx(aes_ctx.as_mut_ptr(), key.as_ptr());
+    }
+    for block in data.chunks(BLOCK_SIZE) {
+        for (m, b) in mac.iter_mut().zip(block) {
+            *m ^= b;
+        }
+        unsafe {
+            tinyaes::AES_ECB_encrypt(aes_ctx.as_ptr(), mac.as_mut_ptr());
+        }
+    }
+    Some(mac)
+}
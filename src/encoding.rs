@@ -0,0 +1,100 @@
+// Copyright 2020 Ledger SAS
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Text encodings of binary data, for display.
+
+use crate::password::ArrayString;
+
+const HEX_CHARS: &[u8] = b"0123456789abcdef";
+const BASE64_CHARS: &[u8] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const BASE32_CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Returns the lowercase hexadecimal representation of up to 32 bytes.
+pub fn to_hex(bytes: &[u8]) -> ArrayString<64> {
+    let mut result = ArrayString::new();
+    for &b in bytes {
+        result.append(&[
+            HEX_CHARS[(b >> 4) as usize],
+            HEX_CHARS[(b & 0x0f) as usize],
+        ]);
+    }
+    result
+}
+
+/// Returns the standard base64 representation (RFC 4648, with padding) of up
+/// to 32 bytes.
+pub fn to_base64(bytes: &[u8]) -> ArrayString<44> {
+    let mut result = ArrayString::new();
+    for chunk in bytes.chunks(3) {
+        let mut block = [0u8; 3];
+        block[..chunk.len()].copy_from_slice(chunk);
+        let n = u32::from_be_bytes([0, block[0], block[1], block[2]]);
+        let mut chars = [b'='; 4];
+        for (i, c) in chars.iter_mut().take(chunk.len() + 1).enumerate() {
+            *c = BASE64_CHARS[((n >> (18 - 6 * i)) & 0x3f) as usize];
+        }
+        result.append(&chars);
+    }
+    result
+}
+
+/// Returns the base32 representation (RFC 4648) of up to 32 bytes, without
+/// padding, as used by authenticator applications.
+pub fn to_base32(bytes: &[u8]) -> ArrayString<52> {
+    let mut result = ArrayString::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for &b in bytes {
+        buffer = (buffer << 8) | b as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            result.append(&[BASE32_CHARS[((buffer >> bits) & 0x1f) as usize]]);
+        }
+    }
+    if bits > 0 {
+        let index = (buffer << (5 - bits)) & 0x1f;
+        result.append(&[BASE32_CHARS[index as usize]]);
+    }
+    result
+}
+
+/// Decodes base32 text (RFC 4648) of up to 32 bytes, in upper or lower case,
+/// with or without padding. Returns the number of bytes written to `output`,
+/// or None if the text is not valid base32 or too long.
+pub fn from_base32(text: &[u8], output: &mut [u8; 32]) -> Option<usize> {
+    let mut end = text.len();
+    while end > 0 && text[end - 1] == b'=' {
+        end -= 1;
+    }
+    let mut len = 0;
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for &c in &text[..end] {
+        let c = c.to_ascii_uppercase();
+        let value = BASE32_CHARS.iter().position(|&x| x == c)? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            if len == output.len() {
+                return None;
+            }
+            output[len] = (buffer >> bits) as u8;
+            len += 1;
+        }
+    }
+    Some(len)
+}
@@ -0,0 +1,224 @@
+// Copyright 2020 Ledger SAS
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Random password generation.
+
+use crate::password::{
+    ArrayString, CLASS_DIGIT, CLASS_LOWER, CLASS_SYMBOL, CLASS_UPPER,
+};
+use core::convert::TryFrom;
+use nanos_sdk::random;
+
+const LOWER_CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyz";
+const UPPER_CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+const DIGIT_CHARS: &[u8] = b"0123456789";
+
+/// Letters of the syllables of pronounceable passwords: each syllable is a
+/// consonant followed by a vowel. The consonants c, q, w, x and y, whose
+/// pronunciation is ambiguous, are left out.
+const SYLLABLE_CONSONANTS: &[u8] = b"bdfghjklmnprstvz";
+const SYLLABLE_VOWELS: &[u8] = b"aeiou";
+
+/// Maximum length of a generated password, which is the capacity of the
+/// password field.
+pub const MAX_LENGTH: u8 = 32;
+
+/// Size of a serialized GenParams without minimums: length, classes and
+/// symbols.
+pub const BASE_PARAMS_SIZE: usize = 18;
+
+/// Size of a serialized GenParams with the minimum counts of each class.
+pub const PARAMS_SIZE: usize = BASE_PARAMS_SIZE + 4;
+
+/// Character classes, in the order of the minimum counts.
+const CLASSES: [u8; 4] = [CLASS_LOWER, CLASS_UPPER, CLASS_DIGIT, CLASS_SYMBOL];
+
+/// Parameters of password generation.
+#[derive(Clone, Copy)]
+pub struct GenParams {
+    /// Length of generated passwords.
+    pub length: u8,
+    /// Bitmask of the character classes used (CLASS_* constants).
+    pub classes: u8,
+    /// Characters used for the symbol class, padded with zeros.
+    pub symbols: ArrayString<16>,
+    /// Minimum number of characters of each class: lowercase, uppercase,
+    /// digits and symbols.
+    pub minimums: [u8; 4],
+}
+
+impl GenParams {
+    /// Factory defaults: 16 letters and digits.
+    pub const fn new() -> GenParams {
+        GenParams {
+            length: 16,
+            classes: CLASS_LOWER | CLASS_UPPER | CLASS_DIGIT,
+            symbols: ArrayString::new(),
+            minimums: [0; 4],
+        }
+    }
+
+    /// Returns parameters initialized from their serialized form: length on
+    /// one byte, classes on one byte, symbols on 16 bytes, and optionally the
+    /// minimum count of each class on 4 bytes.
+    pub fn from_bytes(bytes: &[u8]) -> GenParams {
+        let mut minimums = [0; 4];
+        if bytes.len() >= PARAMS_SIZE {
+            minimums.copy_from_slice(&bytes[BASE_PARAMS_SIZE..PARAMS_SIZE]);
+        }
+        GenParams {
+            length: bytes[0],
+            classes: bytes[1],
+            symbols: ArrayString::from_bytes(&bytes[2..BASE_PARAMS_SIZE]),
+            minimums,
+        }
+    }
+
+    /// Returns the serialized form of the parameters, with the minimums.
+    pub fn to_bytes(&self) -> [u8; PARAMS_SIZE] {
+        let mut bytes = [0; PARAMS_SIZE];
+        bytes[0] = self.length;
+        bytes[1] = self.classes;
+        bytes[2..BASE_PARAMS_SIZE].copy_from_slice(self.symbols.bytes());
+        bytes[BASE_PARAMS_SIZE..].copy_from_slice(&self.minimums);
+        bytes
+    }
+
+    /// Returns true if passwords can be generated with these parameters.
+    /// Symbols must be printable ASCII characters which are neither letters
+    /// nor digits, and must be given if the symbol class is selected.
+    /// Minimums are only allowed for selected classes, and must not exceed
+    /// the length in total.
+    pub fn is_valid(&self) -> bool {
+        let all = CLASS_LOWER | CLASS_UPPER | CLASS_DIGIT | CLASS_SYMBOL;
+        let symbols = &self.symbols.bytes()[..self.symbols.len()];
+        let total: u32 = self.minimums.iter().map(|&m| m as u32).sum();
+        self.length > 0
+            && total <= self.length as u32
+            && CLASSES
+                .iter()
+                .zip(self.minimums.iter())
+                .all(|(&class, &min)| min == 0 || self.classes & class != 0)
+            && self.length <= MAX_LENGTH
+            && self.classes != 0
+            && self.classes & !all == 0
+            && symbols.iter().all(|c| c.is_ascii_punctuation())
+            && (self.classes & CLASS_SYMBOL == 0 || !symbols.is_empty())
+    }
+}
+
+/// Returns an index drawn uniformly in `0..len`.
+///
+/// # Panics
+///
+/// Panics if `len` is zero or does not fit the range of the RNG, rather than
+/// drawing from a truncated range.
+fn random_index(len: usize) -> usize {
+    let bound = u32::try_from(len).unwrap();
+    assert!(bound > 0);
+    random::rand_u32_range(0..bound) as usize
+}
+
+/// Returns a character drawn uniformly from the given set.
+fn random_char(chars: &[u8]) -> u8 {
+    chars[random_index(chars.len())]
+}
+
+/// Generates a random password. The minimum number of characters of each
+/// class are drawn from their class, and the other characters are drawn
+/// uniformly from the union of the selected classes. The characters are then
+/// shuffled, so the position of the mandatory characters is not predictable.
+pub fn generate(params: &GenParams) -> ArrayString<32> {
+    generate_excluding(params, &[])
+}
+
+/// Generates a random password like `generate`, without the characters
+/// present in `exclude`, ignoring the case of letters. A class whose
+/// characters would all be excluded keeps its full set, so the minimums can
+/// always be satisfied.
+pub fn generate_excluding(
+    params: &GenParams,
+    exclude: &[u8],
+) -> ArrayString<32> {
+    let length = params.length as usize;
+    let symbols = &params.symbols.bytes()[..params.symbols.len()];
+    let mut class_chars = [LOWER_CHARS, UPPER_CHARS, DIGIT_CHARS, symbols];
+
+    // Remove the excluded characters from each class
+    let mut buffers = [[0u8; 26]; 4];
+    for (chars, buffer) in class_chars.iter_mut().zip(buffers.iter_mut()) {
+        let mut size = 0;
+        for &c in chars.iter() {
+            if !exclude.iter().any(|e| e.eq_ignore_ascii_case(&c)) {
+                buffer[size] = c;
+                size += 1;
+            }
+        }
+        if size > 0 {
+            let buffer: &[u8] = buffer;
+            *chars = &buffer[..size];
+        }
+    }
+
+    // Draw the mandatory characters, and build the alphabet of the selected
+    // classes
+    let mut pass = [0u8; MAX_LENGTH as usize];
+    let mut filled = 0;
+    let mut alphabet = [0u8; 26 + 26 + 10 + 16];
+    let mut size = 0;
+    let classes = CLASSES.iter().zip(class_chars.iter());
+    for ((&class, &chars), &min) in classes.zip(params.minimums.iter()) {
+        if params.classes & class != 0 {
+            for _ in 0..min {
+                pass[filled] = random_char(chars);
+                filled += 1;
+            }
+            alphabet[size..size + chars.len()].copy_from_slice(chars);
+            size += chars.len();
+        }
+    }
+
+    for item in pass[filled..length].iter_mut() {
+        *item = random_char(&alphabet[..size]);
+    }
+
+    // Fisher-Yates shuffle
+    for i in (1..length).rev() {
+        let j = random_index(i + 1);
+        pass.swap(i, j);
+    }
+    ArrayString::from_bytes(&pass)
+}
+
+/// Generates a random pronounceable password of the given length, made of
+/// syllables drawn uniformly from all the consonant-vowel pairs. If the length
+/// is odd, the last syllable is truncated to its consonant.
+///
+/// Each syllable carries 6.3 bits of entropy, so such a password is weaker
+/// than a random password of the same length, but easier to type from the
+/// screen.
+pub fn generate_pronounceable(length: u8) -> ArrayString<32> {
+    let length = (length as usize).min(MAX_LENGTH as usize);
+    let vowels = SYLLABLE_VOWELS.len();
+    let syllables = SYLLABLE_CONSONANTS.len() * vowels;
+    let mut pass = [0u8; MAX_LENGTH as usize];
+    for pair in pass[..length].chunks_mut(2) {
+        let syllable = random_index(syllables);
+        pair[0] = SYLLABLE_CONSONANTS[syllable / vowels];
+        if let Some(c) = pair.get_mut(1) {
+            *c = SYLLABLE_VOWELS[syllable % vowels];
+        }
+    }
+    ArrayString::from_bytes(&pass)
+}
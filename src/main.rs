@@ -21,14 +21,23 @@ use nanos_sdk::ecc;
 use nanos_sdk::io;
 use nanos_sdk::io::{StatusWords, Reply};
 use nanos_sdk::nvm;
+use nanos_sdk::nvm::SingleStorage;
 use nanos_sdk::random;
 use nanos_sdk::Pic;
 use nanos_ui::ui;
 mod password;
-use heapless::{consts::U96, Vec};
-use password::{ArrayString, PasswordItem};
+use password::{ArrayString, PasswordItem, META_SIZE, RECORD_SIZE};
+mod settings;
+use settings::Settings;
 mod tinyaes;
-use core::mem::MaybeUninit;
+mod crypto;
+mod chacha;
+mod generator;
+mod sha1;
+mod store;
+mod encoding;
+mod uri;
+use generator::GenParams;
 use core::convert::TryFrom;
 
 nanos_sdk::set_panic!(nanos_sdk::exiting_panic);
@@ -36,12 +45,14 @@ nanos_sdk::set_panic!(nanos_sdk::exiting_panic);
 #[no_mangle]
 #[link_section = ".nvm_data"]
 /// Stores all passwords in Non-Volatile Memory
-static mut PASSWORDS: Pic<nvm::Collection<PasswordItem, 128>> =
-    Pic::new(nvm::Collection::new(PasswordItem::new()));
+static mut PASSWORDS: Pic<store::Collection<PasswordItem, 128>> =
+    Pic::new(store::Collection::new(PasswordItem::new()));
 
-/// Possible characters for the randomly generated passwords
-static PASS_CHARS: &str =
-    "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+#[no_mangle]
+#[link_section = ".nvm_data"]
+/// Stores application settings in Non-Volatile Memory
+static mut SETTINGS: Pic<nvm::AtomicStorage<Settings>> =
+    Pic::new(nvm::AtomicStorage::new(&Settings::new()));
 
 /// SLIP16 path for password encryption (used during export/import)
 static BIP32_PATH: [u32; 2] = ecc::make_bip32_path(b"m/10016'/0");
@@ -50,7 +61,17 @@ enum Error {
     NoConsent,
     StorageFull,
     EntryNotFound,
-    DecryptFailed
+    DecryptFailed,
+    InvalidData,
+    WrongLength,
+    NoSession,
+    Internal,
+    ReservedName,
+    BadState,
+    FormatMismatch,
+    NotArmed,
+    CountMismatch,
+    TotpOnly
 }
 
 impl Into<Reply> for Error {
@@ -59,7 +80,17 @@ impl Into<Reply> for Error {
             Error::NoConsent => Reply(0x69f0 as u16),
             Error::StorageFull => Reply(0x9210 as u16),
             Error::EntryNotFound => Reply(0x6a88 as u16),
-            Error::DecryptFailed => Reply(0x9d60 as u16)
+            Error::DecryptFailed => Reply(0x9d60 as u16),
+            Error::InvalidData => Reply(0x6a80 as u16),
+            Error::WrongLength => Reply(0x6700 as u16),
+            Error::NoSession => Reply(0x6985 as u16),
+            Error::Internal => Reply(0x6f00 as u16),
+            Error::ReservedName => Reply(0x6a89 as u16),
+            Error::BadState => Reply(0x6986 as u16),
+            Error::FormatMismatch => Reply(0x6a87 as u16),
+            Error::NotArmed => Reply(0x6982 as u16),
+            Error::CountMismatch => Reply(0x6a8a as u16),
+            Error::TotpOnly => Reply(0x6a8b as u16)
         }
     }
 }
@@ -78,7 +109,71 @@ enum Instruction {
     Clear,
     Quit,
     ShowOnScreen,
-    HasName
+    HasName,
+    GetReuseCount,
+    SetLabel,
+    GetLabel,
+    ImportBlob,
+    GetStaleCount,
+    GetStorageUsage,
+    GetDerivationPath,
+    StartAddSession,
+    GetPasswordClasses,
+    SetDecoy,
+    SetTag,
+    ComparePasswords,
+    SetGenDefaults,
+    ChallengeResponse,
+    SetPassphrase,
+    GetLetterCounts,
+    CheckPassword,
+    RotateTag,
+    GetReadConfirmations,
+    GetCommands,
+    Benchmark,
+    SetIdleMode,
+    GetStoreDigest,
+    GetEntryDigest,
+    GetAddCount,
+    WipePassword,
+    CheckRng,
+    GetConfirmationCount,
+    SetMaxEntries,
+    GetMaxEntries,
+    GetNextIndex,
+    VerifyKey,
+    DeleteRange,
+    GetMissing,
+    Provisioning,
+    ArmEntry,
+    MatchNames,
+    GetFlags,
+    GetCapabilities,
+    GetSequenceRange,
+    GetWearEstimate,
+    SwapPasswords,
+    GetLengthRange,
+    GetWeakest,
+    GetStackUsage,
+    GetNameLength,
+    SetHint,
+    GetHint,
+    VerifyStoreDigest,
+    GetLackingClasses,
+    GetTagCounts,
+    GetDuplicateNames,
+    SetCheckword,
+    GetCheckword,
+    GetGenDefaults,
+    GetImportPending,
+    FindPassword,
+    GetStatus,
+    SortByName,
+    ExportUri,
+    ImportUri,
+    GetTotp,
+    GetTotpRemaining,
+    ImportTotp
 }
 
 impl TryFrom<u8> for Instruction {
@@ -100,6 +195,70 @@ impl TryFrom<u8> for Instruction {
             0x0c => Ok(Self::Quit),
             0x0d => Ok(Self::ShowOnScreen),
             0x0e => Ok(Self::HasName),
+            0x0f => Ok(Self::GetReuseCount),
+            0x10 => Ok(Self::SetLabel),
+            0x11 => Ok(Self::GetLabel),
+            0x12 => Ok(Self::ImportBlob),
+            0x13 => Ok(Self::GetStaleCount),
+            0x14 => Ok(Self::GetStorageUsage),
+            0x15 => Ok(Self::GetDerivationPath),
+            0x16 => Ok(Self::StartAddSession),
+            0x17 => Ok(Self::GetPasswordClasses),
+            0x18 => Ok(Self::SetDecoy),
+            0x19 => Ok(Self::SetTag),
+            0x1a => Ok(Self::ComparePasswords),
+            0x1b => Ok(Self::SetGenDefaults),
+            0x1c => Ok(Self::ChallengeResponse),
+            0x1d => Ok(Self::SetPassphrase),
+            0x1e => Ok(Self::GetLetterCounts),
+            0x1f => Ok(Self::CheckPassword),
+            0x20 => Ok(Self::RotateTag),
+            0x21 => Ok(Self::GetReadConfirmations),
+            0x22 => Ok(Self::GetCommands),
+            0x23 => Ok(Self::Benchmark),
+            0x24 => Ok(Self::SetIdleMode),
+            0x25 => Ok(Self::GetStoreDigest),
+            0x26 => Ok(Self::GetEntryDigest),
+            0x27 => Ok(Self::GetAddCount),
+            0x28 => Ok(Self::WipePassword),
+            0x29 => Ok(Self::CheckRng),
+            0x2a => Ok(Self::GetConfirmationCount),
+            0x2b => Ok(Self::SetMaxEntries),
+            0x2c => Ok(Self::GetMaxEntries),
+            0x2d => Ok(Self::GetNextIndex),
+            0x2e => Ok(Self::VerifyKey),
+            0x2f => Ok(Self::DeleteRange),
+            0x30 => Ok(Self::GetMissing),
+            0x31 => Ok(Self::Provisioning),
+            0x32 => Ok(Self::ArmEntry),
+            0x33 => Ok(Self::MatchNames),
+            0x34 => Ok(Self::GetFlags),
+            0x35 => Ok(Self::GetCapabilities),
+            0x36 => Ok(Self::GetSequenceRange),
+            0x37 => Ok(Self::GetWearEstimate),
+            0x38 => Ok(Self::SwapPasswords),
+            0x39 => Ok(Self::GetLengthRange),
+            0x3a => Ok(Self::GetWeakest),
+            0x3b => Ok(Self::GetStackUsage),
+            0x3c => Ok(Self::GetNameLength),
+            0x3d => Ok(Self::SetHint),
+            0x3e => Ok(Self::GetHint),
+            0x3f => Ok(Self::VerifyStoreDigest),
+            0x40 => Ok(Self::GetLackingClasses),
+            0x41 => Ok(Self::GetTagCounts),
+            0x42 => Ok(Self::GetDuplicateNames),
+            0x43 => Ok(Self::SetCheckword),
+            0x44 => Ok(Self::GetCheckword),
+            0x45 => Ok(Self::GetGenDefaults),
+            0x46 => Ok(Self::GetImportPending),
+            0x47 => Ok(Self::FindPassword),
+            0x48 => Ok(Self::GetStatus),
+            0x49 => Ok(Self::SortByName),
+            0x4a => Ok(Self::ExportUri),
+            0x4b => Ok(Self::ImportUri),
+            0x4c => Ok(Self::GetTotp),
+            0x4d => Ok(Self::GetTotpRemaining),
+            0x4e => Ok(Self::ImportTotp),
             _ => Err(())
         }
     }
@@ -114,16 +273,58 @@ extern "C" fn sample_main() {
     // in the rest of the program the borrow checker will be able to detect
     // missuses correctly.
     let mut passwords = unsafe { PASSWORDS.get_mut() };
+    let settings = unsafe { SETTINGS.get_mut() };
 
     // Encryption/decryption key for import and export.
     let mut enc_key = [0u8; 32];
     if ecc::bip32_derive(ecc::CurvesId::Secp256k1, &BIP32_PATH, &mut enc_key)
         .is_err() { panic!(); };
 
+    // Record the fingerprint of the key the first time the application runs,
+    // so a later seed change can be detected with VerifyKey.
+    if settings.get_ref().key_fingerprint == [0; 8] {
+        let mut new_settings = *settings.get_ref();
+        new_settings.key_fingerprint = key_fingerprint(&enc_key);
+        settings.update(&new_settings);
+    }
+
+    // Whether the idle message is currently displayed. Button events do not
+    // change the screen, so the idle message is only computed and redrawn
+    // after commands, which may have changed it or displayed something else.
+    let mut idle_shown = false;
+    // Number of passwords which can still be created without confirmation
+    // during an add session. Zero if no session is opened.
+    let mut session_adds: u32 = 0;
+    // Key derived from a passphrase given by the client, to encrypt exports
+    // which can be imported without the seed. Kept in RAM only.
+    let mut passphrase_key: Option<[u8; 32]> = None;
+    // Number of passwords added or updated with Add since the application
+    // started, for progress feedback. Kept in RAM only.
+    let mut add_count: u32 = 0;
+    // Name of the password armed for the next command, if it reads it.
+    // Kept in RAM only.
+    let mut armed: Option<ArrayString<32>> = None;
+
     loop {
-        ui::SingleMessage::new("NanoPass").show();
+        if !idle_shown {
+            if session_adds > 0 {
+                ui::SingleMessage::new("Add session").show();
+            } else {
+                let message = settings.get_ref().idle_message(passwords.len());
+                ui::SingleMessage::new(message.as_str()).show();
+            }
+            idle_shown = true;
+        }
+
+        let event = comm.next_event();
+        // An armed password can only be read by the command which follows
+        let mut armed_name = None;
+        if let io::Event::Command(_) = event {
+            idle_shown = false;
+            armed_name = armed.take();
+        }
 
-        match comm.next_event() {
+        match event {
             io::Event::Button(ButtonEvent::BothButtonsRelease) => {
                 nanos_sdk::exit_app(0)
             }
@@ -141,16 +342,70 @@ extern "C" fn sample_main() {
                 comm.append(&[0]); // No flags
                 comm.reply_ok();
             }
+            // Describe the device, so the client can adapt its interface
+            io::Event::Command(Instruction::GetCapabilities) => {
+                comm.append(&[MODEL_ID, CAPABILITIES]);
+                comm.append(&SCREEN_SIZE);
+                comm.reply_ok();
+            }
             // Get number of stored passwords
             io::Event::Command(Instruction::GetSize) => {
                 let len: [u8; 4] = passwords.len().to_be_bytes();
                 comm.append(&len);
                 comm.reply_ok();
             }
+            // Get an overview of the store in a single command: version of
+            // the response format, number of stored passwords, capacity,
+            // free slots and full flag.
+            io::Event::Command(Instruction::GetStatus) => {
+                let count = passwords.len() as u32;
+                let capacity = settings.get_ref().entry_limit(128) as u32;
+                comm.append(&[STATUS_VERSION]);
+                comm.append(&count.to_be_bytes());
+                comm.append(&capacity.to_be_bytes());
+                comm.append(&capacity.saturating_sub(count).to_be_bytes());
+                comm.append(&[is_full(passwords, settings) as u8]);
+                comm.reply_ok();
+            }
             // Add a password
             // If P1 == 0, password is in the data
             // If P1 == 1, password must be generated by the device
+            // If P1 == 2, password is generated by the device and previewed
+            // on screen, so the user can ask for another one before storing.
+            // If P1 == 3, a binary secret is in the data, after its length.
+            // If P1 == 4, password is generated by the device with the
+            // parameters given in the data.
+            // If P2 bit 0 is set, generated passwords do not contain the
+            // characters of the name.
+            // If P2 bit 1 is set, generated passwords are pronounceable, with
+            // the length of the generation parameters.
+            // If P2 bit 2 is set, the fingerprint of the stored password is
+            // returned, so the client can later check a read without the
+            // password being disclosed now.
+            // If P2 bit 3 is set, the reply starts with 1 if an existing
+            // password was updated, 0 if a new one was created.
             io::Event::Command(Instruction::Add) => {
+                // Fields have a fixed size: extra bytes would mean a field
+                // is longer than its capacity, and would be truncated.
+                let len = data_len(&comm);
+                let expected_len = match comm.get_p1() {
+                    0 => 32 * 3,
+                    3 => match get_byte(&comm, 64) {
+                        Some(secret_len) => 32 * 2 + 1 + secret_len as usize,
+                        None => {
+                            comm.reply(Error::WrongLength);
+                            continue;
+                        }
+                    },
+                    4 if len == 32 * 2 + generator::BASE_PARAMS_SIZE => len,
+                    4 => 32 * 2 + generator::PARAMS_SIZE,
+                    _ => 32 * 2,
+                };
+                if data_len(&comm) != expected_len {
+                    comm.reply(Error::WrongLength);
+                    continue;
+                }
+                let mut secret_len = None;
                 let mut offset = 5;
                 let name = ArrayString::<32>::from_bytes(
                     comm.get(offset, offset + 32));
@@ -158,27 +413,113 @@ extern "C" fn sample_main() {
                 let login = ArrayString::<32>::from_bytes(
                     comm.get(offset, offset + 32));
                 offset += 32;
+                let exclude = if comm.get_p2() & 0x01 != 0 {
+                    &name.bytes()[..name.len()]
+                } else {
+                    &[]
+                };
+                let pronounceable = comm.get_p2() & 0x02 != 0;
                 let pass = match comm.get_p1() {
                     0 => Some(ArrayString::<32>::from_bytes(
                         comm.get(offset, offset + 32),
                     )),
+                    1 => Some(generate_password(
+                        &settings.get_ref().gen,
+                        exclude,
+                        pronounceable,
+                    )),
+                    2 => match preview_random_password(
+                        settings,
+                        exclude,
+                        pronounceable,
+                    ) {
+                        Some(p) => Some(p),
+                        None => {
+                            comm.reply(Error::NoConsent);
+                            continue;
+                        }
+                    },
+                    3 => {
+                        let len = comm.get(offset, offset + 1)[0];
+                        if len > 32 {
+                            comm.reply(Error::WrongLength);
+                            continue;
+                        }
+                        offset += 1;
+                        secret_len = Some(len);
+                        Some(ArrayString::<32>::from_bytes(
+                            comm.get(offset, offset + len as usize),
+                        ))
+                    }
+                    4 => {
+                        let params =
+                            GenParams::from_bytes(comm.get(offset, 5 + len));
+                        if !params.is_valid() {
+                            comm.reply(Error::InvalidData);
+                            continue;
+                        }
+                        Some(generate_password(&params, exclude, pronounceable))
+                    }
                     _ => None,
                 };
-                comm.reply::<Reply>(
-                    match set_password(passwords, &name, &login, &pass) {
-                        Ok(()) => StatusWords::Ok.into(),
-                        Err(e) => e.into(),
+                let trusted = session_adds > 0;
+                match set_password(
+                    passwords, settings, &name, &login, &pass, secret_len,
+                    trusted,
+                ) {
+                    Ok(updated) => {
+                        add_count += 1;
+                        // Only the creation of a password uses the session
+                        if trusted && !updated {
+                            session_adds -= 1;
+                        }
+                        if comm.get_p2() & 0x08 != 0 {
+                            comm.append(&[updated as u8]);
+                        }
+                        if comm.get_p2() & 0x04 != 0 {
+                            let stored = passwords
+                                .into_iter()
+                                .find(|x| x.name == name)
+                                .unwrap();
+                            let fingerprint =
+                                secret_fingerprint(stored, &enc_key);
+                            comm.append(fingerprint.as_str().as_bytes());
+                        }
+                        comm.reply_ok();
                     }
-                );
+                    Err(e) => comm.reply(e),
+                }
             }
             // Get password name
             // This is used by the client to list the names of stored password
             // Login is not returned.
+            // P1 can be 0 to use the index in the store, 1 to count it from
+            // the most recently added or updated password, 2 to use the
+            // alphabetical rank of the name.
             io::Event::Command(Instruction::GetName) => {
-                let mut index_bytes = [0; 4];
-                index_bytes.copy_from_slice(comm.get(5, 5 + 4));
-                let index = u32::from_be_bytes(index_bytes);
-                match passwords.get(index as usize) {
+                let index = match get_u32(&comm, 0) {
+                    Some(index) => index as usize,
+                    None => {
+                        comm.reply(Error::WrongLength);
+                        continue;
+                    }
+                };
+                let password = match comm.get_p1() {
+                    0 => passwords.get(index),
+                    1 if index < passwords.len() => {
+                        recent_rank(passwords, index)
+                    }
+                    1 => None,
+                    2 if index < passwords.len() => {
+                        sorted_rank(passwords, index)
+                    }
+                    2 => None,
+                    _ => {
+                        comm.reply(StatusWords::Unknown);
+                        continue;
+                    }
+                };
+                match password {
                     Some(password) => {
                         comm.append(password.name.bytes());
                         comm.reply_ok()
@@ -186,22 +527,162 @@ extern "C" fn sample_main() {
                     None => comm.reply(Error::EntryNotFound),
                 }
             }
+            // Return the number of significant bytes of a name, as names
+            // are padded with zeros when stored.
+            io::Event::Command(Instruction::GetNameLength) => {
+                let index = match get_u32(&comm, 0) {
+                    Some(index) => index as usize,
+                    None => {
+                        comm.reply(Error::WrongLength);
+                        continue;
+                    }
+                };
+                match passwords.get(index) {
+                    Some(password) => {
+                        comm.append(&[password.name.len() as u8]);
+                        comm.reply_ok()
+                    }
+                    None => comm.reply(Error::EntryNotFound),
+                }
+            }
             // Get password by name
             // Returns login and password data.
+            // If P2 == 1, the password is encoded in base64, for transports
+            // which do not handle all byte values.
+            // If P2 == 2, the password is returned as a payload for a QR
+            // code, prefixed with a QR_* format byte.
+            // If P2 == 3, only the first bytes of the password are returned,
+            // their number being given after the name.
+            // If bit 2 of P2 is set, the name can be a prefix, and a status
+            // byte is returned first: the matching password is read, after
+            // its full name, if it is the only one. Otherwise the names of
+            // the candidates are returned.
+            // If bit 0 of P1 is set, the password is marked for rotation once
+            // read, for passwords which are used once.
             io::Event::Command(Instruction::GetByName) => {
-                let name = ArrayString::<32>::from_bytes(comm.get(5, 5 + 32));
+                let mark = comm.get_p1() & 0x01 != 0;
+                let prefix = comm.get_p2() & 0x04 != 0;
+                let (base64, qr, partial) = match comm.get_p2() & !0x04 {
+                    0 => (false, false, None),
+                    1 => (true, false, None),
+                    2 => (false, true, None),
+                    3 => match get_byte(&comm, 32) {
+                        Some(n) if n > 0 && n as usize <= MAX_PARTIAL_READ => {
+                            (false, false, Some(n as usize))
+                        }
+                        _ => {
+                            comm.reply(Error::InvalidData);
+                            continue;
+                        }
+                    },
+                    _ => {
+                        comm.reply(StatusWords::Unknown);
+                        continue;
+                    }
+                };
+                let mut name =
+                    ArrayString::<32>::from_bytes(comm.get(5, 5 + 32));
 
-                match passwords.into_iter().find(|&&x| x.name == name) {
+                // The decoy password is read exactly as a stored one, so it
+                // cannot be told apart by an onlooker.
+                let decoy = settings.get_ref().find_decoy(&name);
+                let is_decoy = decoy.is_some();
+
+                // An exact name is preferred to the names it is a prefix of
+                if prefix
+                    && !is_decoy
+                    && !passwords.into_iter().any(|x| x.name == name)
+                {
+                    let mut matches = passwords
+                        .into_iter()
+                        .filter(|x| x.name.starts_with(&name));
+                    match (matches.next(), matches.next()) {
+                        (Some(p), None) => name = p.name,
+                        (Some(_), Some(_)) => {
+                            let count = 2 + matches.count();
+                            comm.append(&[PREFIX_CANDIDATES, count as u8]);
+                            for p in passwords
+                                .into_iter()
+                                .filter(|x| x.name.starts_with(&name))
+                                .take(MAX_CANDIDATES)
+                            {
+                                comm.append(p.name.bytes());
+                            }
+                            comm.reply_ok();
+                            continue;
+                        }
+                        (None, _) => (),
+                    }
+                }
+
+                match decoy.or(passwords.into_iter().find(|&&x| x.name == name))
+                {
+                    Some(p) if p.is_totp() => {
+                        comm.reply(Error::TotpOnly);
+                    }
+                    Some(p) if !is_unlocked(p, armed_name) => {
+                        comm.reply(Error::NotArmed);
+                    }
                     Some(&p) => {
-                        if ui::MessageValidator::new(
-                            &[name.as_str()],
-                            &[&"Read", &"password"],
-                            &[&"Cancel"],
-                        )
-                        .ask()
-                        {
+                        // A short fingerprint of the secret lets the user
+                        // check the right password is read, without
+                        // displaying it.
+                        let fingerprint = secret_fingerprint(&p, &enc_key);
+                        let mut action = ArrayString::<16>::new();
+                        match partial {
+                            Some(n) => {
+                                action.append(b"Read first ");
+                                action.append_u32(n as u32);
+                            }
+                            None => action.append(b"Read password"),
+                        }
+                        if confirm(
+                            settings,
+                            ui::MessageValidator::new(
+                                &[name.as_str()],
+                                &[action.as_str(), fingerprint.as_str()],
+                                &[&"Cancel"],
+                            ),
+                        ) {
+                            // Store the mark before sending the password. The
+                            // decoy is not stored, so it is never marked. A
+                            // partial read does not disclose the password, so
+                            // it does not mark it either.
+                            if mark && !is_decoy && partial.is_none() {
+                                let index = passwords
+                                    .into_iter()
+                                    .position(|x| x.name == name)
+                                    .unwrap();
+                                let mut item = p;
+                                item.flags |= password::FLAG_ROTATE;
+                                passwords.replace(index, &item);
+                            }
+                            if prefix {
+                                comm.append(&[PREFIX_READ]);
+                                comm.append(name.bytes());
+                            }
                             comm.append(p.login.bytes());
-                            comm.append(p.pass.bytes());
+                            if let Some(n) = partial {
+                                let secret = p.secret();
+                                comm.append(&secret[..n.min(secret.len())]);
+                            } else if qr && p.is_binary() {
+                                let encoded = encoding::to_base64(p.secret());
+                                comm.append(&[QR_BASE64]);
+                                comm.append(encoded.as_str().as_bytes());
+                            } else if qr {
+                                comm.append(&[QR_TEXT]);
+                                comm.append(p.secret());
+                            } else if base64 {
+                                let encoded = encoding::to_base64(p.secret());
+                                comm.append(encoded.as_str().as_bytes());
+                            } else {
+                                comm.append(p.pass.bytes());
+                                // The length of binary secrets is required,
+                                // as they may end with zeros.
+                                if p.is_binary() {
+                                    comm.append(&[p.secret_len]);
+                                }
+                            }
                             comm.reply_ok();
                         } else {
                             comm.reply(Error::NoConsent);
@@ -214,23 +695,172 @@ extern "C" fn sample_main() {
                 }
             }
 
+            // Return the number of confirmations GetByName would ask for the
+            // given name, without reading the password. A protected password
+            // must be armed first, which is confirmed too. This command ends
+            // any arming, so it is not taken into account.
+            io::Event::Command(Instruction::GetReadConfirmations) => {
+                let name = ArrayString::<32>::from_bytes(comm.get(5, 5 + 32));
+                let decoy = settings.get_ref().find_decoy(&name);
+                match decoy.or(passwords.into_iter().find(|&&x| x.name == name))
+                {
+                    Some(p) if p.is_totp() => comm.reply(Error::TotpOnly),
+                    Some(p) => {
+                        let count = if p.is_arm_required() { 2 } else { 1 };
+                        comm.append(&[count]);
+                        comm.reply_ok();
+                    }
+                    None => comm.reply(Error::EntryNotFound),
+                }
+            }
+            // Compute HMAC-SHA1 of a challenge, keyed with a stored password.
+            // This proves the knowledge of the password without sending it.
+            io::Event::Command(Instruction::ChallengeResponse) => {
+                let len = data_len(&comm);
+                if len <= 32 {
+                    comm.reply(Error::WrongLength);
+                    continue;
+                }
+                let name = ArrayString::<32>::from_bytes(comm.get(5, 5 + 32));
+                match passwords.into_iter().find(|&&x| x.name == name) {
+                    Some(p) if p.is_totp() => comm.reply(Error::TotpOnly),
+                    Some(p) if !is_unlocked(p, armed_name) => {
+                        comm.reply(Error::NotArmed)
+                    }
+                    Some(&p) => {
+                        if confirm(
+                            settings,
+                            ui::MessageValidator::new(
+                                &[name.as_str()],
+                                &[&"Answer", &"challenge"],
+                                &[&"Cancel"],
+                            ),
+                        ) {
+                            let key = p.secret();
+                            let challenge = comm.get(5 + 32, 5 + len);
+                            let response = sha1::hmac_sha1(key, challenge);
+                            comm.append(&response);
+                            comm.reply_ok();
+                        } else {
+                            comm.reply(Error::NoConsent);
+                        }
+                    }
+                    None => comm.reply(Error::EntryNotFound),
+                }
+            }
+
+            // Compute the TOTP code of a TOTP secret, at the time given by
+            // the host after the name, as the device has no clock. The secret
+            // is never returned.
+            io::Event::Command(Instruction::GetTotp) => {
+                let time = match get_totp_time(&comm) {
+                    Ok(time) => time,
+                    Err(e) => {
+                        comm.reply(e);
+                        continue;
+                    }
+                };
+                let name = ArrayString::<32>::from_bytes(comm.get(5, 5 + 32));
+                match passwords.into_iter().find(|&&x| x.name == name) {
+                    Some(p) if !p.is_totp() => comm.reply(Error::InvalidData),
+                    Some(p) if !is_unlocked(p, armed_name) => {
+                        comm.reply(Error::NotArmed)
+                    }
+                    Some(&p) => {
+                        if confirm(
+                            settings,
+                            ui::MessageValidator::new(
+                                &[name.as_str()],
+                                &[&"Get TOTP", &"code"],
+                                &[&"Cancel"],
+                            ),
+                        ) {
+                            let code = totp(p.secret(), time);
+                            comm.append(code.as_str().as_bytes());
+                            comm.reply_ok();
+                        } else {
+                            comm.reply(Error::NoConsent);
+                        }
+                    }
+                    None => comm.reply(Error::EntryNotFound),
+                }
+            }
+            // Number of seconds before the TOTP code of a TOTP secret
+            // changes, for a countdown on the host. Reveals nothing about the
+            // secret, so no consent is required.
+            io::Event::Command(Instruction::GetTotpRemaining) => {
+                let time = match get_totp_time(&comm) {
+                    Ok(time) => time,
+                    Err(e) => {
+                        comm.reply(e);
+                        continue;
+                    }
+                };
+                let name = ArrayString::<32>::from_bytes(comm.get(5, 5 + 32));
+                match passwords.into_iter().find(|&&x| x.name == name) {
+                    Some(p) if !p.is_totp() => comm.reply(Error::InvalidData),
+                    Some(_) => {
+                        let remaining = TOTP_PERIOD - time % TOTP_PERIOD;
+                        comm.append(&[remaining as u8]);
+                        comm.reply_ok();
+                    }
+                    None => comm.reply(Error::EntryNotFound),
+                }
+            }
+
             // Display a password on the screen only, without communicating it
             // to the host.
             io::Event::Command(Instruction::ShowOnScreen) => {
                 let name = ArrayString::<32>::from_bytes(comm.get(5, 5 + 32));
 
                 match passwords.into_iter().find(|&&x| x.name == name) {
+                    Some(p) if p.is_totp() => {
+                        ui::popup("TOTP secret");
+                        comm.reply(Error::TotpOnly);
+                    }
+                    Some(p) if !is_unlocked(p, armed_name) => {
+                        ui::popup("Password not armed");
+                        comm.reply(Error::NotArmed);
+                    }
                     Some(&p) => {
-                        if ui::MessageValidator::new(
-                            &[name.as_str()],
-                            &[&"Read", &"password"],
-                            &[&"Cancel"],
-                        )
-                        .ask()
-                        {
+                        if confirm(
+                            settings,
+                            ui::MessageValidator::new(
+                                &[name.as_str()],
+                                &[&"Read", &"password"],
+                                &[&"Cancel"],
+                            ),
+                        ) {
                             ui::popup(p.login.as_str());
-                            ui::popup(p.pass.as_str());
-                            comm.reply_ok();
+                            let hex;
+                            let secret = if p.is_binary() {
+                                hex = encoding::to_hex(p.secret());
+                                hex.as_str()
+                            } else {
+                                p.pass.as_str()
+                            };
+                            // Split display: an onlooker glimpsing one screen
+                            // does not get the whole password.
+                            if comm.get_p2() & 0x01 == 0 {
+                                ui::popup(secret);
+                                comm.reply_ok();
+                            } else {
+                                let (first, second) = split_half(secret);
+                                ui::popup(first);
+                                if confirm(
+                                    settings,
+                                    ui::MessageValidator::new(
+                                        &[],
+                                        &[&"Show second", &"half"],
+                                        &[&"Cancel"],
+                                    ),
+                                ) {
+                                    ui::popup(second);
+                                    comm.reply_ok();
+                                } else {
+                                    comm.reply(Error::NoConsent);
+                                }
+                            }
                         } else {
                             ui::popup("Operation cancelled");
                             comm.reply(Error::NoConsent);
@@ -248,13 +878,14 @@ extern "C" fn sample_main() {
                 let name = ArrayString::<32>::from_bytes(comm.get(5, 5 + 32));
                 match passwords.into_iter().position(|x| x.name == name) {
                     Some(p) => {
-                        if ui::MessageValidator::new(
-                            &[name.as_str()],
-                            &[&"Remove", &"password"],
-                            &[&"Cancel"],
-                        )
-                        .ask()
-                        {
+                        if confirm(
+                            settings,
+                            ui::MessageValidator::new(
+                                &[name.as_str()],
+                                &[&"Remove", &"password"],
+                                &[&"Cancel"],
+                            ),
+                        ) {
                             passwords.remove(p);
                             comm.reply_ok();
                         } else {
@@ -267,45 +898,225 @@ extern "C" fn sample_main() {
                     }
                 }
             }
+            // Remove the passwords of a range of indexes, with a single
+            // confirmation. Data contains the index of the first password and
+            // the number of passwords. The range is clamped to the stored
+            // passwords.
+            io::Event::Command(Instruction::DeleteRange) => {
+                let (start, count) =
+                    match (get_u32(&comm, 0), get_u32(&comm, 4)) {
+                        (Some(start), Some(count)) if data_len(&comm) == 8 => {
+                            (start as usize, count as usize)
+                        }
+                        _ => {
+                            comm.reply(Error::WrongLength);
+                            continue;
+                        }
+                    };
+                let len = passwords.len();
+                let end = start.saturating_add(count).min(len);
+                let removed = end.saturating_sub(start);
+                if removed > 0 {
+                    let mut message = ArrayString::<32>::new();
+                    message.append(b"Remove ");
+                    message.append_u32(removed as u32);
+                    if !confirm(
+                        settings,
+                        ui::MessageValidator::new(
+                            &[message.as_str()],
+                            &[&"Remove", &"passwords"],
+                            &[&"Cancel"],
+                        ),
+                    ) {
+                        comm.reply(Error::NoConsent);
+                        continue;
+                    }
+                    // Remove from the end, so the indexes of the passwords
+                    // left in the range do not shift.
+                    for index in (start..end).rev() {
+                        passwords.remove(index);
+                    }
+                }
+                comm.append(&(removed as u32).to_be_bytes());
+                comm.reply_ok();
+            }
+            // Erase the password of an entry, keeping its name, login, tag
+            // and position as a placeholder. The sequence is kept too: the
+            // position follows the order of modifications, which the wipe
+            // would break if it counted as one.
+            io::Event::Command(Instruction::WipePassword) => {
+                let name = ArrayString::<32>::from_bytes(comm.get(5, 5 + 32));
+                match passwords.into_iter().position(|x| x.name == name) {
+                    Some(index) => {
+                        if confirm(
+                            settings,
+                            ui::MessageValidator::new(
+                                &[name.as_str()],
+                                &[&"Wipe", &"password"],
+                                &[&"Cancel"],
+                            ),
+                        ) {
+                            let mut item = *passwords.get(index).unwrap();
+                            item.pass = ArrayString::new();
+                            item.secret_len = 0;
+                            passwords.replace(index, &item);
+                            comm.reply_ok();
+                        } else {
+                            comm.reply(Error::NoConsent);
+                        }
+                    }
+                    None => comm.reply(Error::EntryNotFound),
+                }
+            }
             // Export
-            // P1 can be 0 for plaintext, 1 for encrypted export.
-            io::Event::Command(Instruction::Export) => match comm.get_p1() {
-                0 => export(&mut comm, &passwords, None),
-                1 => export(&mut comm, &passwords, Some(&enc_key)),
-                _ => comm.reply(StatusWords::Unknown),
-            },
-            // Reserved for export
+            // P1 can be 0 for plaintext, 1 for encrypted export, 2 for export
+            // encrypted with the passphrase key.
+            // Data can contain the index of the first record to be exported,
+            // to resume an interrupted export, followed by a tag to export
+            // only the passwords with this tag.
+            // If P2 bit 0 is set, a trailer is sent after the last password.
+            io::Event::Command(Instruction::Export) => {
+                let trailer = comm.get_p2() & 0x01 != 0;
+                let seal = if comm.get_p2() & 0x02 != 0 {
+                    Some(seal_key(&enc_key))
+                } else {
+                    None
+                };
+                let start = match (data_len(&comm), get_u32(&comm, 0)) {
+                    (0, _) => 0,
+                    (_, Some(start)) => start as usize,
+                    (_, None) => {
+                        comm.reply(Error::WrongLength);
+                        continue;
+                    }
+                };
+                let tag = get_byte(&comm, 4);
+                let key = match comm.get_p1() {
+                    0 => None,
+                    1 => Some(&enc_key),
+                    2 => match &passphrase_key {
+                        Some(k) => Some(k),
+                        None => {
+                            comm.reply(Error::NoSession);
+                            continue;
+                        }
+                    },
+                    _ => {
+                        comm.reply(StatusWords::Unknown);
+                        continue;
+                    }
+                };
+                let compact = comm.get_p2() & 0x04 != 0;
+                let chacha = comm.get_p2() & 0x08 != 0;
+                // Compact records leak the length of the fields, and are not
+                // made of complete blocks
+                if (compact && key.is_some()) || (chacha && key.is_none()) {
+                    comm.reply(StatusWords::Unknown);
+                    continue;
+                }
+                // Only the device can decrypt records encrypted with its own
+                // key: other exports can be read by the host, as the
+                // passphrase is known to it. Protected passwords are then
+                // only exported once armed, and TOTP secrets are left out.
+                let readable = comm.get_p1() != 1;
+                if readable
+                    && passwords.into_iter().any(|p| {
+                        tag.map_or(true, |t| p.tag == t)
+                            && !p.is_totp()
+                            && !is_unlocked(p, armed_name)
+                    })
+                {
+                    comm.reply(Error::NotArmed);
+                    continue;
+                }
+                let options = ExportOptions {
+                    start,
+                    tag,
+                    trailer,
+                    seal,
+                    compact,
+                    chacha,
+                    skip_totp: readable,
+                };
+                export(&mut comm, &passwords, settings, key, options);
+            }
+            // Only valid during an export
             io::Event::Command(Instruction::ExportNext) => {
-                comm.reply(StatusWords::Unknown);
+                comm.reply(Error::NoSession);
             }
             // Import
-            // P1 can be 0 for plaintext, 1 for encrypted import.
-            io::Event::Command(Instruction::Import) => match comm.get_p1() {
-                0 => import(&mut comm, &mut passwords, None),
-                1 => import(&mut comm, &mut passwords, Some(&enc_key)),
-                _ => comm.reply(StatusWords::Unknown),
-            },
-            // Reserved for import
+            // P1 can be 0 for plaintext, 1 for encrypted import, 2 for import
+            // encrypted with the passphrase key.
+            io::Event::Command(Instruction::Import) => {
+                let key = match comm.get_p1() {
+                    0 => None,
+                    1 => Some(&enc_key),
+                    2 => match &passphrase_key {
+                        Some(k) => Some(k),
+                        None => {
+                            comm.reply(Error::NoSession);
+                            continue;
+                        }
+                    },
+                    _ => {
+                        comm.reply(StatusWords::Unknown);
+                        continue;
+                    }
+                };
+                let rename = comm.get_p2() & 0x01 != 0;
+                let seal = if comm.get_p2() & 0x02 != 0 {
+                    Some(seal_key(&enc_key))
+                } else {
+                    None
+                };
+                let verify_only = comm.get_p2() & 0x04 != 0;
+                let compact = comm.get_p2() & 0x08 != 0;
+                let check_count = comm.get_p2() & 0x10 != 0;
+                let newest = comm.get_p2() & 0x20 != 0;
+                let unverified = comm.get_p2() & 0x40 != 0;
+                let chacha = comm.get_p2() & 0x80 != 0;
+                if (compact && key.is_some())
+                    || (unverified && (key.is_none() || verify_only || chacha))
+                    || (chacha && key.is_none())
+                {
+                    comm.reply(StatusWords::Unknown);
+                    continue;
+                }
+                let options = ImportOptions {
+                    rename,
+                    seal,
+                    verify_only,
+                    compact,
+                    check_count,
+                    newest,
+                    unverified,
+                    chacha,
+                };
+                import(&mut comm, &mut passwords, settings, key, options);
+            }
+            // Only valid during an import
             io::Event::Command(Instruction::ImportNext) => {
-                comm.reply(StatusWords::Unknown);
+                comm.reply(Error::NoSession);
             }
             io::Event::Command(Instruction::Clear) => {
                 // Remove all passwords
                 comm.reply::<Reply>(
-                    if ui::MessageValidator::new(
-                        &[],
-                        &[&"Remove all", &"passwords"],
-                        &[&"Cancel"],
-                    )
-                    .ask()
-                    {
-                        if ui::MessageValidator::new(
+                    if confirm(
+                        settings,
+                        ui::MessageValidator::new(
                             &[],
-                            &[&"Are you", &"sure?"],
+                            &[&"Remove all", &"passwords"],
                             &[&"Cancel"],
-                        )
-                        .ask()
-                        {
+                        ),
+                    ) {
+                        if confirm(
+                            settings,
+                            ui::MessageValidator::new(
+                                &[],
+                                &[&"Are you", &"sure?"],
+                                &[&"Cancel"],
+                            ),
+                        ) {
                             passwords.clear();
                             StatusWords::Ok.into()
                         } else {
@@ -324,7 +1135,9 @@ extern "C" fn sample_main() {
             // HasName
             io::Event::Command(Instruction::HasName) => {
                 let name = ArrayString::<32>::from_bytes(comm.get(5, 5 + 32));
-                match passwords.into_iter().find(|&&x| x.name == name) {
+                let decoy = settings.get_ref().find_decoy(&name);
+                match decoy.or(passwords.into_iter().find(|&&x| x.name == name))
+                {
                     Some(_) => {
                         comm.append(&[1]);
                     }
@@ -334,192 +1147,1970 @@ extern "C" fn sample_main() {
                 }
                 comm.reply_ok();
             }
-        }
-    }
-}
-
-/// Generates a random password.
-///
-/// # Arguments
-///
-/// * `dest` - An array where the result is stored. Must be at least
-///   `size` long. No terminal zero is written.
-/// * `size` - The size of the password to be generated
-fn generate_random_password(dest: &mut [u8], size: usize) {
-    for item in dest.iter_mut().take(size) {
-        let rand_index = random::rand_u32_range(0..PASS_CHARS.len() as u32);
-        *item = PASS_CHARS.chars().nth(rand_index as usize).unwrap() as u8;
-    }
-}
-
-/// Adds or update a password in the store.
-/// Queries confirmation from the user in the UX.
-///
-/// # Arguments
-///
-/// * `name` - Slice to the new name of the password. Must be 32 bytes long.
-/// * `login` - Slice to the new login of the password. Must be 32 bytes long.
-/// * `pass` - New password. If None, a password is generated automatically.
-fn set_password(
-    passwords: &mut nvm::Collection<PasswordItem, 128>,
-    name: &ArrayString<32>,
-    login: &ArrayString<32>,
-    pass: &Option<ArrayString<32>>
-) -> Result<(), Error> {
-    // Create the item to be added.
-    let mut new_item = PasswordItem::new();
-    new_item.name = *name;
-    new_item.login = *login;
-    match pass {
-        Some(a) => new_item.pass = *a,
-        None => {
-            let mut pass = [0u8; 16];
-            let len = pass.len();
-            generate_random_password(&mut pass, len);
-            new_item.pass.set_from_bytes(&pass);
-        }
-    }
-
-    return match passwords.into_iter().position(|x| x.name == *name) {
-        Some(index) => {
-            // A password with this name already exists.
-            if !ui::MessageValidator::new(
-                &[name.as_str()],
-                &[&"Update", &"password"],
-                &[&"Cancel"],
-            )
-            .ask()
-            {
-                return Err(Error::NoConsent);
+            // Count the names matching the glob pattern given in Data.
+            // If P1 == 1, the indexes of the matching passwords follow, up to
+            // MAX_MATCHES of them.
+            io::Event::Command(Instruction::MatchNames) => {
+                let pattern = comm.get(5, 5 + data_len(&comm));
+                let mut indexes = [0u8; MAX_MATCHES];
+                let mut count = 0;
+                for (i, p) in passwords.into_iter().enumerate() {
+                    let name = &p.name.bytes()[..p.name.len()];
+                    if password::glob_match(pattern, name) {
+                        if count < MAX_MATCHES {
+                            indexes[count] = i as u8;
+                        }
+                        count += 1;
+                    }
+                }
+                comm.append(&(count as u32).to_be_bytes());
+                if comm.get_p1() == 1 {
+                    comm.append(&indexes[..count.min(MAX_MATCHES)]);
+                }
+                comm.reply_ok();
             }
-            passwords.remove(index);
-            match passwords.add(&new_item) {
-                Ok(()) => Ok(()),
-                // We just removed a password, this should not happen
-                Err(nvm::StorageFullError) => panic!(),
+            // Return the indexes of the passwords named as a password with a
+            // lower index, which lookups never find. Adding and importing
+            // keep names unique, so this only happens if the store has been
+            // corrupted.
+            io::Event::Command(Instruction::GetDuplicateNames) => {
+                let mut indexes = [0u8; 128];
+                let mut count = 0;
+                for (i, p) in passwords.into_iter().enumerate() {
+                    if passwords.into_iter().take(i).any(|x| x.name == p.name)
+                    {
+                        indexes[count] = i as u8;
+                        count += 1;
+                    }
+                }
+                comm.append(&(count as u32).to_be_bytes());
+                comm.append(&indexes[..count]);
+                comm.reply_ok();
             }
-        }
-        None => {
-            // Ask user confirmation
-            if !ui::MessageValidator::new(
-                &[name.as_str()],
-                &[&"Create", &"password"],
-                &[&"Cancel"],
-            )
-            .ask()
-            {
-                return Err(Error::NoConsent);
+            // Return the status of a range of passwords, one byte each, so
+            // the client can list them without a command per password. The
+            // Data field contains the start index and the count.
+            io::Event::Command(Instruction::GetFlags) => {
+                let (start, count) =
+                    match (get_u32(&comm, 0), get_u32(&comm, 4)) {
+                        (Some(start), Some(count)) => {
+                            (start as usize, count as usize)
+                        }
+                        _ => {
+                            comm.reply(Error::WrongLength);
+                            continue;
+                        }
+                    };
+                let count = count.min(MAX_FLAGS);
+                let end = start.saturating_add(count).min(passwords.len());
+                let returned = end.saturating_sub(start);
+                comm.append(&[returned as u8]);
+                for p in passwords.into_iter().skip(start).take(returned) {
+                    comm.append(&[p.status()]);
+                }
+                comm.reply_ok();
             }
-            match passwords.add(&new_item) {
-                Ok(()) => Ok(()),
-                Err(nvm::StorageFullError) => Err(Error::StorageFull),
+            // Return the character classes and the length of a password, so
+            // the client can check it against site rules without reading it.
+            io::Event::Command(Instruction::GetPasswordClasses) => {
+                let name = ArrayString::<32>::from_bytes(comm.get(5, 5 + 32));
+                match passwords.into_iter().find(|&&x| x.name == name) {
+                    Some(p) if p.is_totp() => comm.reply(Error::TotpOnly),
+                    Some(p) if !is_unlocked(p, armed_name) => {
+                        comm.reply(Error::NotArmed)
+                    }
+                    Some(p) => {
+                        let len = p.secret().len() as u8;
+                        comm.append(&[p.pass_classes(), len]);
+                        comm.reply_ok();
+                    }
+                    None => comm.reply(Error::EntryNotFound),
+                }
             }
-        }
-    };
-}
-
-/// Export procedure.
-///
-/// # Arguments
-///
-/// * `enc_key` - Encryption key. If None, passwords are exported in plaintext.
-fn export(
-    comm: &mut io::Comm,
-    passwords: &nvm::Collection<PasswordItem, 128>,
-    enc_key: Option<&[u8; 32]>,
-) {
-    // Ask user confirmation
-    if !ui::MessageValidator::new(&[], &[&"Export", &"passwords"], &[&"Cancel"])
-        .ask()
-    {
-        comm.reply(Error::NoConsent);
-        return;
-    }
-
-    // If export is in plaintext, add a warning
-    let encrypted = enc_key.is_some();
-    if !encrypted
-        && !ui::MessageValidator::new(
-            &[&"Export is plaintext!"],
-            &[&"Confirm"],
-            &[&"Cancel"],
-        )
-        .ask()
+            // Tell if two passwords are identical. Comparison is done on the
+            // device in constant time, only the result is returned.
+            io::Event::Command(Instruction::ComparePasswords) => {
+                let name_a = ArrayString::<32>::from_bytes(comm.get(5, 5 + 32));
+                let name_b =
+                    ArrayString::<32>::from_bytes(comm.get(5 + 32, 5 + 64));
+                let a = passwords.into_iter().find(|&&x| x.name == name_a);
+                let b = passwords.into_iter().find(|&&x| x.name == name_b);
+                match (a, b) {
+                    (Some(a), Some(b)) if a.is_totp() || b.is_totp() => {
+                        comm.reply(Error::TotpOnly)
+                    }
+                    (Some(a), Some(b))
+                        if !is_unlocked(a, armed_name)
+                            || !is_unlocked(b, armed_name) =>
+                    {
+                        comm.reply(Error::NotArmed)
+                    }
+                    (Some(a), Some(b)) => {
+                        comm.append(&[a.pass.ct_eq(&b.pass) as u8]);
+                        comm.reply_ok();
+                    }
+                    _ => comm.reply(Error::EntryNotFound),
+                }
+            }
+            // Return the names of the entries whose password is the one given
+            // in the data, to find where a leaked password is used. The
+            // stored passwords are compared in constant time and never
+            // returned.
+            io::Event::Command(Instruction::FindPassword) => {
+                let len = data_len(&comm);
+                if len > 32 {
+                    comm.reply(Error::WrongLength);
+                    continue;
+                }
+                // An empty value would find the wiped passwords
+                if len == 0 {
+                    comm.reply(Error::InvalidData);
+                    continue;
+                }
+                let value = ArrayString::<32>::from_bytes(comm.get(5, 5 + len));
+                // Binary secrets may end with zeros, so their length must
+                // match too. Protected passwords are skipped unless armed,
+                // and TOTP secrets are only used to compute codes.
+                let matches = |p: &PasswordItem| {
+                    !p.is_totp()
+                        && is_unlocked(p, armed_name)
+                        && p.pass.ct_eq(&value)
+                        && (!p.is_binary() || p.secret_len as usize == len)
+                };
+                if !confirm(
+                    settings,
+                    ui::MessageValidator::new(
+                        &[],
+                        &[&"Find entries", &"of a password"],
+                        &[&"Cancel"],
+                    ),
+                ) {
+                    comm.reply(Error::NoConsent);
+                    continue;
+                }
+                let total =
+                    passwords.into_iter().filter(|p| matches(p)).count();
+                comm.append(&(total as u32).to_be_bytes());
+                for p in passwords
+                    .into_iter()
+                    .filter(|p| matches(p))
+                    .take(MAX_FOUND)
+                {
+                    comm.append(p.name.bytes());
+                }
+                comm.reply_ok();
+            }
+            // Return the names of the passwords ordered from the weakest to
+            // the strongest, starting at the rank given in the data, so an
+            // audit can show the passwords to fix first. Passwords of the
+            // same strength are ordered by index. Protected passwords which
+            // are not armed are left out.
+            io::Event::Command(Instruction::GetWeakest) => {
+                let start = match get_u32(&comm, 0) {
+                    Some(start) => start as usize,
+                    None => {
+                        comm.reply(Error::WrongLength);
+                        continue;
+                    }
+                };
+                let mut scores = [0u16; 128];
+                let mut indexes = [0u8; 128];
+                let mut count = 0;
+                for (i, p) in passwords.into_iter().enumerate() {
+                    if is_unlocked(p, armed_name) {
+                        scores[count] = p.strength();
+                        indexes[count] = i as u8;
+                        count += 1;
+                    }
+                }
+                let scores = &scores[..count];
+                let end = start.saturating_add(MAX_WEAKEST).min(scores.len());
+                comm.append(&[end.saturating_sub(start) as u8]);
+                // The rank of a password is the number of passwords before
+                // it in the order.
+                let key = |i: usize| (scores[i], i);
+                let rank_of = |i| {
+                    (0..scores.len()).filter(|&j| key(j) < key(i)).count()
+                };
+                for rank in start..end {
+                    let index = (0..scores.len())
+                        .find(|&i| rank_of(i) == rank)
+                        .unwrap();
+                    let p = passwords.get(indexes[index] as usize).unwrap();
+                    comm.append(p.name.bytes());
+                }
+                comm.reply_ok();
+            }
+            // Return the names of the passwords which lack one of the
+            // character classes given in the data, starting at the given
+            // position among them, so they can be updated when a site policy
+            // changes. Binary secrets, wiped passwords and protected
+            // passwords which are not armed are ignored.
+            io::Event::Command(Instruction::GetLackingClasses) => {
+                let (required, start) =
+                    match (get_byte(&comm, 0), get_u32(&comm, 1)) {
+                        (Some(required), Some(start)) => {
+                            (required, start as usize)
+                        }
+                        _ => {
+                            comm.reply(Error::WrongLength);
+                            continue;
+                        }
+                    };
+                let all = password::CLASS_LOWER
+                    | password::CLASS_UPPER
+                    | password::CLASS_DIGIT
+                    | password::CLASS_SYMBOL;
+                if required == 0 || required & !all != 0 {
+                    comm.reply(Error::InvalidData);
+                    continue;
+                }
+                let lacking = || {
+                    passwords.into_iter().filter(|x| {
+                        !x.is_binary()
+                            && !x.secret().is_empty()
+                            && is_unlocked(x, armed_name)
+                            && x.pass_classes() & required != required
+                    })
+                };
+                let total = lacking().count();
+                let returned = total.saturating_sub(start).min(MAX_LACKING);
+                comm.append(&(total as u32).to_be_bytes());
+                comm.append(&[returned as u8]);
+                for p in lacking().skip(start).take(returned) {
+                    comm.append(p.name.bytes());
+                }
+                comm.reply_ok();
+            }
+            // Return the lengths of the shortest and longest passwords, so a
+            // client can flag short passwords. Wiped passwords and protected
+            // passwords which are not armed are ignored.
+            io::Event::Command(Instruction::GetLengthRange) => {
+                let mut lengths = passwords
+                    .into_iter()
+                    .filter(|x| is_unlocked(x, armed_name))
+                    .map(|x| x.secret().len() as u8)
+                    .filter(|&len| len > 0);
+                match lengths.next() {
+                    Some(first) => {
+                        let (min, max) = lengths.fold((first, first), |r, l| {
+                            (r.0.min(l), r.1.max(l))
+                        });
+                        comm.append(&[min, max]);
+                        comm.reply_ok();
+                    }
+                    None => comm.reply(Error::EntryNotFound),
+                }
+            }
+            // Tell if a candidate password can be stored as is, without
+            // storing it. The reply is a reason code, 0 if accepted.
+            io::Event::Command(Instruction::CheckPassword) => {
+                let candidate = comm.get(5, 5 + data_len(&comm));
+                comm.append(&[password::check_field(candidate, 32)]);
+                comm.reply_ok();
+            }
+            // Count passwords by first letter of their name, for an
+            // alphabetical index in the client.
+            io::Event::Command(Instruction::GetLetterCounts) => {
+                // One bucket per letter, and a last one for other characters
+                let mut counts = [0u8; 27];
+                for p in passwords.into_iter() {
+                    let first = p.name.bytes()[0].to_ascii_uppercase();
+                    let bucket = match first {
+                        b'A'..=b'Z' => (first - b'A') as usize,
+                        _ => 26,
+                    };
+                    counts[bucket] += 1;
+                }
+                comm.append(&counts);
+                comm.reply_ok();
+            }
+            // Count passwords by tag, for a summary of the categories in the
+            // client. Only the tags in use are returned, starting at the tag
+            // given in the data, as (tag, count) pairs.
+            io::Event::Command(Instruction::GetTagCounts) => {
+                let start = get_byte(&comm, 0).unwrap_or(0) as usize;
+                let mut counts = [0u8; 256];
+                for p in passwords.into_iter() {
+                    counts[p.tag as usize] += 1;
+                }
+                let used = counts
+                    .iter()
+                    .enumerate()
+                    .skip(start)
+                    .filter(|(_, &count)| count > 0)
+                    .take(MAX_TAG_COUNTS);
+                for (tag, &count) in used {
+                    comm.append(&[tag as u8, count]);
+                }
+                comm.reply_ok();
+            }
+            // Count entries sharing their password with another entry.
+            // Comparison is done on the device, only the count is returned.
+            io::Event::Command(Instruction::GetReuseCount) => {
+                let count = reused_count(passwords) as u32;
+                comm.append(&count.to_be_bytes());
+                comm.reply_ok();
+            }
+            // Return the number of NVM bytes used by the stored passwords,
+            // and the total number of bytes reserved for the store.
+            io::Event::Command(Instruction::GetStorageUsage) => {
+                let slot_size =
+                    core::mem::size_of::<nvm::AlignedStorage<PasswordItem>>();
+                let used = (passwords.len() * slot_size) as u32;
+                let total = core::mem::size_of_val(passwords) as u32;
+                comm.append(&used.to_be_bytes());
+                comm.append(&total.to_be_bytes());
+                comm.reply_ok();
+            }
+            // Estimate the number of Flash pages written by an operation, so
+            // the user can avoid unnecessary wear.
+            // If P1 == 0, for removing all the passwords.
+            // If P1 == 1, for importing the number of passwords given in the
+            // data. Each imported password also updates the sequence counter
+            // in the settings.
+            io::Event::Command(Instruction::GetWearEstimate) => {
+                let settings_size = core::mem::size_of::<Settings>();
+                let per_import = passwords.add_page_writes()
+                    + store::atomic_page_writes(settings_size);
+                let writes = match comm.get_p1() {
+                    0 => passwords.clear_page_writes(),
+                    1 => match get_u32(&comm, 0) {
+                        Some(count) => {
+                            (count as usize).saturating_mul(per_import)
+                        }
+                        None => {
+                            comm.reply(Error::WrongLength);
+                            continue;
+                        }
+                    },
+                    _ => {
+                        comm.reply(StatusWords::Unknown);
+                        continue;
+                    }
+                };
+                comm.append(&(writes as u32).to_be_bytes());
+                comm.reply_ok();
+            }
+            // Return the free space of the stack, for diagnostics
+            io::Event::Command(Instruction::GetStackUsage) => {
+                let (free, size) = stack_headroom();
+                comm.append(&(free as u32).to_be_bytes());
+                comm.append(&(size as u32).to_be_bytes());
+                comm.reply_ok();
+            }
+            // Return the BIP32 path used to derive the encryption key, so
+            // the client can check two devices will interoperate.
+            io::Event::Command(Instruction::GetDerivationPath) => {
+                comm.append(&[BIP32_PATH.len() as u8]);
+                for component in BIP32_PATH.iter() {
+                    comm.append(&component.to_be_bytes());
+                }
+                comm.reply_ok();
+            }
+            // Return the codes of the supported instructions, so a client
+            // can detect features without probing each command.
+            io::Event::Command(Instruction::GetCommands) => {
+                for ins in 0..=255u8 {
+                    if Instruction::try_from(ins).is_ok() {
+                        comm.append(&[ins]);
+                    }
+                }
+                comm.reply_ok();
+            }
+            // Encrypt and MAC dummy records as an encrypted export would, so
+            // the client can time the device cryptography apart from the
+            // transport.
+            io::Event::Command(Instruction::Benchmark) => {
+                let count = match get_byte(&comm, 0) {
+                    Some(count) => count,
+                    None => {
+                        comm.reply(Error::WrongLength);
+                        continue;
+                    }
+                };
+                if count > MAX_BENCHMARK_RECORDS {
+                    comm.reply(Error::InvalidData);
+                    continue;
+                }
+                benchmark(count);
+                comm.append(&[count]);
+                comm.reply_ok();
+            }
+            // Return the number of passwords added since the application
+            // started.
+            io::Event::Command(Instruction::GetAddCount) => {
+                comm.append(&add_count.to_be_bytes());
+                comm.reply_ok();
+            }
+            // Run health tests on the random number generator used for
+            // password generation, so a client can refuse generated
+            // passwords if it is faulty.
+            io::Event::Command(Instruction::CheckRng) => {
+                comm.append(&[if rng_healthy() { 0 } else { 1 }]);
+                comm.reply_ok();
+            }
+            // Return the number of confirmations approved by the user. Only
+            // the total is kept, not what was confirmed.
+            io::Event::Command(Instruction::GetConfirmationCount) => {
+                comm.append(&settings.get_ref().confirmations.to_be_bytes());
+                comm.reply_ok();
+            }
+            // Open an add session: the given number of passwords can then be
+            // created without confirmation. A count of zero closes the
+            // session.
+            io::Event::Command(Instruction::StartAddSession) => {
+                let count = match get_byte(&comm, 0) {
+                    Some(count) => count as u32,
+                    None => {
+                        comm.reply(Error::WrongLength);
+                        continue;
+                    }
+                };
+                if count == 0 {
+                    session_adds = 0;
+                    comm.reply_ok();
+                } else if count > MAX_SESSION_ADDS {
+                    comm.reply(Error::InvalidData);
+                } else {
+                    let mut message = ArrayString::<16>::new();
+                    message.append_u32(count);
+                    message.append(b" passwords");
+                    if confirm(
+                        settings,
+                        ui::MessageValidator::new(
+                            &[message.as_str()],
+                            &[&"Add without", &"confirmation"],
+                            &[&"Cancel"],
+                        ),
+                    ) {
+                        session_adds = count;
+                        comm.reply_ok();
+                    } else {
+                        comm.reply(Error::NoConsent);
+                    }
+                }
+            }
+            // Set the label of the device, displayed on the idle screen.
+            // An empty label restores the default one.
+            io::Event::Command(Instruction::SetLabel) => {
+                let label = ArrayString::<16>::from_bytes(comm.get(5, 5 + 16));
+                if !label.is_utf8() {
+                    comm.reply(Error::InvalidData);
+                } else if confirm(
+                    settings,
+                    ui::MessageValidator::new(
+                        &[label.as_str()],
+                        &[&"Set", &"label"],
+                        &[&"Cancel"],
+                    ),
+                ) {
+                    let mut new_settings = *settings.get_ref();
+                    new_settings.label = label;
+                    settings.update(&new_settings);
+                    comm.reply_ok();
+                } else {
+                    comm.reply(Error::NoConsent);
+                }
+            }
+            // Set the recovery checkword of the store. An empty checkword
+            // clears it.
+            io::Event::Command(Instruction::SetCheckword) => {
+                let checkword =
+                    ArrayString::<16>::from_bytes(comm.get(5, 5 + 16));
+                if !checkword.is_utf8() {
+                    comm.reply(Error::InvalidData);
+                } else if confirm(
+                    settings,
+                    ui::MessageValidator::new(
+                        &[checkword.as_str()],
+                        &[&"Set", &"checkword"],
+                        &[&"Cancel"],
+                    ),
+                ) {
+                    let mut new_settings = *settings.get_ref();
+                    new_settings.checkword = checkword;
+                    settings.update(&new_settings);
+                    comm.reply_ok();
+                } else {
+                    comm.reply(Error::NoConsent);
+                }
+            }
+            // Select the content of the idle screen. This is cosmetic, so no
+            // confirmation is required.
+            io::Event::Command(Instruction::SetIdleMode) => {
+                let mode = comm.get_p1();
+                if mode != settings::IDLE_LABEL
+                    && mode != settings::IDLE_LABEL_COUNT
+                    && mode != settings::IDLE_CHECKWORD
+                {
+                    comm.reply(StatusWords::Unknown);
+                    continue;
+                }
+                let mut new_settings = *settings.get_ref();
+                new_settings.idle_mode = mode;
+                settings.update(&new_settings);
+                comm.reply_ok();
+            }
+            // Set the maximum number of stored passwords, below the capacity
+            // of the store. Zero restores the capacity.
+            io::Event::Command(Instruction::SetMaxEntries) => {
+                let max = match get_u32(&comm, 0) {
+                    Some(max) => max,
+                    None => {
+                        comm.reply(Error::WrongLength);
+                        continue;
+                    }
+                };
+                if max as usize > 128 {
+                    comm.reply(Error::InvalidData);
+                    continue;
+                }
+                let mut message = ArrayString::<16>::new();
+                message.append_u32(max);
+                message.append(b" passwords");
+                let message = match max {
+                    0 => "No limit",
+                    _ => message.as_str(),
+                };
+                if confirm(
+                    settings,
+                    ui::MessageValidator::new(
+                        &[message],
+                        &[&"Set", &"limit"],
+                        &[&"Cancel"],
+                    ),
+                ) {
+                    let mut new_settings = *settings.get_ref();
+                    new_settings.max_entries = max;
+                    settings.update(&new_settings);
+                    comm.reply_ok();
+                } else {
+                    comm.reply(Error::NoConsent);
+                }
+            }
+            // Return the maximum number of stored passwords
+            io::Event::Command(Instruction::GetMaxEntries) => {
+                let max = settings.get_ref().entry_limit(128) as u32;
+                comm.append(&max.to_be_bytes());
+                comm.reply_ok();
+            }
+            // Return the index the next created password will have, as
+            // returned by GetName with P1 == 0.
+            io::Event::Command(Instruction::GetNextIndex) => {
+                match passwords.next_index() {
+                    Some(index) if !is_full(passwords, settings) => {
+                        comm.append(&(index as u32).to_be_bytes());
+                        comm.reply_ok();
+                    }
+                    _ => comm.reply(Error::StorageFull),
+                }
+            }
+            // Compare the fingerprint of the encryption key with the recorded
+            // one. A mismatch means the seed changed, and encrypted exports
+            // made before cannot be imported anymore.
+            // If P1 == 1, record the fingerprint of the current key instead.
+            io::Event::Command(Instruction::VerifyKey) => {
+                let current = key_fingerprint(&enc_key);
+                if comm.get_p1() == 1 {
+                    if settings.get_ref().key_fingerprint != current
+                        && !confirm(
+                            settings,
+                            ui::MessageValidator::new(
+                                &[&"Seed changed"],
+                                &[&"Record", &"new key"],
+                                &[&"Cancel"],
+                            ),
+                        )
+                    {
+                        comm.reply(Error::NoConsent);
+                        continue;
+                    }
+                    let mut new_settings = *settings.get_ref();
+                    new_settings.key_fingerprint = current;
+                    settings.update(&new_settings);
+                    comm.reply_ok();
+                    continue;
+                }
+                let recorded = settings.get_ref().key_fingerprint;
+                if recorded != current {
+                    ui::popup("Seed changed");
+                }
+                comm.append(&[(recorded == current) as u8]);
+                comm.append(&recorded);
+                comm.append(&current);
+                comm.reply_ok();
+            }
+            // Count the passwords lacking the field given in P1, to find
+            // incomplete entries. If Data contains an index, return the name
+            // of the password with this index among them instead.
+            io::Event::Command(Instruction::GetMissing) => {
+                let field = comm.get_p1();
+                if field > password::MISSING_TAG {
+                    comm.reply(StatusWords::Unknown);
+                    continue;
+                }
+                let mut missing = passwords
+                    .into_iter()
+                    .filter(|x| x.is_missing(field) == Some(true));
+                if data_len(&comm) == 0 {
+                    comm.append(&(missing.count() as u32).to_be_bytes());
+                    comm.reply_ok();
+                    continue;
+                }
+                let index = match get_u32(&comm, 0) {
+                    Some(index) => index as usize,
+                    None => {
+                        comm.reply(Error::WrongLength);
+                        continue;
+                    }
+                };
+                match missing.nth(index) {
+                    Some(password) => {
+                        comm.append(password.name.bytes());
+                        comm.reply_ok();
+                    }
+                    None => comm.reply(Error::EntryNotFound),
+                }
+            }
+            // Provisioning audit. Passwords created or updated between the
+            // opening and the closing of a provisioning session can be listed
+            // afterwards.
+            // If P1 == 0, open a session. If P1 == 1, close it.
+            // If P1 == 2, count the passwords of the last session, or return
+            // the name of the one with the index given in Data.
+            io::Event::Command(Instruction::Provisioning) => {
+                let current = *settings.get_ref();
+                let mut new_settings = current;
+                let open = current.is_provisioning();
+                match comm.get_p1() {
+                    0 if open => comm.reply(Error::BadState),
+                    1 if !open => comm.reply(Error::BadState),
+                    0 => {
+                        let first = current.sequence;
+                        new_settings.provisioning = (first, u32::MAX);
+                        settings.update(&new_settings);
+                        comm.reply_ok();
+                    }
+                    1 => {
+                        new_settings.provisioning.1 = current.sequence;
+                        settings.update(&new_settings);
+                        comm.reply_ok();
+                    }
+                    2 => {
+                        let mut provisioned = passwords
+                            .into_iter()
+                            .filter(|x| current.is_provisioned(x));
+                        if data_len(&comm) == 0 {
+                            let count = provisioned.count() as u32;
+                            comm.append(&count.to_be_bytes());
+                            comm.reply_ok();
+                            continue;
+                        }
+                        let index = match get_u32(&comm, 0) {
+                            Some(index) => index as usize,
+                            None => {
+                                comm.reply(Error::WrongLength);
+                                continue;
+                            }
+                        };
+                        match provisioned.nth(index) {
+                            Some(password) => {
+                                comm.append(password.name.bytes());
+                                comm.reply_ok();
+                            }
+                            None => comm.reply(Error::EntryNotFound),
+                        }
+                    }
+                    _ => comm.reply(StatusWords::Unknown),
+                }
+            }
+            // Protect sensitive passwords, which can only be read right after
+            // being armed.
+            // If P1 == 0, arm the password for the next command.
+            // If P1 == 1, require arming to read the password.
+            // If P1 == 2, stop requiring arming to read the password.
+            io::Event::Command(Instruction::ArmEntry) => {
+                let name = ArrayString::<32>::from_bytes(comm.get(5, 5 + 32));
+                let index =
+                    match passwords.into_iter().position(|x| x.name == name) {
+                        Some(index) => index,
+                        None => {
+                            comm.reply(Error::EntryNotFound);
+                            continue;
+                        }
+                    };
+                let p1 = comm.get_p1();
+                let action: &[&str] = match p1 {
+                    0 => &[&"Arm", &"password"],
+                    1 => &[&"Require", &"arming"],
+                    2 => &[&"Stop requiring", &"arming"],
+                    _ => {
+                        comm.reply(StatusWords::Unknown);
+                        continue;
+                    }
+                };
+                if !confirm(
+                    settings,
+                    ui::MessageValidator::new(
+                        &[name.as_str()],
+                        action,
+                        &[&"Cancel"],
+                    ),
+                ) {
+                    comm.reply(Error::NoConsent);
+                    continue;
+                }
+                let mut item = *passwords.get(index).unwrap();
+                match p1 {
+                    0 => armed = Some(name),
+                    1 => item.flags |= password::FLAG_ARM_REQUIRED,
+                    _ => item.flags &= !password::FLAG_ARM_REQUIRED,
+                }
+                if p1 != 0 {
+                    passwords.replace(index, &item);
+                }
+                comm.reply_ok();
+            }
+            // Swap the passwords of two entries, which keep their name, login
+            // and tag. Neither password is disclosed.
+            io::Event::Command(Instruction::SwapPasswords) => {
+                if data_len(&comm) != 32 * 2 {
+                    comm.reply(Error::WrongLength);
+                    continue;
+                }
+                let first = ArrayString::<32>::from_bytes(comm.get(5, 5 + 32));
+                let second =
+                    ArrayString::<32>::from_bytes(comm.get(5 + 32, 5 + 64));
+                if first == second {
+                    comm.reply(Error::InvalidData);
+                    continue;
+                }
+                let find = |name| {
+                    passwords.into_iter().position(|x| x.name == name)
+                };
+                let (i, j) = match (find(first), find(second)) {
+                    (Some(i), Some(j)) => (i, j),
+                    _ => {
+                        comm.reply(Error::EntryNotFound);
+                        continue;
+                    }
+                };
+                // Both entries are written to free slots before the previous
+                // ones are released. Check them before asking consent and
+                // using sequence numbers.
+                if 128 - passwords.len() < 2 {
+                    comm.reply(Error::StorageFull);
+                    continue;
+                }
+                if !confirm(
+                    settings,
+                    ui::MessageValidator::new(
+                        &[first.as_str(), second.as_str()],
+                        &[&"Swap", &"passwords"],
+                        &[&"Cancel"],
+                    ),
+                ) {
+                    comm.reply(Error::NoConsent);
+                    continue;
+                }
+                let mut a = *passwords.get(i).unwrap();
+                let mut b = *passwords.get(j).unwrap();
+                core::mem::swap(&mut a.pass, &mut b.pass);
+                core::mem::swap(&mut a.secret_len, &mut b.secret_len);
+                // The protection of a secret follows it, so it cannot be
+                // moved to an unprotected entry to be read
+                let moved = password::FLAG_BINARY
+                    | password::FLAG_ARM_REQUIRED
+                    | password::FLAG_TOTP;
+                let (a_flags, b_flags) = (a.flags, b.flags);
+                a.flags = (a_flags & !moved) | (b_flags & moved);
+                b.flags = (b_flags & !moved) | (a_flags & moved);
+                a.seq = next_sequence(settings);
+                b.seq = next_sequence(settings);
+                match passwords.replace_pair(i, &a, j, &b) {
+                    Ok(()) => comm.reply_ok(),
+                    Err(store::StorageFullError) => {
+                        comm.reply(Error::StorageFull)
+                    }
+                }
+            }
+            // Export a single password as a URI, for other applications:
+            // otpauth for binary secrets, nanopass for passwords. The URI is
+            // in plaintext, so the user is warned.
+            io::Event::Command(Instruction::ExportUri) => {
+                let name = ArrayString::<32>::from_bytes(comm.get(5, 5 + 32));
+                let p = match passwords.into_iter().find(|&&x| x.name == name)
+                {
+                    Some(p) if p.is_totp() => {
+                        comm.reply(Error::TotpOnly);
+                        continue;
+                    }
+                    Some(p) if !is_unlocked(p, armed_name) => {
+                        comm.reply(Error::NotArmed);
+                        continue;
+                    }
+                    Some(&p) => p,
+                    None => {
+                        comm.reply(Error::EntryNotFound);
+                        continue;
+                    }
+                };
+                if !confirm(
+                    settings,
+                    ui::MessageValidator::new(
+                        &[name.as_str()],
+                        &[&"Export URI", &"in plaintext!"],
+                        &[&"Cancel"],
+                    ),
+                ) {
+                    comm.reply(Error::NoConsent);
+                    continue;
+                }
+                let mut buffer = [0u8; uri::MAX_URI_SIZE];
+                match uri::format(&p, &mut buffer) {
+                    Some(len) => {
+                        comm.append(&buffer[..len]);
+                        comm.reply_ok();
+                    }
+                    None => comm.reply(Error::InvalidData),
+                }
+            }
+            // Import a single password from a URI, as returned by ExportUri.
+            // A stored password with the same name is replaced.
+            io::Event::Command(Instruction::ImportUri) => {
+                let item = match uri::parse(comm.get(5, 5 + data_len(&comm)))
+                {
+                    Some(item) => item,
+                    None => {
+                        comm.reply(Error::InvalidData);
+                        continue;
+                    }
+                };
+                if settings.get_ref().find_decoy(&item.name).is_some() {
+                    comm.reply(Error::ReservedName);
+                    continue;
+                }
+                if !confirm(
+                    settings,
+                    ui::MessageValidator::new(
+                        &[item.name.as_str()],
+                        &[&"Import", &"from URI"],
+                        &[&"Cancel"],
+                    ),
+                ) {
+                    comm.reply(Error::NoConsent);
+                    continue;
+                }
+                match store_item(&mut passwords, settings, item, false) {
+                    Ok(()) => comm.reply_ok(),
+                    Err(e) => comm.reply(e),
+                }
+            }
+            // Import TOTP secrets from otpauth URIs, one per ImportNext command
+            io::Event::Command(Instruction::ImportTotp) => {
+                import_totp(&mut comm, &mut passwords, settings);
+            }
+            // Reorder the passwords by name, so their indexes follow the
+            // alphabetical order. The reply is the number of swaps.
+            io::Event::Command(Instruction::SortByName) => {
+                if !confirm(
+                    settings,
+                    ui::MessageValidator::new(
+                        &[],
+                        &[&"Sort passwords", &"by name"],
+                        &[&"Cancel"],
+                    ),
+                ) {
+                    comm.reply(Error::NoConsent);
+                    continue;
+                }
+                ui::SingleMessage::new("Sorting...").show();
+                match sort_by_name(&mut passwords) {
+                    Ok(swaps) => {
+                        comm.append(&swaps.to_be_bytes());
+                        comm.reply_ok();
+                    }
+                    Err(store::StorageFullError) => {
+                        comm.reply(Error::StorageFull)
+                    }
+                }
+            }
+            // Set the tag of a password. Tags are not secret, so no
+            // confirmation is required.
+            io::Event::Command(Instruction::SetTag) => {
+                let tag = match get_byte(&comm, 32) {
+                    Some(tag) if data_len(&comm) == 33 => tag,
+                    _ => {
+                        comm.reply(Error::WrongLength);
+                        continue;
+                    }
+                };
+                let name = ArrayString::<32>::from_bytes(comm.get(5, 5 + 32));
+                match passwords.into_iter().position(|x| x.name == name) {
+                    Some(index) => {
+                        let mut item = *passwords.get(index).unwrap();
+                        item.tag = tag;
+                        passwords.replace(index, &item);
+                        comm.reply_ok();
+                    }
+                    None => comm.reply(Error::EntryNotFound),
+                }
+            }
+            // Set the format hint of a password, which the client uses to
+            // present it. The hint follows the name and may be empty.
+            io::Event::Command(Instruction::SetHint) => {
+                let len = data_len(&comm);
+                if len < 32 {
+                    comm.reply(Error::WrongLength);
+                    continue;
+                }
+                let hint = comm.get(5 + 32, 5 + len);
+                if password::check_field(hint, password::HINT_SIZE)
+                    != password::FIELD_OK
+                {
+                    comm.reply(Error::InvalidData);
+                    continue;
+                }
+                let hint = ArrayString::from_bytes(hint);
+                let name = ArrayString::<32>::from_bytes(comm.get(5, 5 + 32));
+                match passwords.into_iter().position(|x| x.name == name) {
+                    Some(index) => {
+                        let mut item = *passwords.get(index).unwrap();
+                        item.hint = hint;
+                        passwords.replace(index, &item);
+                        comm.reply_ok();
+                    }
+                    None => comm.reply(Error::EntryNotFound),
+                }
+            }
+            // Return the format hint of a password, without padding
+            io::Event::Command(Instruction::GetHint) => {
+                let name = ArrayString::<32>::from_bytes(comm.get(5, 5 + 32));
+                match passwords.into_iter().find(|&&x| x.name == name) {
+                    Some(item) => {
+                        comm.append(&item.hint.bytes()[..item.hint.len()]);
+                        comm.reply_ok()
+                    }
+                    None => comm.reply(Error::EntryNotFound),
+                }
+            }
+            // Set the default parameters of password generation
+            // P1 can be 0 to set the parameters given in Data, 1 to restore
+            // factory defaults.
+            io::Event::Command(Instruction::SetGenDefaults) => {
+                // Minimums are optional
+                let len = data_len(&comm);
+                let params = match comm.get_p1() {
+                    0 if len == generator::BASE_PARAMS_SIZE
+                        || len == generator::PARAMS_SIZE =>
+                    {
+                        GenParams::from_bytes(comm.get(5, 5 + len))
+                    }
+                    0 => {
+                        comm.reply(Error::WrongLength);
+                        continue;
+                    }
+                    1 => GenParams::new(),
+                    _ => {
+                        comm.reply(StatusWords::Unknown);
+                        continue;
+                    }
+                };
+                if !params.is_valid() {
+                    comm.reply(Error::InvalidData);
+                } else if confirm(
+                    settings,
+                    ui::MessageValidator::new(
+                        &[],
+                        &[&"Set generation", &"defaults"],
+                        &[&"Cancel"],
+                    ),
+                ) {
+                    let mut new_settings = *settings.get_ref();
+                    new_settings.gen = params;
+                    settings.update(&new_settings);
+                    comm.reply_ok();
+                } else {
+                    comm.reply(Error::NoConsent);
+                }
+            }
+            // Return 1 if the last import has not completed, 0 otherwise
+            io::Event::Command(Instruction::GetImportPending) => {
+                comm.append(&[settings.get_ref().import_pending as u8]);
+                comm.reply_ok();
+            }
+            // Get the default parameters of password generation, in the
+            // format of SetGenDefaults with minimums
+            io::Event::Command(Instruction::GetGenDefaults) => {
+                comm.append(&settings.get_ref().gen.to_bytes());
+                comm.reply_ok();
+            }
+            // Derive the passphrase key used by export and import with P1=2.
+            // P1 can be 0 to generate a new salt, which is returned, 1 to use
+            // the salt given in Data (for import), 2 to forget the key.
+            io::Event::Command(Instruction::SetPassphrase) => {
+                let mut salt = [0u8; 16];
+                let offset = match comm.get_p1() {
+                    0 => {
+                        random::rand_bytes(&mut salt);
+                        5 + 4
+                    }
+                    1 if data_len(&comm) >= 4 + 16 => {
+                        salt.copy_from_slice(comm.get(5 + 4, 5 + 4 + 16));
+                        5 + 4 + 16
+                    }
+                    1 => {
+                        comm.reply(Error::WrongLength);
+                        continue;
+                    }
+                    2 => {
+                        passphrase_key = None;
+                        comm.reply_ok();
+                        continue;
+                    }
+                    _ => {
+                        comm.reply(StatusWords::Unknown);
+                        continue;
+                    }
+                };
+                let iterations = match get_u32(&comm, 0) {
+                    Some(iterations) => iterations,
+                    None => {
+                        comm.reply(Error::WrongLength);
+                        continue;
+                    }
+                };
+                let end = 5 + data_len(&comm);
+                if end <= offset
+                    || iterations == 0
+                    || iterations > MAX_KDF_ITERATIONS
+                {
+                    comm.reply(Error::InvalidData);
+                    continue;
+                }
+                ui::SingleMessage::new("Deriving key...").show();
+                let mut key = [0u8; 32];
+                sha1::pbkdf2_hmac_sha1(
+                    comm.get(offset, end),
+                    &salt,
+                    iterations,
+                    &mut key,
+                );
+                passphrase_key = Some(key);
+                comm.append(&salt);
+                comm.reply_ok();
+            }
+            // Regenerate the passwords of all the entries with a given tag
+            io::Event::Command(Instruction::RotateTag) => {
+                let tag = match get_byte(&comm, 0) {
+                    Some(tag) if data_len(&comm) == 1 => tag,
+                    _ => {
+                        comm.reply(Error::WrongLength);
+                        continue;
+                    }
+                };
+                match rotate_tag(passwords, settings, tag) {
+                    Ok(count) => {
+                        comm.append(&count.to_be_bytes());
+                        comm.reply_ok();
+                    }
+                    Err(e) => comm.reply(e),
+                }
+            }
+            // Set the decoy password, returned instead of stored passwords
+            // when its name is requested. An empty name disables the decoy.
+            io::Event::Command(Instruction::SetDecoy) => {
+                let decoy =
+                    PasswordItem::from_bytes(comm.get(5, 5 + RECORD_SIZE));
+                let message = if decoy.name.len() == 0 {
+                    "Disable"
+                } else if decoy.name.is_utf8() {
+                    decoy.name.as_str()
+                } else {
+                    comm.reply(Error::InvalidData);
+                    continue;
+                };
+                if confirm(
+                    settings,
+                    ui::MessageValidator::new(
+                        &[message],
+                        &[&"Set", &"decoy"],
+                        &[&"Cancel"],
+                    ),
+                ) {
+                    let mut new_settings = *settings.get_ref();
+                    new_settings.decoy = decoy;
+                    settings.update(&new_settings);
+                    comm.reply_ok();
+                } else {
+                    comm.reply(Error::NoConsent);
+                }
+            }
+            // Get the label of the device
+            io::Event::Command(Instruction::GetLabel) => {
+                comm.append(settings.get_ref().label.bytes());
+                comm.reply_ok();
+            }
+            // Get the recovery checkword of the store
+            io::Event::Command(Instruction::GetCheckword) => {
+                comm.append(settings.get_ref().checkword.bytes());
+                comm.reply_ok();
+            }
+            // Import a few passwords in plaintext with a single APDU
+            io::Event::Command(Instruction::ImportBlob) => {
+                import_blob(&mut comm, &mut passwords, settings)
+            }
+            // Return a digest of all the stored passwords, to compare stores
+            // without transferring them. The digests are keyed with the seed
+            // so they cannot be used to guess a password.
+            io::Event::Command(Instruction::GetStoreDigest) => {
+                comm.append(&store_digest(passwords, &enc_key));
+                comm.reply_ok();
+            }
+            // Compare the store digest with the one recorded by the client,
+            // for instance when making a backup, to tell if the store has
+            // changed since.
+            io::Event::Command(Instruction::VerifyStoreDigest) => {
+                if data_len(&comm) != 20 {
+                    comm.reply(Error::WrongLength);
+                    continue;
+                }
+                let matches =
+                    store_digest(passwords, &enc_key) == comm.get(5, 5 + 20);
+                comm.append(&[matches as u8]);
+                comm.reply_ok();
+            }
+            // Return the digest of a single password, to find which ones
+            // differ when store digests do not match.
+            io::Event::Command(Instruction::GetEntryDigest) => {
+                let name = ArrayString::<32>::from_bytes(comm.get(5, 5 + 32));
+                match passwords.into_iter().find(|&&x| x.name == name) {
+                    Some(p) => {
+                        comm.append(&entry_digest(p, &enc_key));
+                        comm.reply_ok();
+                    }
+                    None => comm.reply(Error::EntryNotFound),
+                }
+            }
+            // Count passwords which have not been updated during the last
+            // modifications, given as a number of sequence steps in Data.
+            io::Event::Command(Instruction::GetStaleCount) => {
+                let age = match get_u32(&comm, 0) {
+                    Some(age) => age,
+                    None => {
+                        comm.reply(Error::WrongLength);
+                        continue;
+                    }
+                };
+                let current = settings.get_ref().sequence;
+                let count = passwords
+                    .into_iter()
+                    .filter(|x| current.wrapping_sub(x.seq) > age)
+                    .count() as u32;
+                comm.append(&count.to_be_bytes());
+                comm.reply_ok();
+            }
+            // Return the sequence values of the oldest and newest passwords,
+            // and the current value of the sequence counter.
+            io::Event::Command(Instruction::GetSequenceRange) => {
+                let oldest = passwords.into_iter().map(|x| x.seq).min();
+                let newest = passwords.into_iter().map(|x| x.seq).max();
+                match (oldest, newest) {
+                    (Some(oldest), Some(newest)) => {
+                        comm.append(&oldest.to_be_bytes());
+                        comm.append(&newest.to_be_bytes());
+                        comm.append(&settings.get_ref().sequence.to_be_bytes());
+                        comm.reply_ok();
+                    }
+                    _ => comm.reply(Error::EntryNotFound),
+                }
+            }
+        }
+    }
+}
+
+/// Returns the number of entries whose password is also used by at least one
+/// other entry. Empty passwords are ignored.
+fn reused_count(passwords: &store::Collection<PasswordItem, 128>) -> usize {
+    passwords
+        .into_iter()
+        .filter(|&a| {
+            a.pass.len() > 0
+                && passwords.into_iter().filter(|&b| b.pass == a.pass).count()
+                    > 1
+        })
+        .count()
+}
+
+/// Returns the digest of a password: HMAC-SHA1 of its exported record, with a
+/// key derived from the encryption key. Devices with the same seed return the
+/// same digest for the same password. The sequence is left out, as it depends
+/// on the history of the device.
+fn entry_digest(item: &PasswordItem, enc_key: &[u8; 32]) -> [u8; 20] {
+    let key = sha1::hmac_sha1(enc_key, b"entry digest");
+    let mut content = *item;
+    content.seq = 0;
+    sha1::hmac_sha1(&key, &content.to_bytes())
+}
+
+/// Returns the fingerprint of the encryption key: the first bytes of an HMAC
+/// keyed with it, which reveals nothing about the key.
+fn key_fingerprint(enc_key: &[u8; 32]) -> [u8; 8] {
+    let mac = sha1::hmac_sha1(enc_key, b"key fingerprint");
+    let mut fingerprint = [0; 8];
+    fingerprint.copy_from_slice(&mac[..8]);
+    fingerprint
+}
+
+/// Returns the fingerprint of a secret displayed when it is read: the
+/// hexadecimal representation of the first 4 bytes of its HMAC-SHA1, with a
+/// key derived from the encryption key. Without the key, the fingerprint
+/// cannot be used to check guesses of the secret.
+fn secret_fingerprint(
+    item: &PasswordItem,
+    enc_key: &[u8; 32],
+) -> ArrayString<64> {
+    let key = sha1::hmac_sha1(enc_key, b"secret fingerprint");
+    encoding::to_hex(&sha1::hmac_sha1(&key, item.secret())[..4])
+}
+
+/// Returns true if the secret of a password can be used by the current
+/// command: the password is not protected, or it has been armed for this
+/// command (see ArmEntry).
+fn is_unlocked(
+    item: &PasswordItem,
+    armed_name: Option<ArrayString<32>>,
+) -> bool {
+    !item.is_arm_required() || armed_name == Some(item.name)
+}
+
+/// Returns the 6-digit TOTP code (RFC 6238) of a secret at the given Unix
+/// time, with HMAC-SHA1 and a period of 30 seconds, as used by most
+/// authenticator applications.
+fn totp(secret: &[u8], time: u64) -> ArrayString<8> {
+    let counter = time / TOTP_PERIOD;
+    let mac = sha1::hmac_sha1(secret, &counter.to_be_bytes());
+    // Dynamic truncation (RFC 4226)
+    let offset = (mac[mac.len() - 1] & 0x0f) as usize;
+    let mut value = [0u8; 4];
+    value.copy_from_slice(&mac[offset..offset + 4]);
+    let code = (u32::from_be_bytes(value) & 0x7fff_ffff) % 1_000_000;
+    let mut result = ArrayString::new();
+    let mut divisor = 100_000;
+    while divisor > 0 {
+        result.append(&[b'0' + (code / divisor % 10) as u8]);
+        divisor /= 10;
+    }
+    result
+}
+
+/// Returns the digest of all the stored passwords: SHA-1 of the concatenated
+/// digests of the passwords, ordered by name.
+fn store_digest(
+    passwords: &store::Collection<PasswordItem, 128>,
+    enc_key: &[u8; 32],
+) -> [u8; 20] {
+    let mut hash = sha1::Sha1::new();
+    // Names are unique: select the next name in order until there is none
+    // left, as there is no room to sort the passwords.
+    let mut last: Option<&PasswordItem> = None;
+    loop {
+        let next = passwords
+            .into_iter()
+            .filter(|x| match last {
+                Some(l) => x.name.bytes() > l.name.bytes(),
+                None => true,
+            })
+            .min_by(|a, b| a.name.bytes().cmp(b.name.bytes()));
+        match next {
+            Some(item) => hash.update(&entry_digest(item, enc_key)),
+            None => break,
+        }
+        last = next;
+    }
+    hash.finalize()
+}
+
+/// Returns the password with the given rank when passwords are sorted by
+/// name, or None if the rank is out of bounds. Names are unique, so the rank
+/// of a password is the number of names before its own.
+fn sorted_rank(
+    passwords: &store::Collection<PasswordItem, 128>,
+    rank: usize,
+) -> Option<&PasswordItem> {
+    passwords.into_iter().find(|&x| {
+        passwords
+            .into_iter()
+            .filter(|y| y.name.bytes() < x.name.bytes())
+            .count()
+            == rank
+    })
+}
+
+/// Returns the password with the given rank when passwords are ordered from
+/// the most recently modified, or None if the rank is out of bounds. Added
+/// passwords take the first free slot, so the order of the slots does not
+/// follow the modifications: the sequence of the passwords does. Passwords
+/// with the same sequence, such as records of old backups, are ordered by
+/// decreasing index.
+fn recent_rank(
+    passwords: &store::Collection<PasswordItem, 128>,
+    rank: usize,
+) -> Option<&PasswordItem> {
+    let key = |(i, p): (usize, &PasswordItem)| (p.seq, i);
+    passwords.into_iter().enumerate().find_map(|(i, x)| {
+        let newer = passwords
+            .into_iter()
+            .enumerate()
+            .filter(|&y| key(y) > key((i, x)))
+            .count();
+        if newer == rank {
+            Some(x)
+        } else {
+            None
+        }
+    })
+}
+
+/// Sorts the passwords by name with a selection sort, which makes the fewest
+/// swaps, as each of them writes several Flash pages. Returns the number of
+/// swaps.
+fn sort_by_name(
+    passwords: &mut store::Collection<PasswordItem, 128>,
+) -> Result<u32, store::StorageFullError> {
+    let mut swaps = 0;
+    for i in 0..passwords.len() {
+        let mut min = i;
+        for (j, p) in passwords.into_iter().enumerate().skip(i + 1) {
+            if p.name.bytes() < passwords.get(min).unwrap().name.bytes() {
+                min = j;
+            }
+        }
+        if min != i {
+            passwords.swap(i, min)?;
+            swaps += 1;
+        }
+    }
+    Ok(swaps)
+}
+
+extern "C" {
+    /// Lowest and highest addresses of the stack, defined by the linker
+    /// script of the SDK. The stack grows down from `_estack` to `_stack`.
+    static _stack: u8;
+    static _estack: u8;
+}
+
+/// Returns an estimate of the number of free bytes of the stack, from the
+/// address of a local variable, followed by the size of the stack.
+#[inline(never)]
+fn stack_headroom() -> (usize, usize) {
+    let marker = 0u8;
+    let current = &marker as *const u8 as usize;
+    let (bottom, top) = unsafe {
+        (&_stack as *const u8 as usize, &_estack as *const u8 as usize)
+    };
+    (current.saturating_sub(bottom), top.saturating_sub(bottom))
+}
+
+/// Splits a string in two halves, at the character boundary closest to its
+/// middle.
+fn split_half(s: &str) -> (&str, &str) {
+    let mut middle = s.len() / 2;
+    while !s.is_char_boundary(middle) {
+        middle += 1;
+    }
+    s.split_at(middle)
+}
+
+/// Returns the length of the Data field of the last received APDU.
+fn data_len(comm: &io::Comm) -> usize {
+    comm.get(4, 5)[0] as usize
+}
+
+/// Returns the byte at the given offset of the Data field of the last received
+/// APDU, or None if the Data field is too short.
+fn get_byte(comm: &io::Comm, offset: usize) -> Option<u8> {
+    if offset < data_len(comm) {
+        Some(comm.get(5 + offset, 5 + offset + 1)[0])
+    } else {
+        None
+    }
+}
+
+/// Returns the Unix time which follows the name in the Data field of GetTotp
+/// and GetTotpRemaining, checking the length of the Data field and that the
+/// time is plausible.
+fn get_totp_time(comm: &io::Comm) -> Result<u64, Error> {
+    if data_len(comm) != 32 + 8 {
+        return Err(Error::WrongLength);
+    }
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(comm.get(5 + 32, 5 + 40));
+    let time = u64::from_be_bytes(bytes);
+    if TOTP_TIME_RANGE.contains(&time) {
+        Ok(time)
+    } else {
+        Err(Error::InvalidData)
+    }
+}
+
+/// Returns the big-endian 32-bit integer at the given offset of the Data
+/// field of the last received APDU, or None if the Data field is too short.
+fn get_u32(comm: &io::Comm, offset: usize) -> Option<u32> {
+    if offset + 4 <= data_len(comm) {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(comm.get(5 + offset, 5 + offset + 4));
+        Some(u32::from_be_bytes(bytes))
+    } else {
+        None
+    }
+}
+
+/// Formats of the payloads returned by GetByName for QR codes: the password
+/// as text, or a binary secret encoded in base64.
+const QR_TEXT: u8 = 0x01;
+const QR_BASE64: u8 = 0x02;
+
+/// Status byte of the responses to GetByName in prefix mode: the password
+/// follows if the prefix matches a single name, otherwise the candidates.
+const PREFIX_READ: u8 = 0x00;
+const PREFIX_CANDIDATES: u8 = 0x01;
+
+/// Status byte of the responses to ImportNext during Import: the record has
+/// been stored, skipped as its name is the decoy name, stored under a new
+/// name, found invalid while verifying, or skipped as the stored password is
+/// newer.
+const IMPORT_STORED: u8 = 0x00;
+const IMPORT_RESERVED: u8 = 0x01;
+const IMPORT_RENAMED: u8 = 0x02;
+const IMPORT_INVALID: u8 = 0x03;
+const IMPORT_OLDER: u8 = 0x04;
+
+/// Status byte of the responses to ImportNext during ImportTotp.
+const TOTP_STORED: u8 = 0x00;
+const TOTP_RESERVED: u8 = 0x01;
+const TOTP_INVALID: u8 = 0x02;
+
+/// Period of TOTP codes in seconds.
+const TOTP_PERIOD: u64 = 30;
+
+/// Unix times accepted by GetTotp and GetTotpRemaining, from 2020 to 2100, to
+/// reject a clock which is obviously wrong.
+const TOTP_TIME_RANGE: core::ops::Range<u64> = 1_577_836_800..4_102_444_800;
+
+/// Maximum number of characters returned by a partial read of GetByName.
+const MAX_PARTIAL_READ: usize = 4;
+
+/// Maximum number of names returned by GetByName in prefix mode.
+const MAX_CANDIDATES: usize = 7;
+
+/// Maximum number of indexes returned by MatchNames.
+const MAX_MATCHES: usize = 64;
+
+/// Model identifier returned by GetCapabilities. The SDK only supports the
+/// Nano S, so its properties are constants.
+const MODEL_ID: u8 = 0x01;
+
+/// Feature bits returned by GetCapabilities. The Nano S has no Bluetooth.
+const CAPABILITIES: u8 = 0;
+
+/// Version of the format of the response to GetStatus. Fields are only added
+/// at the end, with a new version.
+const STATUS_VERSION: u8 = 1;
+
+/// Screen width and height in pixels.
+const SCREEN_SIZE: [u8; 2] = [128, 32];
+
+/// Maximum number of names returned by GetWeakest.
+const MAX_WEAKEST: usize = 7;
+
+/// Maximum number of names returned by GetLackingClasses.
+const MAX_LACKING: usize = 7;
+
+/// Maximum number of names returned by FindPassword.
+const MAX_FOUND: usize = 7;
+
+/// Maximum number of (tag, count) pairs returned by GetTagCounts.
+const MAX_TAG_COUNTS: usize = 64;
+
+/// Maximum number of status bytes returned by GetFlags.
+const MAX_FLAGS: usize = 128;
+
+/// Generates a random password without the characters of `exclude`, or a
+/// pronounceable password with the length of the parameters.
+fn generate_password(
+    params: &GenParams,
+    exclude: &[u8],
+    pronounceable: bool,
+) -> ArrayString<32> {
+    if pronounceable {
+        generator::generate_pronounceable(params.length)
+    } else {
+        generator::generate_excluding(params, exclude)
+    }
+}
+
+/// Maximum number of passwords generated during a preview before giving up.
+const MAX_PREVIEWS: usize = 8;
+
+/// Generates random passwords and displays them on screen, until the user
+/// accepts one. Returns None if all the candidates have been rejected.
+fn preview_random_password(
+    settings: &mut nvm::AtomicStorage<Settings>,
+    exclude: &[u8],
+    pronounceable: bool,
+) -> Option<ArrayString<32>> {
+    let params = settings.get_ref().gen;
+    for _ in 0..MAX_PREVIEWS {
+        let pass = generate_password(&params, exclude, pronounceable);
+        if confirm(
+            settings,
+            ui::MessageValidator::new(
+                &[pass.as_str()],
+                &[&"Use", &"password"],
+                &[&"Generate", &"another"],
+            ),
+        ) {
+            return Some(pass);
+        }
+    }
+    None
+}
+
+/// Asks the user for confirmation, and counts the approvals.
+fn confirm(
+    settings: &mut nvm::AtomicStorage<Settings>,
+    validator: ui::MessageValidator,
+) -> bool {
+    let approved = validator.ask();
+    if approved {
+        let mut new_settings = *settings.get_ref();
+        new_settings.confirmations = new_settings.confirmations.wrapping_add(1);
+        settings.update(&new_settings);
+    }
+    approved
+}
+
+/// Returns true if no password can be added, because the store is full or the
+/// limit set by the user is reached.
+fn is_full(
+    passwords: &store::Collection<PasswordItem, 128>,
+    settings: &nvm::AtomicStorage<Settings>,
+) -> bool {
+    passwords.len() >= settings.get_ref().entry_limit(128)
+}
+
+/// Increments the modification sequence counter and returns its new value.
+/// The counter stays at its maximum rather than wrapping, which would make the
+/// next modifications look older than the previous ones.
+fn next_sequence(settings: &mut nvm::AtomicStorage<Settings>) -> u32 {
+    let mut new_settings = *settings.get_ref();
+    new_settings.sequence = new_settings.sequence.saturating_add(1);
+    settings.update(&new_settings);
+    new_settings.sequence
+}
+
+/// Maximum sequence of a record kept by a newest-wins import. It leaves room
+/// for 2^31 modifications, far more than the Flash memory can endure.
+const MAX_KEPT_SEQUENCE: u32 = u32::MAX / 2;
+
+/// Maximum number of PBKDF2 iterations to derive the passphrase key, which
+/// bounds the derivation time.
+const MAX_KDF_ITERATIONS: u32 = 10000;
+
+/// Maximum number of passwords created without confirmation in an add
+/// session.
+const MAX_SESSION_ADDS: u32 = 32;
+
+/// Adds or update a password in the store.
+/// Queries confirmation from the user in the UX, unless `trusted` is set and
+/// the password is new. Updates of existing passwords are always confirmed.
+///
+/// # Arguments
+///
+/// * `name` - Slice to the new name of the password. Must be 32 bytes long.
+/// * `login` - Slice to the new login of the password. Must be 32 bytes long.
+/// * `pass` - New password. If None, a password is generated automatically.
+/// * `secret_len` - Length of the password if it is a binary secret.
+/// * `trusted` - True during an add session.
+///
+/// Returns true if an existing password has been updated, false if a new one
+/// has been created.
+fn set_password(
+    passwords: &mut store::Collection<PasswordItem, 128>,
+    settings: &mut nvm::AtomicStorage<Settings>,
+    name: &ArrayString<32>,
+    login: &ArrayString<32>,
+    pass: &Option<ArrayString<32>>,
+    secret_len: Option<u8>,
+    trusted: bool,
+) -> Result<bool, Error> {
+    // Create the item to be added.
+    let mut new_item = PasswordItem::new();
+    new_item.name = *name;
+    new_item.login = *login;
+    new_item.pass = match pass {
+        Some(a) => *a,
+        None => generator::generate(&settings.get_ref().gen),
+    };
+    if let Some(len) = secret_len {
+        new_item.flags |= password::FLAG_BINARY;
+        new_item.secret_len = len;
+    }
+    // The password would be hidden by the decoy
+    if settings.get_ref().find_decoy(name).is_some() {
+        return Err(Error::ReservedName);
+    }
+
+    return match passwords.into_iter().position(|x| x.name == *name) {
+        Some(index) => {
+            // A password with this name already exists.
+            if !confirm(
+                settings,
+                ui::MessageValidator::new(
+                    &[name.as_str()],
+                    &[&"Update", &"password"],
+                    &[&"Cancel"],
+                ),
+            ) {
+                return Err(Error::NoConsent);
+            }
+            let previous = passwords.get(index).unwrap();
+            new_item.tag = previous.tag;
+            new_item.hint = previous.hint;
+            new_item.flags |= previous.flags & password::FLAG_ARM_REQUIRED;
+            new_item.seq = next_sequence(settings);
+            passwords.remove(index);
+            match passwords.add(&new_item) {
+                Ok(()) => Ok(true),
+                // We just removed a password, this should not happen
+                Err(store::StorageFullError) => panic!(),
+            }
+        }
+        None => {
+            if is_full(passwords, settings) {
+                return Err(Error::StorageFull);
+            }
+            // Ask user confirmation, unless in an add session
+            if !trusted
+                && !confirm(
+                    settings,
+                    ui::MessageValidator::new(
+                        &[name.as_str()],
+                        &[&"Create", &"password"],
+                        &[&"Cancel"],
+                    ),
+                )
+            {
+                return Err(Error::NoConsent);
+            }
+            new_item.seq = next_sequence(settings);
+            match passwords.add(&new_item) {
+                Ok(()) => Ok(false),
+                Err(store::StorageFullError) => Err(Error::StorageFull),
+            }
+        }
+    };
+}
+
+/// Regenerates the passwords of all the entries with the given tag but the
+/// TOTP secrets, using the default generation parameters. Confirmation is asked
+/// once for all the entries, unless there is none. Each entry is replaced in
+/// place, so an interruption leaves every entry with its previous or its new
+/// password. Returns the number of regenerated passwords.
+fn rotate_tag(
+    passwords: &mut store::Collection<PasswordItem, 128>,
+    settings: &mut nvm::AtomicStorage<Settings>,
+    tag: u8,
+) -> Result<u32, Error> {
+    // TOTP secrets cannot be provisioned again from the device, so they are
+    // never rotated
+    let rotated = |x: &PasswordItem| x.tag == tag && !x.is_totp();
+    let count = passwords.into_iter().filter(|x| rotated(x)).count() as u32;
+    if count == 0 {
+        return Ok(0);
+    }
+    let mut message = ArrayString::<16>::new();
+    message.append_u32(count);
+    message.append(b" passwords");
+    if !confirm(
+        settings,
+        ui::MessageValidator::new(
+            &[message.as_str()],
+            &[&"Rotate", &"passwords"],
+            &[&"Cancel"],
+        ),
+    ) {
+        return Err(Error::NoConsent);
+    }
+
+    // Rotated entries get a sequence number greater than the current one,
+    // which tells them apart from the entries still to be rotated.
+    let start_seq = settings.get_ref().sequence;
+    while let Some(index) = passwords
+        .into_iter()
+        .position(|x| rotated(x) && x.seq <= start_seq)
+    {
+        let mut item = *passwords.get(index).unwrap();
+        item.pass = generator::generate(&settings.get_ref().gen);
+        // A binary secret is replaced by a text password, which is no longer
+        // due for rotation
+        item.flags &= !(password::FLAG_BINARY | password::FLAG_ROTATE);
+        item.seq = next_sequence(settings);
+        passwords.replace(index, &item);
+    }
+    Ok(count)
+}
+
+/// Options of an export, selected by the client.
+#[derive(Clone, Copy)]
+struct ExportOptions {
+    /// Index of the first record to be exported. Records before this index
+    /// are skipped, so an interrupted export can be resumed.
+    start: usize,
+    /// If set, only the passwords with this tag are exported, and `start` is
+    /// an index among these passwords.
+    tag: Option<u8>,
+    /// If true, an additional ExportNext command is expected after the last
+    /// password, and answered with the number of exported passwords.
+    trailer: bool,
+    /// If set, HMAC-SHA1 key of a seal authenticating the whole transfer: a
+    /// random salt is sent with the first response, and the seal is sent in
+    /// the trailer.
+    seal: Option<[u8; sha1::DIGEST_SIZE]>,
+    /// If true, plaintext records are sent in their compact form, without
+    /// padding.
+    compact: bool,
+    /// If true, encrypted records use ChaCha20-Poly1305 instead of
+    /// AES-256-CBC and CBC-MAC, so they can be decrypted by standard tools.
+    chacha: bool,
+    /// If true, TOTP secrets are left out, as the client could read them:
+    /// records are in plaintext, or encrypted with the passphrase key.
+    skip_totp: bool,
+}
+
+/// Export procedure.
+///
+/// # Arguments
+///
+/// * `enc_key` - Encryption key. If None, passwords are exported in plaintext.
+fn export(
+    comm: &mut io::Comm,
+    passwords: &store::Collection<PasswordItem, 128>,
+    settings: &mut nvm::AtomicStorage<Settings>,
+    enc_key: Option<&[u8; 32]>,
+    options: ExportOptions,
+) {
+    let ExportOptions {
+        start,
+        tag,
+        trailer,
+        seal,
+        compact,
+        chacha,
+        skip_totp,
+    } = options;
+    let selected = |p: &&PasswordItem| {
+        tag.map_or(true, |t| p.tag == t) && !(skip_totp && p.is_totp())
+    };
+    let count = passwords.into_iter().filter(selected).count();
+
+    // Ask user confirmation. When exporting a single tag, show the number of
+    // selected passwords.
+    let mut message = ArrayString::<16>::new();
+    message.append_u32(count as u32);
+    message.append(b" passwords");
+    let message = [message.as_str()];
+    let message: &[&str] = if tag.is_some() { &message } else { &[] };
+    if !confirm(
+        settings,
+        ui::MessageValidator::new(
+            message,
+            &[&"Export", &"passwords"],
+            &[&"Cancel"],
+        ),
+    ) {
+        comm.reply(Error::NoConsent);
+        return;
+    }
+
+    // If export is in plaintext, add a warning
+    let encrypted = enc_key.is_some();
+    if !encrypted
+        && !confirm(
+            settings,
+            ui::MessageValidator::new(
+                &[&"Export is plaintext!"],
+                &[&"Confirm"],
+                &[&"Cancel"],
+            ),
+        )
     {
         comm.reply(Error::NoConsent);
         return;
     }
 
-    // User accepted. Reply with the number of passwords
-    let count = passwords.len();
-    comm.append(&count.to_be_bytes());
+    // User accepted. Reply with the total number of passwords, even when
+    // resuming: the client knows how many records it already has. The label
+    // of the device follows.
+    comm.append(&(count as u32).to_be_bytes());
+    comm.append(settings.get_ref().label.bytes());
+    let mut hmac = seal.map(|key| sha1::HmacSha1::new(&key));
+    if let Some(hmac) = &mut hmac {
+        let mut salt = [0u8; 16];
+        random::rand_bytes(&mut salt);
+        hmac.update(&salt);
+        comm.append(&salt);
+    }
     comm.reply_ok();
 
     // We are now waiting for N APDUs to retrieve all passwords.
     // If encryption is enabled, the IV is returned during the first iteration.
     ui::SingleMessage::new("Exporting...").show();
 
-    let mut iter = passwords.into_iter();
+    let mut iter = passwords.into_iter().filter(selected).skip(start);
     let mut next_item = iter.next();
+    let mut exported: u32 = 0;
     while next_item.is_some() {
         match comm.next_command() {
             // Fetch next password
             Instruction::ExportNext => {
                 let password = next_item.unwrap();
                 // If encryption is enabled, encrypt the buffer inplace.
-                if encrypted {
+                if encrypted && chacha {
+                    let mut nonce = [0u8; chacha::NONCE_SIZE];
+                    random::rand_bytes(&mut nonce);
+                    let mut buffer = password.to_bytes();
+                    // Encrypt buffer in ChaCha20-Poly1305, without
+                    // associated data
+                    let key = enc_key.unwrap();
+                    let tag = chacha::seal(key, &nonce, &[], &mut buffer);
+                    for part in [&nonce as &[u8], &buffer, &tag].iter() {
+                        comm.append(part);
+                        if let Some(hmac) = &mut hmac {
+                            hmac.update(part);
+                        }
+                    }
+                } else if encrypted {
                     let mut nonce = [0u8; 16];
                     random::rand_bytes(&mut nonce);
-                    comm.append(&nonce);
-                    let mut buffer: Vec<u8, U96> = Vec::new();
-                    buffer.extend_from_slice(password.name.bytes()).unwrap();
-                    buffer.extend_from_slice(password.login.bytes()).unwrap();
-                    buffer.extend_from_slice(password.pass.bytes()).unwrap();
+                    let mut buffer = password.to_bytes();
                     // Encrypt buffer in AES-256-CBC with random IV
-                    let mut aes_ctx = MaybeUninit::<tinyaes::AES_ctx>::uninit();
-                    unsafe {
-                        tinyaes::AES_init_ctx_iv(
-                            aes_ctx.as_mut_ptr(),
-                            enc_key.unwrap().as_ptr(),
-                            nonce.as_ptr(),
-                        );
-                        tinyaes::AES_CBC_encrypt_buffer(
-                            aes_ctx.as_mut_ptr(),
-                            buffer.as_mut_ptr(),
-                            buffer.len() as u32,
-                        );
-                    }
-                    comm.append(&buffer as &[u8]);
-                    // Now calculate AES-256-CBC-MAC
-                    unsafe {
-                        tinyaes::AES_init_ctx_iv(
-                            aes_ctx.as_mut_ptr(),
-                            enc_key.unwrap().as_ptr(),
-                            nonce.as_ptr(),
-                        );
-                        tinyaes::AES_CBC_encrypt_buffer(
-                            aes_ctx.as_mut_ptr(),
-                            buffer.as_mut_ptr(),
-                            buffer.len() as u32,
-                        );
-                    }
-                    let mac = &buffer[buffer.len() - 16..];
-                    comm.append(mac);
+                    let key = enc_key.unwrap();
+                    crypto::cbc_encrypt(key, &nonce, &mut buffer);
+                    // Now calculate AES-256-CBC-MAC. This fails if the
+                    // buffer is not made of complete blocks.
+                    let mac = match crypto::cbc_mac(key, &nonce, &buffer) {
+                        Some(mac) => mac,
+                        None => {
+                            comm.reply(Error::Internal);
+                            return;
+                        }
+                    };
+                    for part in [&nonce as &[u8], &buffer, &mac].iter() {
+                        comm.append(part);
+                        if let Some(hmac) = &mut hmac {
+                            hmac.update(part);
+                        }
+                    }
                 } else {
-                    comm.append(password.name.bytes());
-                    comm.append(password.login.bytes());
-                    comm.append(password.pass.bytes());
+                    let full = password.to_bytes();
+                    let mut compact_record = [0u8; password::COMPACT_MAX_SIZE];
+                    let record: &[u8] = if compact {
+                        let size = password.to_compact(&mut compact_record);
+                        &compact_record[..size]
+                    } else {
+                        &full
+                    };
+                    comm.append(record);
+                    if let Some(hmac) = &mut hmac {
+                        hmac.update(record);
+                    }
                 }
                 comm.reply_ok();
                 // Advance iterator.
                 next_item = iter.next();
+                exported += 1;
             }
             _ => {
-                comm.reply(StatusWords::Unknown);
+                comm.reply(Error::BadState);
                 return;
             }
         }
     }
+
+    // Report the number of passwords actually sent, so the client can check
+    // it against the number announced at the beginning, followed by the seal.
+    if trailer || hmac.is_some() {
+        match comm.next_command() {
+            Instruction::ExportNext => {
+                if trailer {
+                    comm.append(&exported.to_be_bytes());
+                }
+                if let Some(hmac) = hmac {
+                    comm.append(&hmac.finalize());
+                }
+                comm.reply_ok();
+            }
+            _ => comm.reply(Error::BadState),
+        }
+    }
+}
+
+/// Returns the key of the seals of exports: exports can only be checked by a
+/// device with the same seed.
+fn seal_key(enc_key: &[u8; 32]) -> [u8; sha1::DIGEST_SIZE] {
+    sha1::hmac_sha1(enc_key, b"export seal")
+}
+
+/// Maximum number of records processed by a single Benchmark command.
+const MAX_BENCHMARK_RECORDS: u8 = 64;
+
+/// Encrypts and MACs dummy records with a throwaway key, using the same
+/// primitives as an encrypted export. Stored passwords are not read.
+fn benchmark(count: u8) {
+    let mut key = [0u8; 32];
+    random::rand_bytes(&mut key);
+    let mut nonce = [0u8; 16];
+    let mut buffer = [0u8; RECORD_SIZE + META_SIZE];
+    for _ in 0..count {
+        random::rand_bytes(&mut nonce);
+        crypto::cbc_encrypt(&key, &nonce, &mut buffer);
+        crypto::cbc_mac(&key, &nonce, &buffer);
+    }
+}
+
+/// Options of an import, selected by the client.
+#[derive(Clone, Copy)]
+struct ImportOptions {
+    /// If true, a password with the name of a stored password is imported
+    /// under a new name instead of replacing it.
+    rename: bool,
+    /// If set, HMAC-SHA1 key of the seal of the export: the salt of the seal
+    /// follows the number of passwords, and an additional ImportNext command
+    /// with the seal is expected after the last password.
+    seal: Option<[u8; sha1::DIGEST_SIZE]>,
+    /// If true, the records are only verified, and nothing is stored. An
+    /// additional ImportNext command is expected after the last password, and
+    /// answered with the number of valid and invalid records.
+    verify_only: bool,
+    /// If true, plaintext records are in their compact form, without padding.
+    compact: bool,
+    /// If true, the number of records the client expects to send follows the
+    /// number of passwords (and the salt of the seal), and an additional
+    /// ImportNext command with P1 = 1 ends the import.
+    check_count: bool,
+    /// If true, a password with the name of a stored password replaces it
+    /// only if its sequence is higher, and keeps its sequence.
+    newest: bool,
+    /// If true, encrypted records have no MAC, and are decrypted without
+    /// being authenticated. Only for transfers over a trusted channel.
+    unverified: bool,
+    /// If true, encrypted records use ChaCha20-Poly1305 instead of
+    /// AES-256-CBC and CBC-MAC.
+    chacha: bool,
 }
 
 /// Import procedure.
@@ -529,110 +3120,581 @@ fn export(
 /// * `enc_key` - Encryption key. If None, passwords are imported as plaintext.
 fn import(
     comm: &mut io::Comm,
-    passwords: &mut nvm::Collection<PasswordItem, 128>,
+    passwords: &mut store::Collection<PasswordItem, 128>,
+    settings: &mut nvm::AtomicStorage<Settings>,
     enc_key: Option<&[u8; 32]>,
+    options: ImportOptions,
 ) {
+    let ImportOptions {
+        rename,
+        seal,
+        verify_only,
+        compact,
+        check_count,
+        newest,
+        unverified,
+        chacha,
+    } = options;
     let encrypted = enc_key.is_some();
 
-    // Retrieve the number of passwords to be imported
-    let mut count_bytes = [0u8; 4];
-    count_bytes.copy_from_slice(comm.get(5, 5 + 4));
-    let mut count = u32::from_be_bytes(count_bytes);
-    // Ask user confirmation
-    if !ui::MessageValidator::new(&[], &[&"Import", &"passwords"], &[&"Cancel"])
-        .ask()
+    // Retrieve the number of passwords to be imported, and the number of
+    // records the client expects to send, after the salt of the seal.
+    let expected_offset = if seal.is_some() { 4 + 16 } else { 4 };
+    let mut expected = None;
+    if check_count {
+        match get_u32(comm, expected_offset) {
+            Some(value) => expected = Some(value),
+            None => {
+                comm.reply(Error::WrongLength);
+                return;
+            }
+        }
+    }
+    let mut count = match get_u32(comm, 0) {
+        Some(count) if data_len(comm) >= expected_offset => count,
+        _ => {
+            comm.reply(Error::WrongLength);
+            return;
+        }
+    };
+    // No backup holds more passwords than the capacity of the store, and the
+    // number must fit in the consent message
+    if count as usize > 128 {
+        comm.reply(Error::InvalidData);
+        return;
+    }
+    let mut hmac = seal.map(|key| sha1::HmacSha1::new(&key));
+    if let Some(hmac) = &mut hmac {
+        hmac.update(comm.get(5 + 4, 5 + 4 + 16));
+    }
+
+    // Dropping the authentication of the records must be accepted explicitly
+    if unverified
+        && !confirm(
+            settings,
+            ui::MessageValidator::new(
+                &[&"MACs not checked"],
+                &[&"Accept", &"risk"],
+                &[&"Cancel"],
+            ),
+        )
+    {
+        comm.reply(Error::NoConsent);
+        return;
+    }
+
+    // Ask user confirmation, showing the number of passwords. Verifying a
+    // backup does not change anything, so it does not require consent.
+    let mut message = ArrayString::<16>::new();
+    message.append_u32(count);
+    message.append(b" passwords");
+    if !verify_only
+        && !confirm(
+            settings,
+            ui::MessageValidator::new(
+                &[],
+                &[&"Import", message.as_str()],
+                &[&"Cancel"],
+            ),
+        )
     {
         comm.reply(Error::NoConsent);
         return;
     } else {
         comm.reply_ok();
     }
+    // The marker is only cleared once the import has completed, and stays
+    // set if it ends with an error
+    if !verify_only {
+        set_import_pending(settings, true);
+    }
     // Wait for all items
-    ui::SingleMessage::new("Importing...").show();
+    let progress = if verify_only { "Verifying..." } else { "Importing..." };
+    ui::SingleMessage::new(progress).show();
+    let mut valid: u32 = 0;
+    let mut invalid: u32 = 0;
+    let mut received: u32 = 0;
+    let mut ended = false;
     while count > 0 {
         match comm.next_command() {
+            // The client ends the import early: its backup has less records
+            // than announced.
+            Instruction::ImportNext if check_count && comm.get_p1() == 1 => {
+                ended = true;
+                break;
+            }
             // Fetch next password
             Instruction::ImportNext => {
+                // Check the record is complete before reading it: nonce,
+                // ciphertext and MAC (or tag) if encrypted. Records exported by
+                // older versions have no metadata block. Compact records
+                // are checked while they are parsed.
+                let is_record = |len| {
+                    len == RECORD_SIZE || len == RECORD_SIZE + META_SIZE
+                };
+                let overhead = match (encrypted, chacha, unverified) {
+                    (true, true, _) => chacha::NONCE_SIZE + chacha::TAG_SIZE,
+                    (true, false, false) => 16 + 16,
+                    (true, false, true) => 16,
+                    (false, _, _) => 0,
+                };
+                let record_len = data_len(comm).saturating_sub(overhead);
+                if !compact && !is_record(record_len) {
+                    // Tell the user if the record has the length of the
+                    // other format, which means the wrong mode was selected.
+                    let other_len = if encrypted {
+                        data_len(comm)
+                    } else {
+                        data_len(comm).saturating_sub(16 + 16)
+                    };
+                    comm.reply(if is_record(other_len) {
+                        Error::FormatMismatch
+                    } else {
+                        Error::WrongLength
+                    });
+                    return;
+                }
                 count -= 1;
-                let mut new_item = PasswordItem::new();
+                received += 1;
+                if let Some(hmac) = &mut hmac {
+                    hmac.update(comm.get(5, 5 + data_len(comm)));
+                }
+                let mut new_item;
                 let mut decrypt_failed = false;
-                if encrypted {
-                    let nonce = comm.get(5, 5 + 16);
-                    let mut buffer: Vec<u8, U96> = Vec::new();
-                    buffer
-                        .extend_from_slice(comm.get(5 + 16, 5 + 16 + 96))
-                        .unwrap();
+                if encrypted && chacha {
+                    let key = enc_key.unwrap();
+                    let mut nonce = [0u8; chacha::NONCE_SIZE];
+                    nonce.copy_from_slice(comm.get(5, 5 + chacha::NONCE_SIZE));
+                    let offset = 5 + chacha::NONCE_SIZE;
+                    let ciphertext = comm.get(offset, offset + record_len);
+                    let mut buffer = [0u8; RECORD_SIZE + META_SIZE];
+                    let buffer = &mut buffer[..record_len];
+                    buffer.copy_from_slice(ciphertext);
+                    let tag_offset = offset + record_len;
+                    let tag =
+                        comm.get(tag_offset, tag_offset + chacha::TAG_SIZE);
+                    // Decrypt with ChaCha20-Poly1305, which checks the tag
+                    // first
+                    decrypt_failed =
+                        !chacha::open(key, &nonce, &[], buffer, tag);
+                    new_item = PasswordItem::from_bytes(buffer);
+                } else if encrypted {
+                    let key = enc_key.unwrap();
+                    let mut nonce = [0u8; 16];
+                    nonce.copy_from_slice(comm.get(5, 5 + 16));
+                    let ciphertext = comm.get(5 + 16, 5 + 16 + record_len);
+                    let mut buffer = [0u8; RECORD_SIZE + META_SIZE];
+                    let buffer = &mut buffer[..record_len];
+                    buffer.copy_from_slice(ciphertext);
                     // Decrypt with AES-256-CBC
-                    let mut aes_ctx = MaybeUninit::<tinyaes::AES_ctx>::uninit();
-                    unsafe {
-                        tinyaes::AES_init_ctx_iv(
-                            aes_ctx.as_mut_ptr(),
-                            enc_key.unwrap().as_ptr(),
-                            nonce.as_ptr(),
-                        );
-                        tinyaes::AES_CBC_decrypt_buffer(
-                            aes_ctx.as_mut_ptr(),
-                            buffer.as_mut_ptr(),
-                            buffer.len() as u32,
-                        );
-                    }
-                    new_item.name =
-                        ArrayString::<32>::from_bytes(&buffer[..32]);
-                    new_item.login =
-                        ArrayString::<32>::from_bytes(&buffer[32..64]);
-                    new_item.pass =
-                        ArrayString::<32>::from_bytes(&buffer[64..96]);
-                    // Verify the MAC
-                    buffer.clear();
-                    buffer
-                        .extend_from_slice(comm.get(5 + 16, 5 + 16 + 96))
-                        .unwrap();
-                    unsafe {
-                        tinyaes::AES_init_ctx_iv(
-                            aes_ctx.as_mut_ptr(),
-                            enc_key.unwrap().as_ptr(),
-                            nonce.as_ptr(),
-                        );
-                        tinyaes::AES_CBC_encrypt_buffer(
-                            aes_ctx.as_mut_ptr(),
-                            buffer.as_mut_ptr(),
-                            buffer.len() as u32,
-                        );
-                    }
-                    let received_mac = comm.get(5 + 16 + 96, 5 + 16 + 96 + 16);
-                    let expected_mac = &buffer[buffer.len() - 16..];
-                    decrypt_failed = received_mac != expected_mac;
+                    crypto::cbc_decrypt(key, &nonce, buffer);
+                    new_item = PasswordItem::from_bytes(buffer);
+                    // Verify the MAC, unless the channel is trusted
+                    if !unverified {
+                        let mac_offset = 5 + 16 + record_len;
+                        let received_mac =
+                            comm.get(mac_offset, mac_offset + 16);
+                        let expected_mac =
+                            crypto::cbc_mac(key, &nonce, ciphertext);
+                        decrypt_failed = match expected_mac {
+                            Some(mac) => received_mac != mac,
+                            None => true,
+                        };
+                    }
+                } else if compact {
+                    let record = comm.get(5, 5 + record_len);
+                    new_item = match PasswordItem::from_compact(record) {
+                        Some(item) => item,
+                        None => {
+                            comm.reply(Error::WrongLength);
+                            return;
+                        }
+                    };
                 } else {
-                    let mut offset = 5;
-                    new_item.name = ArrayString::<32>::from_bytes(
-                        comm.get(offset, offset + 32));
-                    offset += 32;
-                    new_item.login = ArrayString::<32>::from_bytes(
-                        comm.get(offset, offset + 32));
-                    offset += 32;
-                    new_item.pass = ArrayString::<32>::from_bytes(
-                        comm.get(offset, offset + 32));
-                }
-                if !decrypt_failed {
-                    if let Some(index) = passwords
-                        .into_iter()
-                        .position(|x| x.name == new_item.name)
+                    new_item =
+                        PasswordItem::from_bytes(comm.get(5, 5 + record_len));
+                }
+                if verify_only {
+                    if decrypt_failed {
+                        invalid += 1;
+                        comm.append(&[IMPORT_INVALID]);
+                    } else {
+                        valid += 1;
+                        comm.append(&[IMPORT_STORED]);
+                    }
+                    comm.reply_ok();
+                } else if !decrypt_failed {
+                    // A kept sequence raises the counter of the device, and
+                    // is not authenticated in plaintext: a value close to the
+                    // maximum would leave no room for later modifications.
+                    if newest && !rename && new_item.seq > MAX_KEPT_SEQUENCE {
+                        comm.reply(Error::InvalidData);
+                        return;
+                    }
+                    // A password named as the decoy would be hidden by it:
+                    // skip it, and tell the client.
+                    if settings.get_ref().find_decoy(&new_item.name).is_some()
                     {
-                        passwords.remove(index);
+                        comm.append(&[IMPORT_RESERVED]);
+                        comm.reply_ok();
+                        continue;
+                    }
+                    let name = new_item.name;
+                    // Keep the stored password if it is not older
+                    let seq = new_item.seq;
+                    if newest
+                        && !rename
+                        && passwords
+                            .into_iter()
+                            .any(|x| x.name == name && x.seq >= seq)
+                    {
+                        comm.append(&[IMPORT_OLDER]);
+                        comm.reply_ok();
+                        continue;
+                    }
+                    let mut renamed = false;
+                    if rename && passwords.into_iter().any(|x| x.name == name)
+                    {
+                        match unique_name(passwords, settings, &new_item.name)
+                        {
+                            Some(name) => new_item.name = name,
+                            None => {
+                                comm.reply(Error::StorageFull);
+                                continue;
+                            }
+                        }
+                        renamed = true;
+                    }
+                    let keep_seq = newest && !rename;
+                    let status = if renamed {
+                        IMPORT_RENAMED
+                    } else {
+                        IMPORT_STORED
+                    };
+                    match store_item(passwords, settings, new_item, keep_seq)
+                    {
+                        Ok(()) => {
+                            comm.append(&[status]);
+                            comm.reply_ok();
+                        }
+                        Err(e) => comm.reply(e),
                     }
-                    comm.reply::<Reply>(match passwords.add(&new_item) {
-                        Ok(()) => StatusWords::Ok.into(),
-                        Err(nvm::StorageFullError) => Error::StorageFull.into(),
-                    });
                 } else {
                     comm.reply(Error::DecryptFailed);
-                    break;
+                    return;
                 }
             }
             _ => {
-                comm.reply(StatusWords::BadCla);
-                break;
+                comm.reply(Error::BadState);
+                return;
+            }
+        }
+    }
+
+    // All the passwords have been received: check the seal of the export.
+    // The passwords are already stored, but the client can warn the user.
+    // When verifying, report the number of valid and invalid records.
+    // When the client gave the number of records it expects to send, it ends
+    // the import with P1 = 1, and the restore is reported as incomplete if
+    // the number of records received is not both the announced and the
+    // expected one.
+    if hmac.is_some() || verify_only || check_count {
+        if !ended {
+            match comm.next_command() {
+                Instruction::ImportNext => {}
+                _ => {
+                    comm.reply(Error::BadState);
+                    return;
+                }
+            }
+        }
+        let complete = count == 0 && Some(received) == expected;
+        if check_count && (comm.get_p1() != 1 || !complete) {
+            comm.reply(Error::CountMismatch);
+            return;
+        }
+        if let Some(hmac) = hmac {
+            let mac = hmac.finalize();
+            if data_len(comm) != mac.len() || comm.get(5, 5 + mac.len()) != mac
+            {
+                comm.reply(Error::DecryptFailed);
+                return;
+            }
+        }
+        if verify_only {
+            comm.append(&valid.to_be_bytes());
+            comm.append(&invalid.to_be_bytes());
+        }
+        comm.reply_ok();
+    }
+    if !verify_only {
+        set_import_pending(settings, false);
+    }
+}
+
+/// Import procedure of TOTP secrets from otpauth URIs. Unlike the import of
+/// a backup, a URI which cannot be imported does not end the procedure: it is
+/// reported to the client, which can tell the user which secrets are missing.
+/// A storage error ends it, as for a backup, and leaves the import-in-progress
+/// marker set.
+fn import_totp(
+    comm: &mut io::Comm,
+    passwords: &mut store::Collection<PasswordItem, 128>,
+    settings: &mut nvm::AtomicStorage<Settings>,
+) {
+    let mut count = match get_u32(comm, 0) {
+        Some(count) if data_len(comm) == 4 => count,
+        _ => {
+            comm.reply(Error::WrongLength);
+            return;
+        }
+    };
+    // The store cannot hold more secrets, and the number must fit in the
+    // consent message
+    if count as usize > 128 {
+        comm.reply(Error::InvalidData);
+        return;
+    }
+
+    let mut message = ArrayString::<16>::new();
+    message.append_u32(count);
+    message.append(b" TOTP keys");
+    if !confirm(
+        settings,
+        ui::MessageValidator::new(
+            &[],
+            &[&"Import", message.as_str()],
+            &[&"Cancel"],
+        ),
+    ) {
+        comm.reply(Error::NoConsent);
+        return;
+    }
+    comm.reply_ok();
+    set_import_pending(settings, true);
+    ui::SingleMessage::new("Importing...").show();
+    while count > 0 {
+        match comm.next_command() {
+            Instruction::ImportNext => {
+                count -= 1;
+                let uri = comm.get(5, 5 + data_len(comm));
+                let item = match uri::parse_totp(uri) {
+                    Some(item) => item,
+                    None => {
+                        comm.append(&[TOTP_INVALID]);
+                        comm.reply_ok();
+                        continue;
+                    }
+                };
+                if settings.get_ref().find_decoy(&item.name).is_some() {
+                    comm.append(&[TOTP_RESERVED]);
+                    comm.reply_ok();
+                    continue;
+                }
+                match store_item(passwords, settings, item, false) {
+                    Ok(()) => {
+                        comm.append(&[TOTP_STORED]);
+                        comm.reply_ok();
+                    }
+                    Err(e) => {
+                        comm.reply(e);
+                        return;
+                    }
+                }
+            }
+            _ => {
+                comm.reply(Error::BadState);
+                return;
+            }
+        }
+    }
+    set_import_pending(settings, false);
+}
+
+/// Records whether an import is in progress, if it changes.
+fn set_import_pending(
+    settings: &mut nvm::AtomicStorage<Settings>,
+    pending: bool,
+) {
+    if settings.get_ref().import_pending != pending {
+        let mut new_settings = *settings.get_ref();
+        new_settings.import_pending = pending;
+        settings.update(&new_settings);
+    }
+}
+
+/// Returns a name which is not used by any stored password nor by the decoy,
+/// made of the given name followed by a numeric suffix ("name-2", "name-3"...).
+/// The name is truncated if needed for the suffix to fit.
+fn unique_name(
+    passwords: &store::Collection<PasswordItem, 128>,
+    settings: &nvm::AtomicStorage<Settings>,
+    name: &ArrayString<32>,
+) -> Option<ArrayString<32>> {
+    // There are less stored passwords than suffixes tried, so one is free
+    // unless the store is full.
+    for i in 2..(passwords.len() as u32 + 3) {
+        let mut suffix = ArrayString::<16>::new();
+        suffix.append(b"-");
+        suffix.append_u32(i);
+        let suffix = &suffix.bytes()[..suffix.len()];
+        // Do not cut a UTF-8 character
+        let bytes = name.bytes();
+        let mut len = name.len().min(32 - suffix.len());
+        while len > 0 && bytes[len] & 0xc0 == 0x80 {
+            len -= 1;
+        }
+        let mut candidate = ArrayString::<32>::from_bytes(&bytes[..len]);
+        candidate.append(suffix);
+        if settings.get_ref().find_decoy(&candidate).is_none()
+            && !passwords.into_iter().any(|x| x.name == candidate)
+        {
+            return Some(candidate);
+        }
+    }
+    None
+}
+
+/// Number of random bytes drawn by the health tests of the RNG.
+const RNG_TEST_SAMPLES: usize = 512;
+
+/// Runs the repetition count and adaptive proportion tests of NIST SP
+/// 800-90B on random bytes, assuming full entropy, with the cutoffs giving a
+/// false positive probability of 2^-20 per test. Returns false if a test
+/// fails.
+fn rng_healthy() -> bool {
+    // Cutoffs for 8 bits of entropy per byte
+    const REPETITION_CUTOFF: u32 = 4;
+    const PROPORTION_CUTOFF: u32 = 13;
+    let mut chunk = [0u8; 32];
+    let mut first = None;
+    let mut occurrences = 0;
+    let mut last = None;
+    let mut repetitions = 0;
+    for _ in 0..RNG_TEST_SAMPLES / chunk.len() {
+        random::rand_bytes(&mut chunk);
+        for &b in chunk.iter() {
+            if last == Some(b) {
+                repetitions += 1;
+                if repetitions >= REPETITION_CUTOFF {
+                    return false;
+                }
+            } else {
+                repetitions = 1;
+                last = Some(b);
+            }
+            match first {
+                None => {
+                    first = Some(b);
+                    occurrences = 1;
+                }
+                Some(f) if f == b => {
+                    occurrences += 1;
+                    if occurrences >= PROPORTION_CUTOFF {
+                        return false;
+                    }
+                }
+                Some(_) => (),
             }
         }
     }
+    true
+}
+
+/// Maximum number of passwords in a single ImportBlob APDU.
+const MAX_BLOB_ITEMS: usize = 2;
+
+/// Single APDU import procedure, for plaintext passwords.
+///
+/// The Data field contains the number of passwords on one byte, followed by
+/// the passwords records, all with or all without metadata.
+fn import_blob(
+    comm: &mut io::Comm,
+    passwords: &mut store::Collection<PasswordItem, 128>,
+    settings: &mut nvm::AtomicStorage<Settings>,
+) {
+    let count = match get_byte(comm, 0) {
+        Some(count) => count as usize,
+        None => {
+            comm.reply(Error::WrongLength);
+            return;
+        }
+    };
+    let record_len = if count > 0 {
+        (data_len(comm) - 1) / count
+    } else {
+        RECORD_SIZE
+    };
+    if (count > MAX_BLOB_ITEMS)
+        || (record_len != RECORD_SIZE && record_len != RECORD_SIZE + META_SIZE)
+        || (data_len(comm) != 1 + count * record_len)
+    {
+        comm.reply(Error::WrongLength);
+        return;
+    }
+    // Ask user confirmation
+    let mut message = ArrayString::<16>::new();
+    message.append_u32(count as u32);
+    message.append(b" passwords");
+    if !confirm(
+        settings,
+        ui::MessageValidator::new(
+            &[],
+            &[&"Import", message.as_str()],
+            &[&"Cancel"],
+        ),
+    ) {
+        comm.reply(Error::NoConsent);
+        return;
+    }
+    set_import_pending(settings, true);
+    let mut skipped = 0u8;
+    for i in 0..count {
+        let offset = 6 + i * record_len;
+        let new_item =
+            PasswordItem::from_bytes(comm.get(offset, offset + record_len));
+        if settings.get_ref().find_decoy(&new_item.name).is_some() {
+            skipped += 1;
+            continue;
+        }
+        if let Err(e) = store_item(passwords, settings, new_item, false) {
+            comm.reply(e);
+            return;
+        }
+    }
+    set_import_pending(settings, false);
+    comm.append(&[skipped]);
+    comm.reply_ok();
+}
+
+/// Stores a password, replacing any stored password with the same name.
+/// No user confirmation is asked: this must have been done before.
+/// If `keep_seq` is set, the password keeps its sequence, and the sequence
+/// counter is raised to it if needed. Otherwise, it counts as a modification.
+fn store_item(
+    passwords: &mut store::Collection<PasswordItem, 128>,
+    settings: &mut nvm::AtomicStorage<Settings>,
+    mut item: PasswordItem,
+    keep_seq: bool,
+) -> Result<(), Error> {
+    let existing = passwords.into_iter().position(|x| x.name == item.name);
+    if existing.is_none() && is_full(passwords, settings) {
+        return Err(Error::StorageFull);
+    }
+    if keep_seq {
+        if item.seq > settings.get_ref().sequence {
+            let mut new_settings = *settings.get_ref();
+            new_settings.sequence = item.seq;
+            settings.update(&new_settings);
+        }
+    } else {
+        item.seq = next_sequence(settings);
+    }
+    if let Some(index) = existing {
+        passwords.remove(index);
+    }
+    match passwords.add(&item) {
+        Ok(()) => Ok(()),
+        Err(store::StorageFullError) => Err(Error::StorageFull),
+    }
 }
@@ -56,6 +56,35 @@ impl<const N: usize> ArrayString<N> {
         result
     }
 
+    /// Appends bytes at the end of the string.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - Bytes to be appended. Must not have null bytes. The string
+    ///   must have enough room left to store them.
+    pub fn append(&mut self, bytes: &[u8]) {
+        let len = self.len();
+        assert!(len + bytes.len() <= N);
+        self.bytes[len..len + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// Appends the decimal representation of an integer at the end of the
+    /// string.
+    pub fn append_u32(&mut self, value: u32) {
+        let mut digits = [0u8; 10];
+        let mut start = digits.len();
+        let mut value = value;
+        loop {
+            start -= 1;
+            digits[start] = b'0' + (value % 10) as u8;
+            value /= 10;
+            if value == 0 {
+                break;
+            }
+        }
+        self.append(&digits[start..]);
+    }
+
     /// Number of bytes in the string.
     pub fn len(&self) -> usize {
         let mut size = N;
@@ -74,6 +103,28 @@ impl<const N: usize> ArrayString<N> {
     pub fn as_str(&self) -> &str {
         core::str::from_utf8(&self.bytes[..self.len()]).unwrap()
     }
+
+    /// Returns true if the bytes are a valid UTF-8 string, which is required
+    /// before calling `as_str`.
+    pub fn is_utf8(&self) -> bool {
+        core::str::from_utf8(&self.bytes[..self.len()]).is_ok()
+    }
+
+    /// Returns true if the string starts with the given prefix.
+    pub fn starts_with(&self, prefix: &Self) -> bool {
+        self.bytes[..self.len()].starts_with(&prefix.bytes[..prefix.len()])
+    }
+
+    /// Compares with another string in constant time, to be used for secrets.
+    /// Since strings are padded with zeros, comparing all the bytes is
+    /// equivalent to comparing the strings.
+    pub fn ct_eq(&self, other: &Self) -> bool {
+        let mut diff = 0;
+        for i in 0..N {
+            diff |= self.bytes[i] ^ other.bytes[i];
+        }
+        diff == 0
+    }
 }
 
 impl<const N: usize> core::cmp::PartialEq for ArrayString<N> {
@@ -101,7 +152,135 @@ impl<const N: usize> Eq for ArrayString<N> {}
 pub struct PasswordItem {
     pub name: ArrayString<32>,
     pub login: ArrayString<32>,
-    pub pass: ArrayString<32>
+    pub pass: ArrayString<32>,
+    /// Value of the modification sequence counter when the item was created
+    /// or last updated. Used to know how old a password is.
+    pub seq: u32,
+    /// Category of the password, chosen by the user. 0 if untagged.
+    pub tag: u8,
+    /// FLAG_* bits.
+    pub flags: u8,
+    /// Length of a binary secret, which may contain zeros. Unused for text
+    /// passwords.
+    pub secret_len: u8,
+    /// Format hint chosen by the user, telling the client how to present
+    /// the password (for instance a prefix). Not interpreted by the device.
+    pub hint: ArrayString<HINT_SIZE>
+}
+
+/// Maximum size of the format hint of a password.
+pub const HINT_SIZE: usize = 8;
+
+/// Size of name, login and password blocks of a serialized PasswordItem.
+pub const RECORD_SIZE: usize = 96;
+
+/// Size of the metadata block which follows the name, login and password
+/// blocks in a serialized PasswordItem: flags, secret length, tag, sequence
+/// and format hint, then a reserved byte.
+pub const META_SIZE: usize = 16;
+
+/// Maximum size of a compact serialized PasswordItem: name, login and
+/// password each prefixed with their length, then flags, tag, sequence and
+/// format hint prefixed with its length.
+pub const COMPACT_MAX_SIZE: usize = 3 * (1 + 32) + 2 + 4 + 1 + HINT_SIZE;
+
+/// The password is a binary secret, which is not UTF-8 and may contain zeros.
+pub const FLAG_BINARY: u8 = 0x01;
+
+/// The password can only be read right after it has been armed.
+pub const FLAG_ARM_REQUIRED: u8 = 0x02;
+
+/// The password has been marked to be rotated, for instance after it has been
+/// used once. Cleared when the password is updated.
+pub const FLAG_ROTATE: u8 = 0x04;
+
+/// The password is a TOTP secret, which is also a binary secret. It is never
+/// disclosed, and is only used to compute TOTP codes.
+pub const FLAG_TOTP: u8 = 0x20;
+
+/// Status bits reported by `PasswordItem::status` in addition to the flags.
+/// They are derived from the entry and are not stored.
+pub const STATUS_TAGGED: u8 = 0x08;
+pub const STATUS_WIPED: u8 = 0x10;
+
+/// Flags kept during export and import.
+const FLAGS: u8 = FLAG_BINARY | FLAG_ARM_REQUIRED | FLAG_ROTATE | FLAG_TOTP;
+
+/// Result codes of `check_field`.
+pub const FIELD_OK: u8 = 0;
+pub const FIELD_TOO_LONG: u8 = 1;
+pub const FIELD_CONTAINS_ZERO: u8 = 2;
+pub const FIELD_INVALID_UTF8: u8 = 3;
+
+/// Checks if a value can be stored in a field of the given capacity without
+/// being altered. Fields are zero padded, so a zero byte would truncate the
+/// value, and stored values must be valid UTF-8 to be displayed.
+pub fn check_field(value: &[u8], capacity: usize) -> u8 {
+    if value.len() > capacity {
+        FIELD_TOO_LONG
+    } else if value.contains(&0) {
+        FIELD_CONTAINS_ZERO
+    } else if core::str::from_utf8(value).is_err() {
+        FIELD_INVALID_UTF8
+    } else {
+        FIELD_OK
+    }
+}
+
+/// Returns the position of the UTF-8 character following the one at `pos`.
+fn next_char(s: &[u8], pos: usize) -> usize {
+    let mut next = pos + 1;
+    while next < s.len() && s[next] & 0xc0 == 0x80 {
+        next += 1;
+    }
+    next
+}
+
+/// Returns true if a name matches a glob pattern, where `*` matches any
+/// sequence of characters and `?` matches a single character.
+pub fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // Position after the last `*` of the pattern, and position in the name
+    // it currently matches up to, to backtrack on mismatch.
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && pattern[p] == b'?' {
+            p += 1;
+            n = next_char(name, n);
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            p += 1;
+            star = Some((p, n));
+        } else if p < pattern.len() && pattern[p] == name[n] {
+            p += 1;
+            n += 1;
+        } else if let Some((star_p, star_n)) = star {
+            // Let the last `*` match one more character
+            let star_n = next_char(name, star_n);
+            star = Some((star_p, star_n));
+            p = star_p;
+            n = star_n;
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Character class bits returned by `PasswordItem::pass_classes`.
+pub const CLASS_LOWER: u8 = 0x01;
+pub const CLASS_UPPER: u8 = 0x02;
+pub const CLASS_DIGIT: u8 = 0x04;
+pub const CLASS_SYMBOL: u8 = 0x08;
+
+/// Fields checked by `PasswordItem::is_missing`.
+pub const MISSING_LOGIN: u8 = 0;
+pub const MISSING_PASSWORD: u8 = 1;
+pub const MISSING_TAG: u8 = 2;
+
+impl Default for PasswordItem {
+    fn default() -> PasswordItem {
+        PasswordItem::new()
+    }
 }
 
 impl PasswordItem {
@@ -109,7 +288,231 @@ impl PasswordItem {
         PasswordItem {
             name: ArrayString::new(),
             login: ArrayString::new(),
-            pass: ArrayString::new()
+            pass: ArrayString::new(),
+            seq: 0,
+            tag: 0,
+            flags: 0,
+            secret_len: 0,
+            hint: ArrayString::new()
+        }
+    }
+
+    /// Returns a PasswordItem initialized from its serialized form, as used
+    /// during export and import.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - RECORD_SIZE bytes: 32 bytes for the name, 32 bytes for the
+    ///   login and 32 bytes for the password, each padded with zeros.
+    ///   Optionally followed by META_SIZE bytes of metadata. Without metadata,
+    ///   the password is an untagged text password.
+    pub fn from_bytes(bytes: &[u8]) -> PasswordItem {
+        let mut item = PasswordItem {
+            name: ArrayString::from_bytes(&bytes[..32]),
+            login: ArrayString::from_bytes(&bytes[32..64]),
+            pass: ArrayString::from_bytes(&bytes[64..96]),
+            seq: 0,
+            tag: 0,
+            flags: 0,
+            secret_len: 0,
+            hint: ArrayString::new()
+        };
+        if bytes.len() >= RECORD_SIZE + META_SIZE {
+            let meta = &bytes[RECORD_SIZE..RECORD_SIZE + META_SIZE];
+            item.flags = meta[0] & FLAGS;
+            item.secret_len = meta[1].min(32);
+            item.tag = meta[2];
+            let mut seq = [0; 4];
+            seq.copy_from_slice(&meta[3..7]);
+            item.seq = u32::from_be_bytes(seq);
+            item.hint = ArrayString::from_bytes(&meta[7..7 + HINT_SIZE]);
+        }
+        item
+    }
+
+    /// Returns the serialized form of the item, with metadata.
+    pub fn to_bytes(&self) -> [u8; RECORD_SIZE + META_SIZE] {
+        let mut bytes = [0u8; RECORD_SIZE + META_SIZE];
+        bytes[..32].copy_from_slice(self.name.bytes());
+        bytes[32..64].copy_from_slice(self.login.bytes());
+        bytes[64..96].copy_from_slice(self.pass.bytes());
+        bytes[RECORD_SIZE] = self.flags;
+        bytes[RECORD_SIZE + 1] = self.secret_len;
+        bytes[RECORD_SIZE + 2] = self.tag;
+        bytes[RECORD_SIZE + 3..RECORD_SIZE + 7]
+            .copy_from_slice(&self.seq.to_be_bytes());
+        bytes[RECORD_SIZE + 7..RECORD_SIZE + 7 + HINT_SIZE]
+            .copy_from_slice(self.hint.bytes());
+        bytes
+    }
+
+    /// Writes the compact serialized form of the item, without padding, and
+    /// returns its size. Each field is prefixed with its length, and followed
+    /// by the flags, the tag and the sequence. The format hint, prefixed with
+    /// its length, is only written if the item has one.
+    pub fn to_compact(&self, bytes: &mut [u8; COMPACT_MAX_SIZE]) -> usize {
+        let mut size = 0;
+        let fields = [
+            &self.name.bytes()[..self.name.len()],
+            &self.login.bytes()[..self.login.len()],
+            self.secret(),
+        ];
+        for field in fields.iter() {
+            bytes[size] = field.len() as u8;
+            bytes[size + 1..size + 1 + field.len()].copy_from_slice(field);
+            size += 1 + field.len();
+        }
+        bytes[size] = self.flags;
+        bytes[size + 1] = self.tag;
+        bytes[size + 2..size + 6].copy_from_slice(&self.seq.to_be_bytes());
+        size += 6;
+        let hint_len = self.hint.len();
+        if hint_len > 0 {
+            bytes[size] = hint_len as u8;
+            bytes[size + 1..size + 1 + hint_len]
+                .copy_from_slice(&self.hint.bytes()[..hint_len]);
+            size += 1 + hint_len;
+        }
+        size
+    }
+
+    /// Returns a PasswordItem initialized from its compact serialized form
+    /// (see `to_compact`), or None if the bytes are not a valid compact
+    /// record. Records without sequence or format hint, written by older
+    /// versions, are accepted.
+    pub fn from_compact(bytes: &[u8]) -> Option<PasswordItem> {
+        let mut item = PasswordItem::new();
+        let mut offset = 0;
+        for i in 0..3 {
+            let len = *bytes.get(offset)? as usize;
+            if len > 32 {
+                return None;
+            }
+            let field = bytes.get(offset + 1..offset + 1 + len)?;
+            match i {
+                0 => item.name = ArrayString::from_bytes(field),
+                1 => item.login = ArrayString::from_bytes(field),
+                _ => {
+                    item.pass = ArrayString::from_bytes(field);
+                    item.secret_len = len as u8;
+                }
+            }
+            offset += 1 + len;
+        }
+        let remaining = bytes.len() - offset;
+        if remaining >= 6 {
+            let mut seq = [0; 4];
+            seq.copy_from_slice(&bytes[offset + 2..offset + 6]);
+            item.seq = u32::from_be_bytes(seq);
+        }
+        match remaining {
+            2 | 6 => (),
+            _ if remaining > 7 => {
+                let hint = &bytes[offset + 7..];
+                if bytes[offset + 6] as usize != hint.len()
+                    || hint.len() > HINT_SIZE
+                    || hint.contains(&0)
+                {
+                    return None;
+                }
+                item.hint = ArrayString::from_bytes(hint);
+            }
+            _ => return None,
+        }
+        item.flags = bytes[offset] & FLAGS;
+        item.tag = bytes[offset + 1];
+        if !item.is_binary() {
+            item.secret_len = 0;
+        }
+        Some(item)
+    }
+
+    /// Returns true if the password is a binary secret.
+    pub fn is_binary(&self) -> bool {
+        self.flags & FLAG_BINARY != 0
+    }
+
+    /// Returns true if the password is a TOTP secret, which must not be
+    /// disclosed.
+    pub fn is_totp(&self) -> bool {
+        self.flags & FLAG_TOTP != 0
+    }
+
+    /// Returns true if the password must be armed before being read.
+    pub fn is_arm_required(&self) -> bool {
+        self.flags & FLAG_ARM_REQUIRED != 0
+    }
+
+    /// Returns the bytes of the password, without padding.
+    pub fn secret(&self) -> &[u8] {
+        if self.is_binary() {
+            &self.pass.bytes()[..self.secret_len as usize]
+        } else {
+            &self.pass.bytes()[..self.pass.len()]
+        }
+    }
+
+    /// Returns true if the given field (MISSING_* constants) is empty, or None
+    /// if the field is unknown. A password is missing if it has been wiped,
+    /// and a tag if the password is untagged.
+    pub fn is_missing(&self, field: u8) -> Option<bool> {
+        match field {
+            MISSING_LOGIN => Some(self.login.len() == 0),
+            MISSING_PASSWORD => Some(self.secret().is_empty()),
+            MISSING_TAG => Some(self.tag == 0),
+            _ => None,
+        }
+    }
+
+    /// Returns the flags of the entry, with the STATUS_* bits set if it is
+    /// tagged or its password has been wiped.
+    pub fn status(&self) -> u8 {
+        let mut status = self.flags & FLAGS;
+        if self.tag != 0 {
+            status |= STATUS_TAGGED;
+        }
+        if self.secret().is_empty() {
+            status |= STATUS_WIPED;
+        }
+        status
+    }
+
+    /// Returns a rough estimate of the strength of the password, in bits: its
+    /// length multiplied by the number of bits of a character drawn from the
+    /// classes it contains. Binary secrets count 8 bits per byte.
+    pub fn strength(&self) -> u16 {
+        let classes = self.pass_classes();
+        let sizes = [
+            (CLASS_LOWER, 26),
+            (CLASS_UPPER, 26),
+            (CLASS_DIGIT, 10),
+            (CLASS_SYMBOL, 32),
+        ];
+        let bits = if self.is_binary() {
+            8
+        } else {
+            let size: u32 = sizes
+                .iter()
+                .filter(|(class, _)| classes & class != 0)
+                .map(|(_, size)| size)
+                .sum();
+            31u32.saturating_sub(size.leading_zeros())
+        };
+        self.secret().len() as u16 * bits as u16
+    }
+
+    /// Returns a bitmask of the character classes present in the password.
+    /// Any byte which is not an ASCII letter or digit counts as a symbol.
+    pub fn pass_classes(&self) -> u8 {
+        let mut classes = 0;
+        for &c in self.secret() {
+            classes |= match c {
+                b'a'..=b'z' => CLASS_LOWER,
+                b'A'..=b'Z' => CLASS_UPPER,
+                b'0'..=b'9' => CLASS_DIGIT,
+                _ => CLASS_SYMBOL,
+            };
         }
+        classes
     }
 }
@@ -0,0 +1,143 @@
+// Copyright 2020 Ledger SAS
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::generator::GenParams;
+use crate::password::{ArrayString, PasswordItem};
+
+/// Idle screen shows the label only.
+pub const IDLE_LABEL: u8 = 0;
+/// Idle screen shows the label followed by the number of passwords.
+pub const IDLE_LABEL_COUNT: u8 = 1;
+/// Idle screen shows the recovery checkword, or the label if it is not set.
+pub const IDLE_CHECKWORD: u8 = 2;
+
+/// Application settings.
+///
+/// This is intended to be stored in the Flash memory, as a single item which
+/// is updated atomically. Members have fixed size.
+#[derive(Clone, Copy)]
+pub struct Settings {
+    /// Label of the device or vault, displayed on the idle screen and
+    /// returned during export. Empty for the default label.
+    pub label: ArrayString<16>,
+    /// Modification sequence counter, incremented each time a password is
+    /// created or updated.
+    pub sequence: u32,
+    /// Decoy password, returned instead of any stored password when its name
+    /// is requested. Disabled if the name is empty.
+    pub decoy: PasswordItem,
+    /// Default parameters of password generation.
+    pub gen: GenParams,
+    /// Content of the idle screen (IDLE_* constants).
+    pub idle_mode: u8,
+    /// Number of confirmations approved by the user since the application
+    /// was installed.
+    pub confirmations: u32,
+    /// Maximum number of stored passwords set by the user, below the
+    /// capacity of the store. Zero if there is no such limit.
+    pub max_entries: u32,
+    /// Fingerprint of the encryption key derived from the seed, recorded to
+    /// detect a seed change. All zeros if none has been recorded.
+    pub key_fingerprint: [u8; 8],
+    /// Range of modification sequence values of the last provisioning
+    /// session: passwords created or updated during the session have a
+    /// sequence value after the first bound, up to the second one. The
+    /// second bound is u32::MAX while the session is open.
+    pub provisioning: (u32, u32),
+    /// Recovery checkword chosen by the user, so a restored store can be
+    /// recognized. This is not a secret. Empty if none has been set.
+    pub checkword: ArrayString<16>,
+    /// Set when an import starts, and cleared when it completes, so a
+    /// restore interrupted by a power loss or an error can be detected.
+    pub import_pending: bool,
+}
+
+impl Settings {
+    pub const fn new() -> Settings {
+        Settings {
+            label: ArrayString::new(),
+            sequence: 0,
+            decoy: PasswordItem::new(),
+            gen: GenParams::new(),
+            idle_mode: IDLE_LABEL,
+            confirmations: 0,
+            max_entries: 0,
+            key_fingerprint: [0; 8],
+            provisioning: (0, 0),
+            checkword: ArrayString::new(),
+            import_pending: false,
+        }
+    }
+
+    /// Returns the label to be displayed on the idle screen.
+    pub fn label_str(&self) -> &str {
+        match self.label.len() {
+            0 => "NanoPass",
+            _ => self.label.as_str(),
+        }
+    }
+
+    /// Returns the message displayed on the idle screen.
+    ///
+    /// # Arguments
+    ///
+    /// * `count` - Number of stored passwords.
+    pub fn idle_message(&self, count: usize) -> ArrayString<32> {
+        let mut message = ArrayString::new();
+        if self.idle_mode == IDLE_CHECKWORD && self.checkword.len() > 0 {
+            message.append(self.checkword.as_str().as_bytes());
+            return message;
+        }
+        message.append(self.label_str().as_bytes());
+        if self.idle_mode == IDLE_LABEL_COUNT {
+            message.append(b" (");
+            message.append_u32(count as u32);
+            message.append(b")");
+        }
+        message
+    }
+
+    /// Returns the maximum number of stored passwords.
+    ///
+    /// # Arguments
+    ///
+    /// * `capacity` - Capacity of the store.
+    pub fn entry_limit(&self, capacity: usize) -> usize {
+        match self.max_entries {
+            0 => capacity,
+            max => capacity.min(max as usize),
+        }
+    }
+
+    /// Returns true if a provisioning session is open.
+    pub fn is_provisioning(&self) -> bool {
+        self.provisioning.1 == u32::MAX
+    }
+
+    /// Returns true if a password has been created or updated during the last
+    /// provisioning session, and not updated since.
+    pub fn is_provisioned(&self, item: &PasswordItem) -> bool {
+        let (first, last) = self.provisioning;
+        item.seq > first && item.seq <= last
+    }
+
+    /// Returns the decoy password if it is enabled and has the given name.
+    pub fn find_decoy(&self, name: &ArrayString<32>) -> Option<&PasswordItem> {
+        if self.decoy.name.len() > 0 && self.decoy.name == *name {
+            Some(&self.decoy)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,199 @@
+// Copyright 2020 Ledger SAS
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! SHA-1, HMAC-SHA1 and PBKDF2-HMAC-SHA1, as used by challenge-response
+//! authentication and passphrase based encryption.
+
+/// Size of a SHA-1 digest in bytes.
+pub const DIGEST_SIZE: usize = 20;
+
+/// Size of a SHA-1 message block in bytes.
+const BLOCK_SIZE: usize = 64;
+
+/// Incremental SHA-1 computation.
+pub struct Sha1 {
+    state: [u32; 5],
+    block: [u8; BLOCK_SIZE],
+    block_len: usize,
+    total_len: u64,
+}
+
+impl Sha1 {
+    pub const fn new() -> Sha1 {
+        Sha1 {
+            state: [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0],
+            block: [0; BLOCK_SIZE],
+            block_len: 0,
+            total_len: 0,
+        }
+    }
+
+    /// Hashes more data.
+    pub fn update(&mut self, data: &[u8]) {
+        for &b in data {
+            self.block[self.block_len] = b;
+            self.block_len += 1;
+            if self.block_len == BLOCK_SIZE {
+                self.compress();
+                self.block_len = 0;
+            }
+        }
+        self.total_len += data.len() as u64;
+    }
+
+    /// Terminates the computation and returns the digest.
+    pub fn finalize(mut self) -> [u8; DIGEST_SIZE] {
+        let bit_len = self.total_len * 8;
+        self.update(&[0x80]);
+        while self.block_len != BLOCK_SIZE - 8 {
+            self.update(&[0]);
+        }
+        self.update(&bit_len.to_be_bytes());
+        let mut digest = [0; DIGEST_SIZE];
+        for (chunk, word) in digest.chunks_mut(4).zip(self.state.iter()) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    /// Processes the current block.
+    fn compress(&mut self) {
+        let mut w = [0u32; 80];
+        for i in 0..16 {
+            let mut word = [0; 4];
+            word.copy_from_slice(&self.block[i * 4..i * 4 + 4]);
+            w[i] = u32::from_be_bytes(word);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = self.state;
+        for (i, &wi) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(wi);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (s, v) in self.state.iter_mut().zip([a, b, c, d, e].iter()) {
+            *s = s.wrapping_add(*v);
+        }
+    }
+}
+
+/// Computes HMAC-SHA1 of a message.
+///
+/// # Arguments
+///
+/// * `key` - HMAC key. Keys longer than a block are hashed first.
+/// * `message` - Authenticated message.
+pub fn hmac_sha1(key: &[u8], message: &[u8]) -> [u8; DIGEST_SIZE] {
+    hmac_sha1_parts(key, &[message])
+}
+
+/// Computes HMAC-SHA1 of a message given in several parts, which are
+/// concatenated.
+fn hmac_sha1_parts(key: &[u8], parts: &[&[u8]]) -> [u8; DIGEST_SIZE] {
+    let mut hmac = HmacSha1::new(key);
+    for part in parts {
+        hmac.update(part);
+    }
+    hmac.finalize()
+}
+
+/// Incremental HMAC-SHA1 computation, for messages which are not available
+/// at once.
+pub struct HmacSha1 {
+    inner: Sha1,
+    outer: Sha1,
+}
+
+impl HmacSha1 {
+    /// Starts a computation. Keys longer than a block are hashed first.
+    pub fn new(key: &[u8]) -> HmacSha1 {
+        let mut block_key = [0u8; BLOCK_SIZE];
+        if key.len() > BLOCK_SIZE {
+            let mut h = Sha1::new();
+            h.update(key);
+            block_key[..DIGEST_SIZE].copy_from_slice(&h.finalize());
+        } else {
+            block_key[..key.len()].copy_from_slice(key);
+        }
+
+        let mut pad = [0u8; BLOCK_SIZE];
+        for (p, k) in pad.iter_mut().zip(block_key.iter()) {
+            *p = k ^ 0x36;
+        }
+        let mut inner = Sha1::new();
+        inner.update(&pad);
+
+        for (p, k) in pad.iter_mut().zip(block_key.iter()) {
+            *p = k ^ 0x5c;
+        }
+        let mut outer = Sha1::new();
+        outer.update(&pad);
+        HmacSha1 { inner, outer }
+    }
+
+    /// Authenticates more data.
+    pub fn update(&mut self, data: &[u8]) {
+        self.inner.update(data);
+    }
+
+    /// Terminates the computation and returns the MAC.
+    pub fn finalize(mut self) -> [u8; DIGEST_SIZE] {
+        self.outer.update(&self.inner.finalize());
+        self.outer.finalize()
+    }
+}
+
+/// Derives a key from a password with PBKDF2-HMAC-SHA1 (RFC 8018).
+///
+/// # Arguments
+///
+/// * `password` - Password, used as HMAC key.
+/// * `salt` - Random salt.
+/// * `iterations` - Number of iterations, at least 1.
+/// * `output` - Buffer filled with the derived key.
+pub fn pbkdf2_hmac_sha1(
+    password: &[u8],
+    salt: &[u8],
+    iterations: u32,
+    output: &mut [u8],
+) {
+    for (i, chunk) in output.chunks_mut(DIGEST_SIZE).enumerate() {
+        let index = (i as u32 + 1).to_be_bytes();
+        let mut u = hmac_sha1_parts(password, &[salt, &index]);
+        let mut t = u;
+        for _ in 1..iterations {
+            u = hmac_sha1(password, &u);
+            for (a, b) in t.iter_mut().zip(u.iter()) {
+                *a ^= b;
+            }
+        }
+        chunk.copy_from_slice(&t[..chunk.len()]);
+    }
+}
@@ -0,0 +1,288 @@
+// Copyright 2020 Ledger SAS
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Password storage in NVM.
+//!
+//! This is the same as the SDK `nvm::Collection`, except that removed items
+//! are overwritten, so deleted secrets cannot be recovered from the Flash
+//! memory.
+
+use core::mem::size_of;
+use nanos_sdk::nvm::{AlignedStorage, AtomicStorage, SingleStorage};
+pub use nanos_sdk::nvm::StorageFullError;
+
+/// Flag value of an allocated slot.
+const STORAGE_VALID: u8 = 0xa5;
+
+/// Size of a page of the Flash memory, which is the unit of NVM writes.
+pub const PAGE_SIZE: usize = 64;
+
+/// Returns the number of Flash pages written when updating a value of the
+/// given size.
+pub const fn page_writes(size: usize) -> usize {
+    (size + PAGE_SIZE - 1) / PAGE_SIZE
+}
+
+/// Returns the approximate number of Flash pages written when updating an
+/// `AtomicStorage` holding a value of the given size: the value is written to
+/// the inactive copy, then the page selecting the active copy is updated.
+pub const fn atomic_page_writes(size: usize) -> usize {
+    page_writes(size) + 1
+}
+
+/// Fixed size collection of items stored in NVM.
+pub struct Collection<T, const N: usize> {
+    flags: AtomicStorage<[u8; N]>,
+    slots: [AlignedStorage<T>; N],
+}
+
+impl<T, const N: usize> Collection<T, N>
+where
+    T: Copy + Default,
+{
+    pub const fn new(value: T) -> Collection<T, N> {
+        Collection {
+            flags: AtomicStorage::new(&[0; N]),
+            slots: [AlignedStorage::new(value); N],
+        }
+    }
+
+    /// Adds an item in the collection. Returns an error if there is no free
+    /// slot.
+    /// This operation is atomic.
+    pub fn add(&mut self, value: &T) -> Result<(), StorageFullError> {
+        match self.flags.get_ref().iter().position(|&f| f != STORAGE_VALID) {
+            Some(key) => {
+                self.slots[key].update(value);
+                let mut new_flags = *self.flags.get_ref();
+                new_flags[key] = STORAGE_VALID;
+                self.flags.update(&new_flags);
+                Ok(())
+            }
+            None => Err(StorageFullError),
+        }
+    }
+
+    /// Returns the approximate number of Flash pages written by `add`.
+    pub fn add_page_writes(&self) -> usize {
+        page_writes(size_of::<AlignedStorage<T>>()) + atomic_page_writes(N)
+    }
+
+    /// Returns the approximate number of Flash pages written by `clear`,
+    /// which overwrites every slot.
+    pub fn clear_page_writes(&self) -> usize {
+        atomic_page_writes(N) + N * page_writes(size_of::<AlignedStorage<T>>())
+    }
+
+    /// Returns the number of items in the collection.
+    pub fn len(&self) -> usize {
+        self.flags
+            .get_ref()
+            .iter()
+            .filter(|&&f| f == STORAGE_VALID)
+            .count()
+    }
+
+    /// Returns the index the next added item will have in the collection, or
+    /// None if there is no free slot.
+    pub fn next_index(&self) -> Option<usize> {
+        let flags = self.flags.get_ref();
+        flags.iter().position(|&f| f != STORAGE_VALID).map(|key| {
+            flags[..key].iter().filter(|&&f| f == STORAGE_VALID).count()
+        })
+    }
+
+    /// Returns the slot of an item, given its index in the collection, or
+    /// None if the index is out of bounds.
+    fn index_to_key(&self, index: usize) -> Option<usize> {
+        self.flags
+            .get_ref()
+            .iter()
+            .enumerate()
+            .filter(|(_, &f)| f == STORAGE_VALID)
+            .nth(index)
+            .map(|(key, _)| key)
+    }
+
+    /// Returns a reference to an item, or None if the index is out of bounds.
+    pub fn get(&self, index: usize) -> Option<&T> {
+        self.index_to_key(index).map(|key| self.slots[key].get_ref())
+    }
+
+    /// Replaces an item in place: it keeps its position in the collection,
+    /// and unlike a removal followed by an addition, it is never absent from
+    /// the collection. The slot is overwritten, so the previous value cannot
+    /// be recovered.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is out of bounds.
+    pub fn replace(&mut self, index: usize, value: &T) {
+        let key = self.index_to_key(index).unwrap();
+        self.slots[key].update(value);
+    }
+
+    /// Replaces two items at once: either both are replaced, or none. The new
+    /// values are written to free slots, which are then swapped with the
+    /// previous ones by a single update of the flags. The previous slots are
+    /// overwritten afterwards, so the previous values cannot be recovered.
+    /// The items may change position in the collection.
+    ///
+    /// Returns an error if there are less than two free slots.
+    ///
+    /// # Panics
+    ///
+    /// Panics if an index is out of bounds.
+    pub fn replace_pair(
+        &mut self,
+        first: usize,
+        first_value: &T,
+        second: usize,
+        second_value: &T,
+    ) -> Result<(), StorageFullError> {
+        let old_keys = [
+            self.index_to_key(first).unwrap(),
+            self.index_to_key(second).unwrap(),
+        ];
+        let mut new_flags = *self.flags.get_ref();
+        let mut free = new_flags
+            .iter()
+            .enumerate()
+            .filter(|(_, &f)| f != STORAGE_VALID)
+            .map(|(key, _)| key);
+        let new_keys = match (free.next(), free.next()) {
+            (Some(a), Some(b)) => [a, b],
+            _ => return Err(StorageFullError),
+        };
+        self.slots[new_keys[0]].update(first_value);
+        self.slots[new_keys[1]].update(second_value);
+        for (&old, &new) in old_keys.iter().zip(new_keys.iter()) {
+            new_flags[old] = 0;
+            new_flags[new] = STORAGE_VALID;
+        }
+        self.flags.update(&new_flags);
+        for &key in old_keys.iter() {
+            self.slots[key].update(&T::default());
+        }
+        Ok(())
+    }
+
+    /// Swaps the positions of two items in the collection. The items are
+    /// moved through a free slot, with an update of the flags after each
+    /// move, so every item remains in the collection exactly once if the
+    /// operation is interrupted, possibly at another position. The free slot
+    /// is overwritten afterwards.
+    ///
+    /// Returns an error if there is no free slot.
+    ///
+    /// # Panics
+    ///
+    /// Panics if an index is out of bounds.
+    pub fn swap(
+        &mut self,
+        first: usize,
+        second: usize,
+    ) -> Result<(), StorageFullError> {
+        let first_key = self.index_to_key(first).unwrap();
+        let second_key = self.index_to_key(second).unwrap();
+        let free_key =
+            match self.flags.get_ref().iter().position(|&f| f != STORAGE_VALID)
+            {
+                Some(key) => key,
+                None => return Err(StorageFullError),
+            };
+        let first_value = *self.slots[first_key].get_ref();
+        let second_value = *self.slots[second_key].get_ref();
+        // Each step writes a slot which is not in the collection, then moves
+        // an item into it.
+        let moves = [
+            (free_key, first_value, first_key),
+            (first_key, second_value, second_key),
+            (second_key, first_value, free_key),
+        ];
+        for &(to, value, from) in moves.iter() {
+            self.slots[to].update(&value);
+            let mut new_flags = *self.flags.get_ref();
+            new_flags[to] = STORAGE_VALID;
+            new_flags[from] = 0;
+            self.flags.update(&new_flags);
+        }
+        self.slots[free_key].update(&T::default());
+        Ok(())
+    }
+
+    /// Removes an item from the collection, and overwrites its slot with the
+    /// default value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is out of bounds.
+    pub fn remove(&mut self, index: usize) {
+        let key = self.index_to_key(index).unwrap();
+        let mut new_flags = *self.flags.get_ref();
+        new_flags[key] = 0;
+        self.flags.update(&new_flags);
+        self.slots[key].update(&T::default());
+    }
+
+    /// Removes all the items from the collection, and overwrites every slot
+    /// with the default value.
+    /// Removal is atomic: the slots are only scrubbed once all the items have
+    /// been removed, so an interruption cannot leave a partial collection.
+    pub fn clear(&mut self) {
+        self.flags.update(&[0; N]);
+        for slot in self.slots.iter_mut() {
+            slot.update(&T::default());
+        }
+    }
+}
+
+impl<'a, T, const N: usize> IntoIterator for &'a Collection<T, N>
+where
+    T: Copy + Default,
+{
+    type Item = &'a T;
+    type IntoIter = CollectionIterator<'a, T, N>;
+
+    fn into_iter(self) -> CollectionIterator<'a, T, N> {
+        CollectionIterator {
+            container: self,
+            next_key: 0,
+        }
+    }
+}
+
+pub struct CollectionIterator<'a, T, const N: usize> {
+    container: &'a Collection<T, N>,
+    next_key: usize,
+}
+
+impl<'a, T, const N: usize> Iterator for CollectionIterator<'a, T, N>
+where
+    T: Copy + Default,
+{
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        let flags = self.container.flags.get_ref();
+        while self.next_key < N {
+            let key = self.next_key;
+            self.next_key += 1;
+            if flags[key] == STORAGE_VALID {
+                return Some(self.container.slots[key].get_ref());
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,236 @@
+// Copyright 2020 Ledger SAS
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! URI form of a single entry, to move it to or from other applications:
+//! binary secrets are written as `otpauth://totp/` URIs, understood by
+//! authenticator applications, and passwords as `nanopass://entry/` URIs.
+
+use crate::encoding;
+use crate::password::{self, ArrayString, PasswordItem};
+
+/// Maximum size of a URI, which is sent in a single APDU.
+pub const MAX_URI_SIZE: usize = 255;
+
+const OTPAUTH_PREFIX: &[u8] = b"otpauth://totp/";
+const NANOPASS_PREFIX: &[u8] = b"nanopass://entry/";
+
+const HEX_CHARS: &[u8] = b"0123456789ABCDEF";
+
+/// Bytes written to a fixed size buffer, which remembers if some did not fit.
+struct Writer<'a> {
+    buffer: &'a mut [u8; MAX_URI_SIZE],
+    len: usize,
+    overflow: bool,
+}
+
+impl Writer<'_> {
+    fn push(&mut self, bytes: &[u8]) {
+        if self.len + bytes.len() > self.buffer.len() {
+            self.overflow = true;
+        } else {
+            self.buffer[self.len..self.len + bytes.len()]
+                .copy_from_slice(bytes);
+            self.len += bytes.len();
+        }
+    }
+
+    /// Writes a value with percent-encoding (RFC 3986): all the bytes but
+    /// unreserved characters are encoded.
+    fn push_encoded(&mut self, value: &[u8]) {
+        for &b in value {
+            if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+                self.push(&[b]);
+            } else {
+                self.push(&[
+                    b'%',
+                    HEX_CHARS[(b >> 4) as usize],
+                    HEX_CHARS[(b & 0x0f) as usize],
+                ]);
+            }
+        }
+    }
+}
+
+/// Writes the URI of an entry to `buffer`. Returns its length, or None if it
+/// does not fit.
+///
+/// Binary secrets give `otpauth://totp/NAME:LOGIN?secret=BASE32` (without
+/// `:LOGIN` if the login is empty). Passwords give
+/// `nanopass://entry/NAME?login=LOGIN&password=PASSWORD`.
+pub fn format(
+    item: &PasswordItem,
+    buffer: &mut [u8; MAX_URI_SIZE],
+) -> Option<usize> {
+    let mut writer = Writer {
+        buffer,
+        len: 0,
+        overflow: false,
+    };
+    let name = &item.name.bytes()[..item.name.len()];
+    let login = &item.login.bytes()[..item.login.len()];
+    if item.is_binary() {
+        writer.push(OTPAUTH_PREFIX);
+        writer.push_encoded(name);
+        if !login.is_empty() {
+            writer.push(b":");
+            writer.push_encoded(login);
+        }
+        writer.push(b"?secret=");
+        writer.push(encoding::to_base32(item.secret()).as_str().as_bytes());
+    } else {
+        writer.push(NANOPASS_PREFIX);
+        writer.push_encoded(name);
+        writer.push(b"?login=");
+        writer.push_encoded(login);
+        writer.push(b"&password=");
+        writer.push_encoded(item.secret());
+    }
+    if writer.overflow {
+        None
+    } else {
+        Some(writer.len)
+    }
+}
+
+/// Returns the entry described by a URI in the form written by `format`, or
+/// None if the URI is malformed or a field cannot be stored. Parameters
+/// other than the secret of `otpauth` URIs (algorithm, digits, period...)
+/// are ignored, as the device only stores the secret.
+pub fn parse(uri: &[u8]) -> Option<PasswordItem> {
+    let (binary, rest) = if uri.starts_with(OTPAUTH_PREFIX) {
+        (true, &uri[OTPAUTH_PREFIX.len()..])
+    } else if uri.starts_with(NANOPASS_PREFIX) {
+        (false, &uri[NANOPASS_PREFIX.len()..])
+    } else {
+        return None;
+    };
+    let (label, query) = match rest.iter().position(|&c| c == b'?') {
+        Some(pos) => (&rest[..pos], &rest[pos + 1..]),
+        None => (rest, &rest[rest.len()..]),
+    };
+    let mut item = PasswordItem::new();
+    if binary {
+        // The account follows the issuer after a colon, which is encoded if
+        // it is part of the name.
+        let (name, login) = match label.iter().position(|&c| c == b':') {
+            Some(pos) => (&label[..pos], &label[pos + 1..]),
+            None => (label, &label[label.len()..]),
+        };
+        item.name = decode_field(name)?;
+        item.login = decode_field(login)?;
+        let mut secret = [0u8; 32];
+        let len = encoding::from_base32(param(query, b"secret")?, &mut secret)?;
+        if len == 0 {
+            return None;
+        }
+        item.pass = ArrayString::from_bytes(&secret[..len]);
+        item.secret_len = len as u8;
+        item.flags |= password::FLAG_BINARY;
+    } else {
+        item.name = decode_field(label)?;
+        item.login = decode_field(param(query, b"login").unwrap_or(b""))?;
+        item.pass = decode_field(param(query, b"password").unwrap_or(b""))?;
+    }
+    match item.name.len() {
+        0 => None,
+        _ => Some(item),
+    }
+}
+
+/// Returns the TOTP secret described by an `otpauth://totp/` URI, as exported
+/// by authenticator applications, marked with `FLAG_TOTP` so it is never
+/// disclosed. Returns None if the URI is malformed or describes codes the
+/// device cannot compute (see `totp` in main.rs): the `digits`, `period` and
+/// `algorithm` parameters, if present, must be 6, 30 and SHA1. If the label
+/// has no account, it is the login, and the `issuer` parameter gives the name.
+pub fn parse_totp(uri: &[u8]) -> Option<PasswordItem> {
+    if !uri.starts_with(OTPAUTH_PREFIX) {
+        return None;
+    }
+    let query = match uri.iter().position(|&c| c == b'?') {
+        Some(pos) => &uri[pos + 1..],
+        None => return None,
+    };
+    let supported = [
+        (&b"digits"[..], &b"6"[..]),
+        (b"period", b"30"),
+        (b"algorithm", b"SHA1"),
+    ];
+    for (key, expected) in supported.iter() {
+        match param(query, key) {
+            Some(value) if !value.eq_ignore_ascii_case(expected) => {
+                return None
+            }
+            _ => (),
+        }
+    }
+    let mut item = parse(uri)?;
+    item.flags |= password::FLAG_TOTP;
+    if item.login.len() == 0 {
+        if let Some(issuer) = param(query, b"issuer") {
+            item.login = item.name;
+            item.name = decode_field(issuer)?;
+            if item.name.len() == 0 {
+                return None;
+            }
+        }
+    }
+    Some(item)
+}
+
+/// Returns the value of a parameter of a query string, not decoded.
+fn param<'a>(query: &'a [u8], key: &[u8]) -> Option<&'a [u8]> {
+    query.split(|&c| c == b'&').find_map(|pair| {
+        let pos = pair.iter().position(|&c| c == b'=')?;
+        if &pair[..pos] == key {
+            Some(&pair[pos + 1..])
+        } else {
+            None
+        }
+    })
+}
+
+/// Decodes a percent-encoded value, which must fit in a field of 32 bytes
+/// (see `password::check_field`).
+fn decode_field(value: &[u8]) -> Option<ArrayString<32>> {
+    let mut decoded = [0u8; 33];
+    let mut len = 0;
+    let mut i = 0;
+    while i < value.len() {
+        let b = match value[i] {
+            b'%' => {
+                let hex = value.get(i + 1..i + 3)?;
+                if !hex.iter().all(|c| c.is_ascii_hexdigit()) {
+                    return None;
+                }
+                i += 3;
+                let text = core::str::from_utf8(hex).ok()?;
+                u8::from_str_radix(text, 16).ok()?
+            }
+            b => {
+                i += 1;
+                b
+            }
+        };
+        if len == decoded.len() {
+            return None;
+        }
+        decoded[len] = b;
+        len += 1;
+    }
+    match password::check_field(&decoded[..len], 32) {
+        password::FIELD_OK => Some(ArrayString::from_bytes(&decoded[..len])),
+        _ => None,
+    }
+}
@@ -19,6 +19,14 @@ from time import sleep
 from binascii import hexlify
 import socket
 
+class StatusError(Exception):
+    """
+    Raised when the device replies with a status word other than 0x9000.
+    """
+    def __init__(self, sw: int):
+        super().__init__("Device replied 0x{:04x}".format(sw))
+        self.sw = sw
+
 class Automaton:
     def __init__(self):
         self.sock = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
@@ -60,7 +68,9 @@ class Automaton:
                 # Next actions for next APDU
                 break;
         resp = self.receive()
-        assert resp[-2:] == b'\x90\x00'
+        sw = int.from_bytes(resp[-2:], 'big')
+        if sw != 0x9000:
+            raise StatusError(sw)
         return resp[:-2]
         
     def __recv_all(self, n) -> bytes:
@@ -14,9 +14,17 @@
 # See the License for the specific language governing permissions and
 # limitations under the License.
 
-from automaton import Automaton
-from nanopass import Client
+from automaton import Automaton, StatusError
+from nanopass import (AmbiguousName, Client, CLASS_LOWER, CLASS_UPPER,
+    CLASS_DIGIT, CLASS_SYMBOL, CHECK_OK, CHECK_TOO_LONG, CHECK_CONTAINS_ZERO,
+    IDLE_LABEL, IDLE_LABEL_COUNT, IDLE_CHECKWORD, MISSING_LOGIN,
+    MISSING_PASSWORD, MISSING_TAG, FLAG_ARM_REQUIRED, FLAG_TOTP, MODEL_NANO_S,
+    SYLLABLE_CONSONANTS, SYLLABLE_VOWELS, TOTP_INVALID, TOTP_STORED,
+    apply_hint, decrypt_entry, passphrase_key)
+import base64
 import random
+import hmac
+import hashlib
 
 passwords = [
     ("x", "", "1"),
@@ -29,11 +37,37 @@ passwords = [
 auto = Automaton()
 client = Client(auto)
 
+# Status words of the errors checked by the tests
+SW_STORAGE_FULL = 0x9210
+SW_INVALID_DATA = 0x6a80
+SW_DECRYPT_FAILED = 0x9d60
+SW_BAD_STATE = 0x6986
+SW_NOT_ARMED = 0x6982
+SW_COUNT_MISMATCH = 0x6a8a
+SW_TOTP_ONLY = 0x6a8b
+SW_WRONG_LENGTH = 0x6700
+
+def expect_error(sw, function, *args, **kwargs):
+    """
+    Check the device replies with the status word sw to a call of the client.
+    """
+    try:
+        function(*args, **kwargs)
+    except StatusError as e:
+        assert e.sw == sw, e
+    else:
+        assert False, "Device accepted the command"
+
 def test_password_list():
     """ Test password name listing. """
     entries = client.get_names()
     assert (set(client.get_names()) ==
         set(name for (name, _, _) in passwords))
+    assert client.get_names(recent_first=True) == entries[::-1]
+    assert client.get_names(by_name=True) == sorted(entries,
+        key=lambda name: name.encode())
+    assert all(client.get_name_length(i) == len(name.encode())
+        for (i, name) in enumerate(entries))
 
 def test_has_name():
     """ Test the HasName APDU command """
@@ -41,9 +75,106 @@ def test_has_name():
         assert client.has_name(name)
     assert not client.has_name("undefined")
 
+def test_missing():
+    without_login = [name for name, login, _ in passwords if login == ""]
+    assert client.get_missing_count(MISSING_LOGIN) == len(without_login)
+    assert sorted(client.get_missing(MISSING_LOGIN)) == sorted(without_login)
+    assert client.get_missing_count(MISSING_PASSWORD) == 0
+    assert client.get_missing_count(MISSING_TAG) == client.get_size()
+    expect_error(SW_WRONG_LENGTH, auto.apdu_exchange, 0x30, bytes(2),
+        p1=MISSING_TAG)
+
+def test_match_names():
+    names = client.get_names()
+    for pattern, expected in [("*", names), ("q*", ["question",
+        "quick fiber estate ripple phrase"]), ("?", ["x"]), ("*e*e*", ["emerge",
+        "question", "quick fiber estate ripple phrase"]), ("nothing", [])]:
+        count, indexes = client.match_names(pattern)
+        assert count == len(expected)
+        assert sorted(names[i] for i in indexes) == sorted(expected)
+
+def test_reuse_count():
+    """ Test detection of passwords shared between entries. """
+    assert client.get_reuse_count() == 0
+    auto.actions = "rb"
+    client.add("reused", "", passwords[0][2])
+    assert client.get_reuse_count() == 2
+    assert client.compare_passwords("reused", passwords[0][0])
+    assert not client.compare_passwords("reused", passwords[1][0])
+    auto.actions = "rb"
+    client.delete_by_name("reused")
+    assert client.get_reuse_count() == 0
+
+def test_stale_count():
+    """ Test counting of passwords not updated recently. """
+    assert client.get_stale_count(0) == len(passwords)
+    assert client.get_stale_count(len(passwords)) == 0
+    expect_error(SW_WRONG_LENGTH, auto.apdu_exchange, 0x13, bytes(2))
+    name, login, password = passwords[0]
+    auto.actions = "rb"
+    client.add(name, login, password)
+    assert client.get_stale_count(1) == len(passwords) - 1
+    oldest, newest, current = client.get_sequence_range()
+    assert oldest < newest == current
+    lengths = [len(p.encode()) for _, _, p in passwords if p]
+    assert client.get_length_range() == (min(lengths), max(lengths))
+    weakest = client.get_weakest()
+    assert sorted(weakest) == sorted(name for name, _, _ in passwords)
+    assert weakest[0] == "x"
+    assert client.get_lacking_classes(CLASS_UPPER) == ["x"]
+    assert client.get_duplicate_names() == []
+    assert (sorted(client.get_lacking_classes(CLASS_DIGIT | CLASS_SYMBOL)) ==
+        sorted(name for name, _, _ in passwords))
+
+def test_storage_usage():
+    """ Test the report of NVM bytes used by the password store. """
+    used, total = client.get_storage_usage()
+    assert 0 < used < total
+    assert used % len(passwords) == 0
+    assert client.get_clear_wear() >= total // 64
+    assert client.get_import_wear(4) == 2 * client.get_import_wear(2) > 0
+
+def test_password_classes():
+    """ Test the character classes reported for stored passwords. """
+    classes, length = client.get_password_classes("want")
+    assert classes == CLASS_LOWER | CLASS_UPPER | CLASS_DIGIT
+    assert length == len("epuu7Aeja9")
+    classes, length = client.get_password_classes("x")
+    assert classes == CLASS_DIGIT
+    assert length == 1
+
+def test_challenge_response():
+    """ Test HMAC-SHA1 challenge-response keyed with a stored password. """
+    name, _, password = passwords[1]
+    challenge = bytes(range(16))
+    auto.actions = "rb"
+    response = client.challenge_response(name, challenge)
+    assert response == hmac.new(password.encode(), challenge,
+        hashlib.sha1).digest()
+
+def test_letter_counts():
+    """ Test the count of passwords by first letter of their name. """
+    counts = client.get_letter_counts()
+    assert counts['Q'] == 2
+    assert counts['X'] == 1
+    assert sum(counts.values()) == len(passwords)
+
+def test_check_password():
+    """ Test validation of candidate passwords. """
+    assert client.check_password("epuu7Aeja9") == CHECK_OK
+    assert client.check_password("x" * 33) == CHECK_TOO_LONG
+    assert client.check_password("a\0b") == CHECK_CONTAINS_ZERO
+
+def test_store_digest():
+    names = sorted(name.encode() for name, _, _ in passwords)
+    digests = [client.get_entry_digest(name.decode()) for name in names]
+    assert client.get_store_digest() == hashlib.sha1(b"".join(digests)).digest()
+    return client.get_store_digest()
+
 def test_password_retrieval():
     """ Verify the correctness of login and password values. """
     for name, login, password in passwords:
+        assert client.get_read_confirmations(name) == 1
         auto.actions = "rb"
         login2, password2 = client.get_by_name(name)
         assert login == login2
@@ -60,45 +191,666 @@ def test_clear():
 
 # Test password insertion
 assert client.get_size() == 0
+add_count = client.get_add_count()
+confirmations = client.get_confirmation_count()
 for i, (name, login, password) in enumerate(passwords):
     auto.actions = "rb"
     client.add(name, login, password)
     assert client.get_size() == i+1
+assert client.get_add_count() == add_count + len(passwords)
+assert client.get_confirmation_count() == confirmations + len(passwords)
+
+# Test the limit of the number of stored passwords
+auto.actions = "rb"
+client.set_max_entries(len(passwords))
+assert client.get_max_entries() == len(passwords)
+assert client.get_status() == {'count': len(passwords),
+    'capacity': len(passwords), 'free': 0, 'full': 1}
+auto.actions = ""
+expect_error(SW_STORAGE_FULL, client.add, "over limit", "", "password")
+expect_error(SW_WRONG_LENGTH, auto.apdu_exchange, 0x2b, bytes(2))
+assert client.get_size() == len(passwords)
+auto.actions = "rb"
+client.set_max_entries(0)
+assert client.get_max_entries() == 128
+assert client.get_status() == {'count': len(passwords), 'capacity': 128,
+    'free': 128 - len(passwords), 'full': 0}
 
 test_password_list()
 test_has_name()
+test_missing()
+test_match_names()
+# The most recent entry is listed first even when it reuses a freed slot
+for name in ["early", "late"]:
+    auto.actions = "rb"
+    client.add(name, "", "password")
+auto.actions = "rb"
+client.delete_by_name("early")
+auto.actions = "rb"
+client.add("recent", "", "password")
+assert client.get_names()[-1] == "late"
+assert client.get_names(recent_first=True)[:2] == ["recent", "late"]
+for name in ["recent", "late"]:
+    auto.actions = "rb"
+    client.delete_by_name(name)
+assert client.get_derivation_path() == [0x80000000 | 10016, 0]
+assert set(range(0x01, 0x4f)) <= set(client.get_commands())
+assert client.get_capabilities() == (MODEL_NANO_S, 0, (128, 32))
+free, size = client.get_stack_usage()
+assert 0 < free < size
+match, recorded, current = client.verify_key()
+assert match and recorded == current and current != bytes(8)
+client.record_key()
+assert client.verify_key() == (match, recorded, current)
+assert client.benchmark(4) >= 0
+expect_error(SW_WRONG_LENGTH, auto.apdu_exchange, 0x23)
+assert client.check_rng() or client.check_rng()
+client.set_idle_mode(IDLE_LABEL_COUNT)
+client.set_idle_mode(IDLE_LABEL)
+assert client.get_checkword() == ""
+auto.actions = "rb"
+client.set_checkword("bluebird")
+assert client.get_checkword() == "bluebird"
+client.set_idle_mode(IDLE_CHECKWORD)
+client.set_idle_mode(IDLE_LABEL)
+test_reuse_count()
+test_stale_count()
+# Audits ignore protected passwords which are not armed
+auto.actions = "rb"
+client.set_arm_required("x", True)
+lengths = [len(p.encode()) for name, _, p in passwords if name != "x"]
+assert client.get_length_range() == (min(lengths), max(lengths))
+assert "x" not in client.get_weakest()
+assert client.get_lacking_classes(CLASS_UPPER) == []
+auto.actions = "rb"
+client.set_arm_required("x", False)
+test_storage_usage()
+test_password_classes()
+test_challenge_response()
+test_letter_counts()
+test_check_password()
 test_password_retrieval()
+# Partial names are read only if a single name matches
+auto.actions = "rb"
+assert client.get_by_prefix("que") == passwords[3]
+auto.actions = "rb"
+assert client.get_by_prefix("x") == passwords[0]
+try:
+    client.get_by_prefix("qu")
+    assert False
+except AmbiguousName as e:
+    assert e.count == 2
+    assert set(e.names) == {passwords[3][0], passwords[4][0]}
+auto.actions = "b"
+assert client.find_password(passwords[3][2]) == (1, [passwords[3][0]])
+auto.actions = "b"
+assert client.find_password("not stored") == (0, [])
+for count in (1, 4):
+    auto.actions = "rb"
+    assert client.get_password_start(passwords[0][0], count) == (
+        passwords[0][1], passwords[0][2][:count].encode())
+store_digest = test_store_digest()
 
 # Export in plain text and also in encrypted form
 # Do this before password removal testing
 auto.actions = "brb"
-export_plain = client.export(encrypt=False)
+_, export_plain = client.export(encrypt=False)
 auto.actions = "b"
-export_encrypted = client.export()
+_, export_encrypted = client.export()
+
+# Test sorting the store by name, which keeps every password
+auto.actions = "b"
+assert client.sort_by_name() > 0
+assert client.get_names() == sorted(client.get_names(),
+    key=lambda name: name.encode())
+auto.actions = "b"
+assert client.sort_by_name() == 0
+test_password_retrieval()
+assert client.get_store_digest() == store_digest
 
 # Test password removal
 removal_order = [name for name, _, _ in passwords]
 random.shuffle(removal_order)
 names = set(removal_order)
 for name in removal_order:
+    names_before = client.get_names()
+    index = names_before.index(name)
     auto.actions = "rb"
     client.delete_by_name(name)
     names.remove(name)
     assert set(client.get_names()) == names
+    # The slot freed first is reused
+    assert client.get_next_index() <= index
 assert client.get_size() == 0
 
 # Test import plain
 test_clear()
+assert not client.is_import_pending()
 auto.actions = ";b"
 client.import_("1.1.0", export_plain, encrypted=False)
+assert not client.is_import_pending()
 test_password_list()
 test_password_retrieval()
 
+# Test verification of a backup, which does not import anything
+assert client.verify_backup(export_encrypted, encrypted=True) == (
+    len(passwords), 0)
+corrupted = bytearray(export_encrypted[0])
+corrupted[-1] ^= 1
+assert client.verify_backup([bytes(corrupted)] + export_encrypted[1:],
+    encrypted=True) == (len(passwords) - 1, 1)
+assert client.get_size() == len(passwords)
+
 # Test import encrypted
 test_clear()
+# An import which fails or is abandoned leaves the marker set, until an import
+# completes
+auto.actions = ";b"
+expect_error(SW_DECRYPT_FAILED, client.import_, "1.1.0",
+    [bytes(corrupted)] + export_encrypted[1:], encrypted=True)
+assert client.is_import_pending()
+auto.actions = ";b"
+client.import_("1.1.0", export_encrypted, encrypted=True)
+assert not client.is_import_pending()
+auto.actions = "b"
+auto.apdu_exchange(0x09, len(passwords).to_bytes(4, 'big'), p1=0x01)
+expect_error(SW_BAD_STATE, client.get_size)
+assert client.is_import_pending()
+test_clear()
 auto.actions = ";b"
 client.import_("1.1.0", export_encrypted, encrypted=True)
+assert not client.is_import_pending()
 test_password_list()
 test_password_retrieval()
+assert test_store_digest() == store_digest
+assert client.verify_store_digest(store_digest)
+assert not client.verify_store_digest(bytes(20))
+
+# Test encrypted import without MAC verification, for trusted channels
+test_clear()
+auto.actions = ";rbb"
+client.import_("1.1.0", export_encrypted, encrypted=True, unverified=True)
+test_password_list()
+test_password_retrieval()
+
+# Test sealed plaintext export, checked during import
+auto.actions = "brb"
+_, export_sealed, seal = client.export_sealed(encrypt=False)
+# Records carry their sequence, which changed when they were imported
+without_seq = lambda records: [e[:99] + e[103:] for e in records]
+assert without_seq(export_sealed) == without_seq(export_plain)
+assert len(seal) == 16 + 20
+test_clear()
+# A corrupted backup is rejected before anything is stored
+corrupted = list(export_sealed)
+corrupted[-1] = corrupted[-1][:64] + b"X" + corrupted[-1][65:]
+auto.actions = ""
+expect_error(SW_DECRYPT_FAILED, client.import_, "1.1.0", corrupted,
+    encrypted=False, seal=seal)
+assert client.get_size() == 0
+# The backup is verified before being imported: the consent is asked once
+# the seal has been checked
+verify_actions = ";" * (1 + len(export_sealed) + 2)
+auto.actions = verify_actions + "b"
+client.import_("1.1.0", export_sealed, encrypted=False, seal=seal)
+test_password_list()
+
+# Test import checking the number of passwords announced by the backup
+test_clear()
+auto.actions = verify_actions + "b"
+client.import_("1.1.0", export_sealed, encrypted=False, seal=seal,
+    announced=len(export_sealed))
+test_password_list()
+# The device rejects a backup with more or less records than announced
+for announced in (len(export_plain) + 1, len(export_plain) - 1):
+    test_clear()
+    auto.actions = ";b"
+    expect_error(SW_COUNT_MISMATCH, client.import_, "1.1.0", export_plain,
+        encrypted=False, announced=announced)
+test_clear()
+auto.actions = ";b"
+client.import_("1.1.0", export_plain, encrypted=False)
+test_password_list()
+
+# Test compact plaintext export, without padding
+auto.actions = "brb"
+_, export_compact = client.export(encrypt=False, compact=True)
+assert sum(map(len, export_compact)) < sum(map(len, export_plain))
+test_clear()
+auto.actions = ";b"
+client.import_("1.1.0", export_compact, encrypted=False, compact=True)
+test_password_list()
+test_password_retrieval()
+
+# Test import keeping both versions of passwords with the same name
+auto.actions = ";b"
+assert client.import_("1.1.0", export_plain[:2], encrypted=False,
+    rename=True) == (0, 2)
+assert client.get_size() == len(passwords) + 2
+for name, login, password in passwords[:2]:
+    auto.actions = "rb"
+    assert client.get_by_name(name + "-2") == (login, password)
+
+# Test import keeping the most recently modified passwords: the stored
+# passwords have been imported since the sealed export, so they are newer
+auto.actions = ";b"
+assert client.import_("1.1.0", export_sealed, encrypted=False,
+    newest=True) == (len(passwords), 0)
+_, _, current = client.get_sequence_range()
+newer = bytearray(export_sealed[0])
+newer[99:103] = (current + 1).to_bytes(4, 'big')
+auto.actions = ";b"
+assert client.import_("1.1.0", [bytes(newer)], encrypted=False,
+    newest=True) == (0, 0)
+assert client.get_sequence_range()[1:] == (current + 1, current + 1)
+# A forged sequence close to the maximum is rejected
+forged = bytearray(export_sealed[1])
+forged[99:103] = (0xffffffff).to_bytes(4, 'big')
+auto.actions = ";b"
+expect_error(SW_INVALID_DATA, client.import_, "1.1.0", [bytes(forged)],
+    encrypted=False, newest=True)
+assert client.get_sequence_range()[2] == current + 1
+
+# Test removal of a range of passwords
+names = client.get_names()
+auto.actions = "rb"
+assert client.delete_range(1, 2) == 2
+assert client.get_names() == names[:1] + names[3:]
+assert client.delete_range(len(names), 5) == 0
+expect_error(SW_WRONG_LENGTH, auto.apdu_exchange, 0x2f, bytes(4))
+assert client.get_size() == len(names) - 2
+
+# Imports of more passwords than the capacity are rejected before consent
+auto.actions = ""
+expect_error(SW_INVALID_DATA, auto.apdu_exchange, 0x09,
+    (129).to_bytes(4, 'big'))
+expect_error(SW_INVALID_DATA, auto.apdu_exchange, 0x09,
+    (1000000).to_bytes(4, 'big'), p2=0x04)
+
+# Test single APDU import
+test_clear()
+expect_error(SW_WRONG_LENGTH, auto.apdu_exchange, 0x12)
+auto.actions = "b"
+client.import_blob(export_plain[:2])
+assert client.get_size() == 2
+
+# Test add session: new passwords are created without confirmation
+test_clear()
+expect_error(SW_WRONG_LENGTH, auto.apdu_exchange, 0x16)
+client.open_provisioning()
+auto.actions = "rb"
+client.start_add_session(len(passwords))
+for name, login, password in passwords:
+    client.add(name, login, password)
+client.close_provisioning()
+assert client.get_size() == len(passwords)
+assert client.get_provisioned() == [name for name, _, _ in passwords]
+expect_error(SW_WRONG_LENGTH, auto.apdu_exchange, 0x31, bytes(2), p1=0x02)
+test_password_retrieval()
+
+# Updates do not use the add session, which only counts created passwords
+auto.actions = "rb"
+client.start_add_session(1)
+auto.actions = "rb"
+client.add(*passwords[0])
+auto.actions = ""
+client.add("session", "", "created")
+auto.actions = "rb"
+client.delete_by_name("session")
+
+# Test decoy password, which hides a stored password with the same name
+auto.actions = "rb"
+client.set_decoy(passwords[0][0], "decoy", "harmless")
+assert client.has_name(passwords[0][0])
+auto.actions = "rb"
+assert client.get_by_name(passwords[0][0]) == ("decoy", "harmless")
+assert client.get_size() == len(passwords)
+
+# Imported passwords named as the decoy are skipped
+auto.actions = "bb"
+client.clear()
+auto.actions = "b"
+assert client.import_blob(export_plain[:2]) == 1
+assert client.get_size() == 1
+auto.actions = "rb"
+client.set_decoy("")
+auto.actions = "b"
+assert client.import_blob(export_plain[:1]) == 0
+for name, login, password in passwords[2:]:
+    auto.actions = "rb"
+    client.add(name, login, password)
+test_password_retrieval()
+
+# Test export of a single tag
+names = client.get_names()
+client.set_tag(passwords[1][0], 7)
+client.set_tag(passwords[2][0], 7)
+assert client.get_names() == names
+expect_error(SW_WRONG_LENGTH, auto.apdu_exchange, 0x19, bytes(32))
+assert client.get_tag_counts() == {0: len(passwords) - 2, 7: 2}
+auto.actions = "rb"
+_, export_tag = client.export(tag=7)
+assert len(export_tag) == 2
+
+# Test format hints, kept by export and import
+client.set_hint(passwords[0][0], "Bearer ")
+client.set_hint(passwords[1][0], "<*>")
+assert client.get_hint(passwords[0][0]) == "Bearer "
+assert client.get_hint(passwords[2][0]) == ""
+assert apply_hint(client.get_hint(passwords[1][0]), "x") == "<x>"
+for compact in (False, True):
+    auto.actions = "brb"
+    _, export_hint = client.export(encrypt=False, compact=compact)
+    test_clear()
+    auto.actions = ";b"
+    client.import_("1.1.0", export_hint, encrypted=False, compact=compact)
+    assert client.get_hint(passwords[0][0]) == "Bearer "
+    assert client.get_hint(passwords[1][0]) == "<*>"
+names = client.get_names()
+client.set_hint(passwords[1][0], "")
+assert client.get_hint(passwords[1][0]) == ""
+assert client.get_names() == names
+
+# Test rotation of the passwords with a tag
+names = client.get_names()
+auto.actions = "rb"
+assert client.rotate_tag(7) == 2
+assert client.get_names() == names
+auto.actions = ""
+assert client.rotate_tag(8) == 0
+for i, (name, login, password) in enumerate(passwords):
+    auto.actions = "rb"
+    login2, password2 = client.get_by_name(name)
+    assert login == login2
+    assert (password == password2) == (i not in (1, 2))
+test_clear()
+for name, login, password in passwords:
+    auto.actions = "rb"
+    client.add(name, login, password)
+
+# Test export and import encrypted with a passphrase
+expect_error(SW_WRONG_LENGTH, auto.apdu_exchange, 0x1d, bytes(2))
+expect_error(SW_WRONG_LENGTH, auto.apdu_exchange, 0x1d,
+    (16).to_bytes(4, 'big') + bytes(8), p1=0x01)
+salt = client.set_passphrase("correct horse battery staple", 16)
+auto.actions = "b"
+_, export_passphrase = client.export(passphrase=True)
+test_clear()
+client.set_passphrase("correct horse battery staple", 16, salt)
+auto.actions = ";b"
+client.import_("1.1.0", export_passphrase, encrypted=True, passphrase=True)
+test_password_list()
+test_password_retrieval()
+
+# Test export with ChaCha20-Poly1305, which is decrypted without a device
+auto.actions = "b"
+_, export_chacha = client.export(passphrase=True, chacha=True)
+key = passphrase_key("correct horse battery staple", salt, 16)
+records = [decrypt_entry(key, e) for e in export_chacha]
+assert (set(record[:32].rstrip(b"\0").decode() for record in records) ==
+    set(name for name, _, _ in passwords))
+test_clear()
+auto.actions = ";b"
+client.import_("1.1.0", export_chacha, encrypted=True, passphrase=True,
+    chacha=True)
+test_password_list()
+test_password_retrieval()
+
+# Test generation defaults
+auto.actions = "b"
+client.set_gen_defaults(20, CLASS_DIGIT)
+assert client.get_gen_defaults() == (20, CLASS_DIGIT, "", (0, 0, 0, 0))
+auto.actions = "rb"
+client.add("generated", "")
+auto.actions = "rb"
+_, generated = client.get_by_name("generated")
+assert len(generated) == 20 and generated.isdigit()
+auto.actions = "rb"
+assert client.get_by_name_qr("generated") == ("", generated)
+auto.actions = "b"
+client.reset_gen_defaults()
+assert client.get_gen_defaults() == (16,
+    CLASS_LOWER | CLASS_UPPER | CLASS_DIGIT, "", (0, 0, 0, 0))
+
+# Test generation of pronounceable passwords
+for length in (9, 16):
+    auto.actions = "rb"
+    updated, _ = client.add_generated("pronounceable", "", length,
+        CLASS_LOWER, pronounceable=True)
+    assert updated == (length != 9)
+    auto.actions = "rb"
+    _, generated = client.get_by_name("pronounceable")
+    assert len(generated) == length
+    assert all(c in SYLLABLE_CONSONANTS for c in generated[0::2])
+    assert all(c in SYLLABLE_VOWELS for c in generated[1::2])
+auto.actions = "rb"
+client.delete_by_name("pronounceable")
+
+# Test swapping the passwords of two entries
+for name in ("swap-a", "swap-b"):
+    auto.actions = "rb"
+    client.add(name, "login-" + name, "secret-" + name)
+auto.actions = "rrb"
+client.swap_passwords("swap-a", "swap-b")
+for name, other in (("swap-a", "swap-b"), ("swap-b", "swap-a")):
+    auto.actions = "rb"
+    assert client.get_by_name(name) == ("login-" + name, "secret-" + other)
+    auto.actions = "rb"
+    client.delete_by_name(name)
+
+# Test generation without the characters of the name
+auto.actions = "rb"
+client.add_generated("minimums", "", 32, CLASS_LOWER | CLASS_UPPER,
+    exclude_name=True)
+auto.actions = "rb"
+_, generated = client.get_by_name("minimums")
+assert len(generated) == 32
+assert not set(generated.lower()) & set("minus")
+
+# Test generation with minimums of each class, which must always be satisfied
+for i in range(8):
+    auto.actions = "rb"
+    updated, fingerprint = client.add_generated("minimums", "", 6,
+        CLASS_LOWER | CLASS_DIGIT | CLASS_SYMBOL, "!#", (0, 0, 3, 2))
+    assert updated
+    auto.actions = "rb"
+    _, generated = client.get_by_name("minimums")
+    assert len(generated) == 6
+    assert len(fingerprint) == 8
+    assert sum(c.isdigit() for c in generated) >= 3
+    assert sum(c in "!#" for c in generated) >= 2
+
+# Equal secrets have equal fingerprints, whatever their name
+auto.actions = "rb"
+assert client.add("fingerprint", "", generated) == (False, fingerprint)
+auto.actions = "rb"
+_, other = client.add("fingerprint", "", generated + "x")
+assert other != fingerprint
+auto.actions = "rb"
+client.delete_by_name("fingerprint")
+
+# Test protected password, which can only be read right after being armed
+auto.actions = "rb"
+client.set_arm_required("minimums", True)
+names = client.get_names()
+flags = client.get_flags()
+assert len(flags) == len(names)
+assert flags[names.index("minimums")] & FLAG_ARM_REQUIRED
+assert client.get_flags(names.index("minimums"), 1) == [
+    flags[names.index("minimums")]]
+assert client.get_flags(len(names), 4) == []
+assert client.get_read_confirmations("minimums") == 2
+auto.actions = ""
+expect_error(SW_NOT_ARMED, client.get_by_name, "minimums")
+expect_error(SW_NOT_ARMED, client.challenge_response, "minimums", b"nonce")
+expect_error(SW_NOT_ARMED, client.export, encrypt=False)
+# The host knows the passphrase, so it could decrypt such an export
+client.set_passphrase("correct horse battery staple", 16)
+expect_error(SW_NOT_ARMED, client.export, passphrase=True)
+auto.actions = "b"
+assert client.find_password(generated)[0] == 0
+# The protection follows the password when it is swapped
+auto.actions = "rb"
+client.add("unprotected", "", "other")
+auto.actions = "rrb"
+client.swap_passwords("minimums", "unprotected")
+auto.actions = ""
+expect_error(SW_NOT_ARMED, client.get_by_name, "unprotected")
+auto.actions = "rrb"
+client.swap_passwords("minimums", "unprotected")
+auto.actions = "rb"
+client.delete_by_name("unprotected")
+auto.actions = "rb;rb"
+client.arm("minimums")
+_, armed = client.get_by_name("minimums")
+assert armed == generated
+auto.actions = "rb"
+client.set_arm_required("minimums", False)
+assert client.get_read_confirmations("minimums") == 1
+
+# Test marking a password for rotation when reading it
+auto.actions = "rb"
+assert client.get_by_name("minimums", mark_rotation=True)[1] == generated
+auto.actions = "brb"
+_, export_marked = client.export(encrypt=False)
+marked = [e for e in export_marked if e[:32].rstrip(b"\0") == b"minimums"]
+assert marked[0][96] & 0x04
+
+# Test password erasure, which keeps the entry in place
+names = client.get_names()
+sequence_range = client.get_sequence_range()
+auto.actions = "rb"
+client.wipe_password("minimums")
+assert client.get_names() == names
+assert client.get_sequence_range() == sequence_range
+auto.actions = "rb"
+assert client.get_by_name("minimums") == ("", "")
+assert client.get_missing(MISSING_PASSWORD) == ["minimums"]
+
+# Test binary secrets, which survive an export and import
+secret = b"\xff\x00binary\x80\x00"
+auto.actions = "rb"
+client.add_binary("binary", "key", secret)
+auto.actions = "rb"
+assert client.get_by_name("binary") == ("key", secret)
+auto.actions = "rb"
+assert client.get_by_name_base64("binary") == ("key", secret)
+auto.actions = "rb"
+assert client.get_password_start("binary", 4) == ("key", secret[:4])
+auto.actions = "rb"
+assert client.get_by_name_qr("binary") == (
+    "key", base64.b64encode(secret).decode())
+auto.actions = "brb"
+_, export_binary = client.export(encrypt=False)
+test_clear()
+auto.actions = ";b"
+client.import_("1.1.0", export_binary, encrypted=False)
+auto.actions = "rb"
+assert client.get_by_name("binary") == ("key", secret)
+
+# Test moving entries through URIs
+auto.actions = "rb"
+uri = client.export_uri("binary")
+assert uri == "otpauth://totp/binary:key?secret=" + (
+    base64.b32encode(secret).decode().rstrip("="))
+auto.actions = "rb"
+client.delete_by_name("binary")
+auto.actions = "rb"
+client.import_uri(uri)
+auto.actions = "rb"
+assert client.get_by_name("binary") == ("key", secret)
+uri = "nanopass://entry/uri%20test?login=me&password=p%40ss"
+auto.actions = "rb"
+client.import_uri(uri)
+auto.actions = "rb"
+assert client.get_by_name("uri test") == ("me", "p@ss")
+auto.actions = "rb"
+assert client.export_uri("uri test") == uri
+
+# Test TOTP codes, with the test vector of RFC 6238
+auto.actions = "b"
+assert client.import_totp(["otpauth://totp/totp?secret=" +
+    base64.b32encode(b"12345678901234567890").decode()]) == [TOTP_STORED]
+auto.actions = "rb"
+assert client.get_totp("totp", 2000000000) == "279037"
+auto.actions = "rb"
+assert client.get_totp("totp", 2000000029) == "279037"
+assert client.get_totp_remaining("totp", 2000000000) == 10
+assert client.get_totp_remaining("totp", 2000000010) == 30
+assert client.get_totp_remaining("totp", 2000000039) == 1
+# The secret of a TOTP entry is never disclosed, and only the entries imported
+# as TOTP secrets compute codes
+names = client.get_names()
+assert client.get_flags()[names.index("totp")] & FLAG_TOTP
+auto.actions = ""
+expect_error(SW_TOTP_ONLY, client.get_by_name, "totp")
+expect_error(SW_TOTP_ONLY, client.export_uri, "totp")
+expect_error(SW_TOTP_ONLY, client.challenge_response, "totp", b"nonce")
+expect_error(SW_INVALID_DATA, client.get_totp, "binary", 2000000000)
+auto.actions = "b"
+assert client.find_password(b"12345678901234567890")[0] == 0
+auto.actions = "brb"
+_, exported = client.export(encrypt=False)
+assert len(exported) == len(names) - 1
+# GetTotp requires a protected secret to be armed
+auto.actions = "rb"
+client.set_arm_required("totp", True)
+auto.actions = ""
+expect_error(SW_NOT_ARMED, client.get_totp, "totp", 2000000000)
+auto.actions = "rb;rb"
+client.arm("totp")
+assert client.get_totp("totp", 2000000000) == "279037"
+auto.actions = "rb"
+client.set_arm_required("totp", False)
+# Rotating a tag regenerates the binary secrets but keeps the TOTP secrets
+client.set_tag("totp", 9)
+client.set_tag("binary", 9)
+auto.actions = "rb"
+assert client.rotate_tag(9) == 1
+auto.actions = "rb"
+assert client.get_totp("totp", 2000000000) == "279037"
+auto.actions = "rb"
+assert client.get_by_name("binary")[1] != secret
+
+# Test bulk import of TOTP secrets, where invalid URIs are skipped
+auto.actions = ""
+expect_error(SW_INVALID_DATA, auto.apdu_exchange, 0x4e,
+    (1000000).to_bytes(4, 'big'))
+auto.actions = "b"
+assert client.import_totp([
+    "otpauth://totp/Example:alice?secret=JBSWY3DPEHPK3PXP&issuer=Example",
+    "otpauth://totp/eight?secret=JBSWY3DPEHPK3PXP&digits=8",
+    "otpauth://totp/bob?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ"
+        "&issuer=Service&period=30",
+    "otpauth://totp/bad?secret=JBSWY3DP1",
+]) == [TOTP_STORED, TOTP_INVALID, TOTP_STORED, TOTP_INVALID]
+assert not client.has_name("eight")
+auto.actions = ""
+expect_error(SW_TOTP_ONLY, client.get_by_name, "Example")
+auto.actions = "rb"
+assert client.get_totp("Service", 2000000000) == "279037"
+# A storage error ends the import, which stays pending
+size = client.get_size()
+auto.actions = "rb"
+client.set_max_entries(size)
+auto.actions = "b"
+expect_error(SW_STORAGE_FULL, client.import_totp, [
+    "otpauth://totp/full?secret=JBSWY3DPEHPK3PXP",
+    "otpauth://totp/after?secret=JBSWY3DPEHPK3PXP",
+])
+assert client.is_import_pending()
+assert client.get_size() == size
+auto.actions = "rb"
+client.set_max_entries(0)
+auto.actions = "b"
+assert client.import_totp([
+    "otpauth://totp/full?secret=JBSWY3DPEHPK3PXP"]) == [TOTP_STORED]
+assert not client.is_import_pending()
+
+# Test import of records exported without metadata
+test_clear()
+auto.actions = "b"
+client.import_blob([e[:96] for e in export_plain[:2]])
+assert client.get_size() == 2
 
 print("Test complete!")